@@ -0,0 +1,234 @@
+//! High-level operations on queues, to build small ops binaries on this crate rather than shelling out
+//! to `rabbitmqadmin`.
+//!
+//! Every message is republished with confirms before the source one is acked, a failure leaves it in its queue.
+
+use crate::audit::ConfirmOutcome;
+use crate::republish::Overrides;
+use crate::naming::{self, TopologyAffixes};
+use crate::{confirm, rejection, Error, Result};
+use lapin::message::Delivery;
+use lapin::options::{
+    BasicAckOptions, BasicGetOptions, BasicNackOptions, BasicPublishOptions, ConfirmSelectOptions, QueueDeclareOptions,
+    QueuePurgeOptions,
+};
+use lapin::types::FieldTable;
+use lapin::{BasicProperties, Channel, Connection};
+
+/// Counts of a queue, as returned by a passive declaration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueInfo {
+    pub name: String,
+    /// Ready messages, not counting the ones delivered and not acked yet.
+    pub messages: u32,
+    pub consumers: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayDeadLettersSummary {
+    pub replayed: u64,
+    /// Not matching the filter, left in the dead-letter queue.
+    pub skipped: u64,
+}
+
+/// A message about to be republished by `Admin::move_messages`, as its transform may change it.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+    pub properties: BasicProperties,
+}
+
+/// Admin operations, on a dedicated channel in confirm mode.
+#[derive(Clone, Debug)]
+pub struct Admin {
+    channel: Channel,
+    affixes: TopologyAffixes,
+}
+
+impl Admin {
+    pub async fn new(conn: &Connection) -> Result<Self> {
+        let channel = conn.create_channel().await?;
+        channel.confirm_select(ConfirmSelectOptions::default()).await?;
+
+        Ok(Self {
+            channel,
+            affixes: naming::topology_affixes(),
+        })
+    }
+
+    /// The queues and exchanges named with `affixes`, rather than the ones of `naming::set_topology_affixes`.
+    pub fn with_topology_affixes(mut self, affixes: TopologyAffixes) -> Self {
+        self.affixes = affixes;
+        self
+    }
+
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    pub async fn inspect(&self, queue: &str) -> Result<QueueInfo> {
+        let declared = self
+            .channel
+            .queue_declare(
+                &self.affixes.resolve(queue),
+                QueueDeclareOptions {
+                    passive: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        Ok(QueueInfo {
+            name: declared.name().to_string(),
+            messages: declared.message_count(),
+            consumers: declared.consumer_count(),
+        })
+    }
+
+    /// Up to `max` messages of `queue`, left in the queue.
+    /// They are requeued once all were fetched, so they are redelivered with the `redelivered` flag.
+    pub async fn peek(&self, queue: &str, max: usize) -> Result<Vec<Delivery>> {
+        let mut deliveries = vec![];
+        while deliveries.len() < max {
+            let Some(message) = self.channel.basic_get(&self.affixes.resolve(queue), BasicGetOptions::default()).await? else {
+                break;
+            };
+            deliveries.push(message.delivery);
+        }
+        self.requeue(&deliveries).await?;
+
+        Ok(deliveries)
+    }
+
+    /// Delete every ready message of `queue`, returns how many were.
+    pub async fn purge(&self, queue: &str) -> Result<u32> {
+        Ok(self.channel.queue_purge(&self.affixes.resolve(queue), QueuePurgeOptions::default()).await?)
+    }
+
+    /// Move up to `max` messages from `source` to `destination`, through the default exchange.
+    /// Returns how many were moved.
+    pub async fn move_between_queues(&self, source: &str, destination: &str, max: u64) -> Result<u64> {
+        self.move_messages(source, "", Some(&self.affixes.resolve(destination)), max, |_| {}).await
+    }
+
+    /// Fix and replay: move up to `max` messages from `source` to `exchange`, with `routing_key` or their own one,
+    /// once changed by `transform`. Returns how many were moved.
+    pub async fn move_messages<F>(
+        &self,
+        source: &str,
+        exchange: &str,
+        routing_key: Option<&str>,
+        max: u64,
+        mut transform: F,
+    ) -> Result<u64>
+    where
+        F: FnMut(&mut Message),
+    {
+        let mut moved = 0;
+        while moved < max {
+            let Some(got) = self.channel.basic_get(&self.affixes.resolve(source), BasicGetOptions::default()).await? else {
+                break;
+            };
+            let delivery = got.delivery;
+
+            let mut message = Message {
+                routing_key: routing_key.unwrap_or(delivery.routing_key.as_str()).to_string(),
+                payload: delivery.data.clone(),
+                properties: delivery.properties.clone(),
+            };
+            transform(&mut message);
+
+            self.republish(&delivery, exchange, &message.routing_key, &message.payload, message.properties).await?;
+            moved += 1;
+        }
+
+        Ok(moved)
+    }
+
+    /// Republish to `exchange`, routing key preserved, the messages of the dead-letter queue `queue` matching `filter`,
+    /// without their rejection reason. The others are left in the queue.
+    pub async fn replay_dead_letters<F>(&self, queue: &str, exchange: &str, filter: F) -> Result<ReplayDeadLettersSummary>
+    where
+        F: Fn(&Delivery) -> bool,
+    {
+        // the skipped messages are held until the end, a requeued one would be fetched again right away
+        let pending = self.inspect(queue).await?.messages;
+        let mut skipped = vec![];
+        let mut summary = ReplayDeadLettersSummary::default();
+
+        for _ in 0..pending {
+            let Some(message) = self.channel.basic_get(&self.affixes.resolve(queue), BasicGetOptions::default()).await? else {
+                break;
+            };
+            let delivery = message.delivery;
+
+            if !filter(&delivery) {
+                skipped.push(delivery);
+                continue;
+            }
+
+            let overrides = Overrides::default().with_exchange(exchange).without_header(rejection::REASON_HEADER);
+            let (exchange, routing_key, properties) = overrides.apply(&delivery, &self.affixes);
+
+            let res = self.republish(&delivery, &exchange, &routing_key, &delivery.data, properties).await;
+            if let Err(err) = res {
+                self.requeue(&skipped).await?;
+                return Err(err);
+            }
+            summary.replayed += 1;
+        }
+
+        summary.skipped = skipped.len() as u64;
+        self.requeue(&skipped).await?;
+
+        Ok(summary)
+    }
+
+    async fn requeue(&self, deliveries: &[Delivery]) -> Result<()> {
+        for delivery in deliveries {
+            delivery
+                .nack(BasicNackOptions {
+                    requeue: true,
+                    ..BasicNackOptions::default()
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish mandatory, and ack `source` once the broker confirmed it routed the message, requeue it otherwise.
+    async fn republish(
+        &self,
+        source: &Delivery,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<()> {
+        let options = BasicPublishOptions {
+            mandatory: true,
+            ..BasicPublishOptions::default()
+        };
+        let res = match self.channel.basic_publish(&self.affixes.resolve(exchange), routing_key, options, payload, properties).await {
+            Ok(confirm) => confirm.await,
+            Err(err) => Err(err),
+        };
+
+        match confirm::outcome(&res) {
+            ConfirmOutcome::Ack => {
+                source.ack(BasicAckOptions::default()).await?;
+                Ok(())
+            }
+            outcome => {
+                self.requeue(std::slice::from_ref(source)).await?;
+                Err(Error::NotConfirmed {
+                    exchange: exchange.to_string(),
+                    outcome: outcome.as_str(),
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Opt-in audit trail of every publish, for compliance-heavy environments.
+
+/// What finally happened to a published message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfirmOutcome {
+    Ack,
+    Nack,
+    /// Unroutable with the `mandatory` flag, returned by the broker.
+    Returned,
+    /// The channel isn't in confirm mode.
+    NotRequested,
+    /// The publish itself failed.
+    Failed(String),
+    /// The confirmation was dropped before being awaited.
+    Unknown,
+}
+
+impl ConfirmOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfirmOutcome::Ack => "ack",
+            ConfirmOutcome::Nack => "nack",
+            ConfirmOutcome::Returned => "returned",
+            ConfirmOutcome::NotRequested => "not_requested",
+            ConfirmOutcome::Failed(_) => "failed",
+            ConfirmOutcome::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    pub exchange: String,
+    pub routing_key: String,
+    pub message_id: Option<String>,
+    pub size: usize,
+    pub confirm: ConfirmOutcome,
+}
+
+/// Receive an `AuditRecord` once the outcome of each publish is known.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Record publishes as structured `tracing` events on the `amqp_audit` target.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingAuditSink;
+
+impl AuditSink for TracingAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        info!(
+            target: "amqp_audit",
+            exchange = %record.exchange,
+            routing_key = %record.routing_key,
+            message_id = record.message_id.as_deref().unwrap_or(""),
+            size = record.size,
+            confirm = ?record.confirm,
+            "Message published",
+        );
+    }
+}
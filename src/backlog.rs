@@ -0,0 +1,63 @@
+//! Backlog alarms: the depth of queues is polled, and a callback is called while one of them is above its threshold,
+//! e.g. to trigger autoscaling or alerting right from the consumer process.
+
+use crate::naming::TopologyAffixes;
+use crate::runtime::{self, JoinHandle};
+use crate::tasks::{self, TaskKind};
+use lapin::options::QueueDeclareOptions;
+use lapin::types::FieldTable;
+use lapin::Channel;
+use std::time::Duration;
+
+/// Ready messages of `queue` above which it's backlogged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BacklogThreshold {
+    pub queue: String,
+    pub max_depth: u32,
+}
+
+impl BacklogThreshold {
+    pub fn new(queue: &str, max_depth: u32) -> Self {
+        Self {
+            queue: queue.to_string(),
+            max_depth,
+        }
+    }
+}
+
+/// Check every `interval` the depth of the queues of `thresholds`, `on_backlog(queue, depth)` is called on every check
+/// a queue is above its threshold. The queues are named with `affixes`.
+pub(crate) fn spawn<F>(
+    channel: Channel,
+    affixes: TopologyAffixes,
+    thresholds: Vec<BacklogThreshold>,
+    interval: Duration,
+    on_backlog: F,
+) -> JoinHandle<()>
+where
+    F: Fn(&str, u32) + Send + Sync + 'static,
+{
+    tasks::spawn(TaskKind::Background, "amqp-backlog-alarm", async move {
+        loop {
+            runtime::sleep(interval).await;
+
+            for threshold in &thresholds {
+                let options = QueueDeclareOptions {
+                    passive: true,
+                    ..QueueDeclareOptions::default()
+                };
+                let declared = channel.queue_declare(&affixes.resolve(&threshold.queue), options, FieldTable::default()).await;
+
+                match declared {
+                    Ok(declared) if declared.message_count() > threshold.max_depth => {
+                        let depth = declared.message_count();
+                        warn!(queue = threshold.queue.as_str(), depth, max_depth = threshold.max_depth, "Queue backlogged");
+                        on_backlog(&threshold.queue, depth);
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!(%err, queue = threshold.queue.as_str(), "Failed to poll the depth of a queue"),
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,187 @@
+//! Batching of the deliveries of a listener, e.g. to write them to a database in one statement.
+//!
+//! The listener pushes the item of each delivery to a `Batcher` from `consume` and returns the outcome of its batch,
+//! so every delivery is acked or rejected once its batch is flushed. A batch is flushed once it has `max_size` items,
+//! or `max_wait` after its first one: keep the `max_concurrent_tasks` of the listener at least at `max_size`.
+//!
+//! With `Batcher::with_outcomes`, the flush returns the outcome of each item: only the failed ones are rejected
+//! (or dead-lettered), the others are acked.
+
+use crate::metrics;
+use crate::runtime;
+use crate::tasks::{self, TaskKind};
+use crate::Rejection;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Write a batch, its outcome is the one of each of its deliveries.
+pub type FlushFn<T> = Arc<dyn Fn(Vec<T>) -> Pin<Box<dyn Future<Output = Result<(), Rejection>> + Send>> + Send + Sync>;
+
+/// Write a batch, returning the outcome of each of its items, in order.
+pub type OutcomesFlushFn<T> =
+    Arc<dyn Fn(Vec<T>) -> Pin<Box<dyn Future<Output = Vec<Result<(), Rejection>>> + Send>> + Send + Sync>;
+
+enum Flush<T> {
+    Batch(FlushFn<T>),
+    Items(OutcomesFlushFn<T>),
+}
+
+/// Why a batch was flushed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushReason {
+    /// It reached `max_size`.
+    Size,
+    /// `max_wait` passed since its first item.
+    Timeout,
+}
+
+impl FlushReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FlushReason::Size => "size",
+            FlushReason::Timeout => "timeout",
+        }
+    }
+}
+
+struct Pending<T> {
+    items: Vec<T>,
+    waiters: Vec<oneshot::Sender<Result<(), Rejection>>>,
+    /// Incremented on each flush, so a timer doesn't flush the batch after the one it was started for.
+    generation: u64,
+}
+
+impl<T> Pending<T> {
+    fn take(&mut self) -> (Vec<T>, Vec<oneshot::Sender<Result<(), Rejection>>>) {
+        self.generation += 1;
+        (std::mem::take(&mut self.items), std::mem::take(&mut self.waiters))
+    }
+}
+
+/// Batches the items pushed by a listener, the window can be tuned at runtime.
+pub struct Batcher<T> {
+    /// Label of the metrics.
+    name: &'static str,
+    max_size: AtomicUsize,
+    max_wait_ms: AtomicU64,
+    pending: Mutex<Pending<T>>,
+    flush: Flush<T>,
+}
+
+impl<T> std::fmt::Debug for Batcher<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Batcher")
+            .field("name", &self.name)
+            .field("window", &self.window())
+            .field("pending", &self.pending.lock().unwrap().items.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Batcher<T> {
+    pub fn new(name: &'static str, max_size: usize, max_wait: Duration, flush: FlushFn<T>) -> Arc<Self> {
+        Self::with_flush(name, max_size, max_wait, Flush::Batch(flush))
+    }
+
+    /// A batcher whose flush returns an outcome per item. An item without outcome (the vector being too short)
+    /// is requeued.
+    pub fn with_outcomes(name: &'static str, max_size: usize, max_wait: Duration, flush: OutcomesFlushFn<T>) -> Arc<Self> {
+        Self::with_flush(name, max_size, max_wait, Flush::Items(flush))
+    }
+
+    fn with_flush(name: &'static str, max_size: usize, max_wait: Duration, flush: Flush<T>) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            max_size: AtomicUsize::new(max_size.max(1)),
+            max_wait_ms: AtomicU64::new(max_wait.as_millis() as u64),
+            pending: Mutex::new(Pending {
+                items: vec![],
+                waiters: vec![],
+                generation: 0,
+            }),
+            flush,
+        })
+    }
+
+    /// Change the window, from the next batch on.
+    pub fn set_window(&self, max_size: usize, max_wait: Duration) {
+        self.max_size.store(max_size.max(1), Ordering::Relaxed);
+        self.max_wait_ms.store(max_wait.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// `max_size` and `max_wait`.
+    pub fn window(&self) -> (usize, Duration) {
+        (
+            self.max_size.load(Ordering::Relaxed),
+            Duration::from_millis(self.max_wait_ms.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+impl<T: Send + 'static> Batcher<T> {
+    /// Add `item` to the current batch and wait for it to be flushed.
+    pub async fn push(self: &Arc<Self>, item: T) -> Result<(), Rejection> {
+        let (max_size, max_wait) = self.window();
+        let (tx, rx) = oneshot::channel();
+
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.items.push(item);
+            pending.waiters.push(tx);
+
+            if pending.items.len() >= max_size {
+                Some(pending.take())
+            } else {
+                if pending.items.len() == 1 {
+                    self.flush_after(max_wait, pending.generation);
+                }
+                None
+            }
+        };
+        if let Some((items, waiters)) = full {
+            self.flush_batch(items, waiters, FlushReason::Size).await;
+        }
+
+        rx.await.unwrap_or_else(|_| Err(Rejection::requeue().with_reason("batch_dropped")))
+    }
+
+    fn flush_after(self: &Arc<Self>, max_wait: Duration, generation: u64) {
+        let batcher = self.clone();
+
+        tasks::spawn(TaskKind::Background, "amqp-batch-flush", async move {
+            runtime::sleep(max_wait).await;
+
+            let (items, waiters) = {
+                let mut pending = batcher.pending.lock().unwrap();
+                if pending.generation != generation || pending.items.is_empty() {
+                    return;
+                }
+                pending.take()
+            };
+            batcher.flush_batch(items, waiters, FlushReason::Timeout).await;
+        });
+    }
+
+    async fn flush_batch(&self, items: Vec<T>, waiters: Vec<oneshot::Sender<Result<(), Rejection>>>, reason: FlushReason) {
+        debug!(batch = self.name, size = items.len(), reason = reason.as_str(), "Flushing a batch");
+        metrics::observe_batch(self.name, items.len(), reason.as_str());
+
+        let outcomes = match &self.flush {
+            Flush::Batch(flush) => vec![flush(items).await; waiters.len()],
+            Flush::Items(flush) => flush(items).await,
+        };
+        if outcomes.len() < waiters.len() {
+            warn!(batch = self.name, outcomes = outcomes.len(), size = waiters.len(), "Batch outcomes missing, requeueing their items");
+        }
+
+        let mut outcomes = outcomes.into_iter();
+        for waiter in waiters {
+            let outcome = outcomes.next().unwrap_or_else(|| Err(Rejection::requeue().with_reason("batch_outcome_missing")));
+            let _ = waiter.send(outcome);
+        }
+    }
+}
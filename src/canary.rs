@@ -0,0 +1,151 @@
+//! End-to-end health check: probes published to a loopback exchange and consumed by the same process.
+//!
+//! Unlike "the connection is open", a probe coming back proves that publishing, routing and consuming all work.
+
+use crate::clock;
+use crate::runtime::{self, JoinHandle};
+use crate::tasks::{self, TaskKind};
+use crate::{BrokerListener, Consumer, ExclusiveQueue, Publisher, Rejection, Result};
+use async_trait::async_trait;
+use lapin::message::Delivery;
+use lapin::options::{BasicConsumeOptions, ExchangeDeclareOptions};
+use lapin::types::FieldTable;
+use lapin::ExchangeKind;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct CanaryState {
+    next_probe: u64,
+    in_flight: HashMap<u64, Instant>,
+    last_received: Option<Instant>,
+    last_round_trip: Option<Duration>,
+}
+
+/// Periodically publish probes to `exchange` and measure how long they take to come back.
+#[derive(Clone)]
+pub struct Canary {
+    exchange: &'static str,
+    state: Arc<Mutex<CanaryState>>,
+    queue: Arc<Mutex<Option<ExclusiveQueue>>>,
+}
+
+impl std::fmt::Debug for Canary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Canary")
+            .field("exchange", &self.exchange)
+            .field("last_round_trip", &self.last_round_trip())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Canary {
+    pub fn new(exchange: &'static str) -> Self {
+        Self {
+            exchange,
+            state: Arc::default(),
+            queue: Arc::default(),
+        }
+    }
+
+    /// Declare the loopback exchange and an exclusive queue bound to it, then register the probe listener on `consumer`.
+    /// Must be done before the consumer is spawned.
+    pub async fn attach(&self, consumer: &mut Consumer) -> Result<()> {
+        consumer
+            .channel()
+            .exchange_declare(
+                &consumer.topology_affixes().resolve(self.exchange),
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        let mut queue = consumer.declare_exclusive_queue().await?;
+        queue.bind(self.exchange, "").await?;
+        consumer.add_consumer(queue.consume(BasicConsumeOptions::default()).await?);
+        consumer.add_listener(Arc::new(self.clone()));
+
+        *self.queue.lock().unwrap() = Some(queue);
+
+        Ok(())
+    }
+
+    /// Publish a probe every `interval`, `on_alert` is called with the time since the last probe came back
+    /// whenever it exceeds `timeout`.
+    pub fn spawn<F>(&self, publisher: Publisher, interval: Duration, timeout: Duration, on_alert: F) -> JoinHandle<()>
+    where
+        F: Fn(Duration) + Send + Sync + 'static,
+    {
+        let canary = self.clone();
+        let started_at = clock::now();
+
+        tasks::spawn(TaskKind::Background, "amqp-canary", async move {
+            loop {
+                runtime::sleep(interval).await;
+
+                let since_last = clock::elapsed(canary.state.lock().unwrap().last_received.unwrap_or(started_at));
+                if since_last > timeout {
+                    warn!(exchange_name = canary.exchange, ?since_last, "Canary probes stopped coming back");
+                    on_alert(since_last);
+                }
+
+                let probe = {
+                    let mut state = canary.state.lock().unwrap();
+                    let probe = state.next_probe;
+                    state.next_probe += 1;
+                    // forget the probes which will never come back
+                    state.in_flight.retain(|_, sent_at| clock::elapsed(*sent_at) < timeout);
+                    state.in_flight.insert(probe, clock::now());
+                    probe
+                };
+
+                if let Err(err) = publisher.publish_raw(canary.exchange, "", &probe.to_be_bytes()).await {
+                    error!(%err, exchange_name = canary.exchange, "Failed to publish a canary probe");
+                }
+            }
+        })
+    }
+
+    /// Round trip of the last probe which came back.
+    pub fn last_round_trip(&self) -> Option<Duration> {
+        self.state.lock().unwrap().last_round_trip
+    }
+
+    /// Whether a probe came back during the last `timeout`.
+    pub fn is_healthy(&self, timeout: Duration) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .last_received
+            .map(|received| clock::elapsed(received) <= timeout)
+            .unwrap_or(false)
+    }
+}
+
+#[async_trait]
+impl BrokerListener for Canary {
+    fn exchange_name(&self) -> &'static str {
+        self.exchange
+    }
+
+    async fn consume(&self, delivery: &Delivery) -> std::result::Result<(), Rejection> {
+        let Ok(probe) = <[u8; 8]>::try_from(delivery.data.as_slice()) else {
+            warn!(exchange_name = self.exchange, "Unexpected message on the canary exchange");
+            return Err(Rejection::discard().with_reason("unexpected_probe"));
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.last_received = Some(clock::now());
+
+        // probes of another instance sharing the exchange are only a liveness signal
+        if let Some(sent_at) = state.in_flight.remove(&u64::from_be_bytes(probe)) {
+            let round_trip = clock::elapsed(sent_at);
+            state.last_round_trip = Some(round_trip);
+            crate::metrics::observe_canary_round_trip(self.exchange, round_trip.as_secs_f64());
+        }
+
+        Ok(())
+    }
+}
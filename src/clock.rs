@@ -0,0 +1,108 @@
+//! The time seen by this crate: its delays, timeouts and schedules, so they can be driven by a `MockClock`
+//! in tests instead of real sleeps. The metrics durations always use the real time.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+
+    /// Resolve once `duration` elapsed, according to this clock.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+static CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+
+/// Replace the clock of the whole crate, `None` goes back to the real time of the runtime.
+pub fn set_clock(clock: Option<Arc<dyn Clock>>) {
+    *CLOCK.write().unwrap() = clock;
+}
+
+pub(crate) fn current() -> Option<Arc<dyn Clock>> {
+    CLOCK.read().unwrap().clone()
+}
+
+pub(crate) fn now() -> Instant {
+    match current() {
+        Some(clock) => clock.now(),
+        None => Instant::now(),
+    }
+}
+
+pub(crate) fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+#[derive(Debug)]
+struct MockState {
+    now: Instant,
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+/// A clock which only moves with `advance`, waking the sleeps it made due.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                now: Instant::now(),
+                sleepers: vec![],
+            })),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+
+        let now = state.now;
+        let (due, pending) = std::mem::take(&mut state.sleepers)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        state.sleepers = pending;
+
+        for (_, wake) in due {
+            let _ = wake.send(());
+        }
+    }
+
+    /// Sleeps not due yet.
+    pub fn pending_sleeps(&self) -> usize {
+        self.state.lock().unwrap().sleepers.len()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mut state = self.state.lock().unwrap();
+        if duration.is_zero() {
+            return Box::pin(async {});
+        }
+
+        let (wake, woken) = oneshot::channel();
+        let deadline = state.now + duration;
+        state.sleepers.push((deadline, wake));
+
+        Box::pin(async move {
+            let _ = woken.await;
+        })
+    }
+}
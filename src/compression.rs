@@ -0,0 +1,25 @@
+//! Payloads compressed by their producer, as told by their `content_encoding`: decompressed before reaching
+//! the listeners, `gzip` with the `gzip` feature and `zstd` with the `zstd` feature.
+//! Other encodings, or one whose feature is disabled, are handed to the listeners as is.
+#![cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+
+use crate::Result;
+
+/// The decompressed `data`, `None` when `encoding` isn't supported.
+pub(crate) fn decompress(encoding: &str, data: &[u8]) -> Result<Option<Vec<u8>>> {
+    match encoding {
+        #[cfg(feature = "gzip")]
+        "gzip" => {
+            use std::io::Read;
+
+            let mut decompressed = vec![];
+            flate2::read::GzDecoder::new(data)
+                .read_to_end(&mut decompressed)
+                .map_err(|err| crate::Error::Decompression(err.to_string()))?;
+            Ok(Some(decompressed))
+        }
+        #[cfg(feature = "zstd")]
+        "zstd" => zstd::decode_all(data).map(Some).map_err(|err| crate::Error::Decompression(err.to_string())),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,195 @@
+//! Settings of a `Broker` in a single value, see `Broker::from_config`: deserialized from a file
+//! (with any serde format, `from_toml_file` with the `config-toml` feature) or read from the environment.
+
+use crate::metrics::{self, MetricsCategory};
+use crate::reconnect::ReconnectPolicy;
+use crate::retry::RetryPolicy;
+use crate::{Error, Result};
+use lapin::tcp::{OwnedIdentity, OwnedTLSConfig};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BrokerConfig {
+    /// Tried in order until one accepts the connection.
+    pub uris: Vec<String>,
+    /// See `Broker::set_separate_connections`.
+    pub separate_connections: bool,
+    /// For the `amqps://` URIs.
+    pub tls: Option<TlsConfig>,
+    /// `basic.qos` prefetch count of the consumer channel.
+    pub prefetch: Option<u16>,
+    /// Of the listeners keeping the default `BrokerListener::max_concurrent_tasks`.
+    pub max_concurrent_tasks: Option<usize>,
+    /// Of the listeners without their own.
+    pub retry: Option<RetryConfig>,
+    pub metrics: MetricsConfig,
+    /// Of the broker, its publisher and its consumer, see `Broker::set_topology_affixes`.
+    pub topology_prefix: String,
+    pub topology_suffix: String,
+    /// See `Broker::run`.
+    pub reconnect: Option<ReconnectPolicy>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file of CA certificates to trust, along with the system ones.
+    pub cert_chain: Option<PathBuf>,
+    /// PKCS#12 DER file of the client certificate.
+    pub identity: Option<PathBuf>,
+    pub identity_password: String,
+}
+
+impl TlsConfig {
+    /// Read the files, on each connection.
+    pub(crate) fn load(&self) -> Result<OwnedTLSConfig> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|err| Error::InvalidConfig(format!("{}: {}", path.display(), err)))
+        };
+
+        let cert_chain = match &self.cert_chain {
+            Some(path) => Some(String::from_utf8(read(path)?)?),
+            None => None,
+        };
+        let identity = match &self.identity {
+            Some(path) => Some(OwnedIdentity {
+                der: read(path)?,
+                password: self.identity_password.clone(),
+            }),
+            None => None,
+        };
+
+        Ok(OwnedTLSConfig { identity, cert_chain })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct RetryConfig {
+    pub exchange: String,
+    pub max_attempts: u32,
+    #[serde(default)]
+    pub ladder: Vec<String>,
+}
+
+impl RetryConfig {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            exchange: self.exchange.clone(),
+            max_attempts: self.max_attempts,
+            ladder: self.ladder.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub disabled_categories: Vec<MetricsCategory>,
+    /// See `metrics::set_duration_buckets`.
+    pub duration_buckets: Option<Vec<f64>>,
+    /// See `metrics::set_label_headers`.
+    pub label_headers: Vec<String>,
+    pub label_header_max_values: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            disabled_categories: vec![],
+            duration_buckets: None,
+            label_headers: vec![],
+            label_header_max_values: 100,
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// Set the metrics switches of the process accordingly.
+    pub fn apply(&self) {
+        metrics::set_enabled(self.enabled);
+        for category in &self.disabled_categories {
+            metrics::set_category_enabled(*category, false);
+        }
+        if let Some(buckets) = self.duration_buckets.clone() {
+            if !metrics::set_duration_buckets(buckets) {
+                warn!("Duration buckets not applied, they were already set or a listener was already registered");
+            }
+        }
+        if !self.label_headers.is_empty() {
+            let headers: Vec<&str> = self.label_headers.iter().map(String::as_str).collect();
+            if !metrics::set_label_headers(&headers, self.label_header_max_values) {
+                warn!(?headers, "Label headers not applied, up to 2 headers can be set once");
+            }
+        }
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str) -> Result<Option<T>> {
+    env(name)
+        .map(|value| value.parse().map_err(|_| Error::InvalidConfig(format!("{name}: invalid value `{value}`"))))
+        .transpose()
+}
+
+impl BrokerConfig {
+    /// Read from the environment:
+    ///  - `AMQP_URI`: comma-separated list of URIs
+    ///  - `AMQP_SEPARATE_CONNECTIONS`: `true` or `false`
+    ///  - `AMQP_TLS_CERT_CHAIN`, `AMQP_TLS_IDENTITY` and `AMQP_TLS_IDENTITY_PASSWORD`
+    ///  - `AMQP_PREFETCH` and `AMQP_MAX_CONCURRENT_TASKS`
+    ///  - `AMQP_RETRY_EXCHANGE` and `AMQP_RETRY_MAX_ATTEMPTS`
+    ///  - `AMQP_METRICS_ENABLED`: `true` or `false`
+    ///  - `AMQP_TOPOLOGY_PREFIX` and `AMQP_TOPOLOGY_SUFFIX`
+    pub fn from_env() -> Result<Self> {
+        let uris = env("AMQP_URI")
+            .map(|uris| uris.split(',').map(|uri| uri.trim().to_string()).collect())
+            .unwrap_or_default();
+
+        let tls = TlsConfig {
+            cert_chain: env("AMQP_TLS_CERT_CHAIN").map(PathBuf::from),
+            identity: env("AMQP_TLS_IDENTITY").map(PathBuf::from),
+            identity_password: env("AMQP_TLS_IDENTITY_PASSWORD").unwrap_or_default(),
+        };
+
+        let retry = match env("AMQP_RETRY_EXCHANGE") {
+            Some(exchange) => Some(RetryConfig {
+                exchange,
+                max_attempts: parse_env("AMQP_RETRY_MAX_ATTEMPTS")?.unwrap_or(3),
+                ladder: vec![],
+            }),
+            None => None,
+        };
+
+        Ok(Self {
+            uris,
+            separate_connections: parse_env("AMQP_SEPARATE_CONNECTIONS")?.unwrap_or(false),
+            tls: (tls != TlsConfig::default()).then_some(tls),
+            prefetch: parse_env("AMQP_PREFETCH")?,
+            max_concurrent_tasks: parse_env("AMQP_MAX_CONCURRENT_TASKS")?,
+            retry,
+            metrics: MetricsConfig {
+                enabled: parse_env("AMQP_METRICS_ENABLED")?.unwrap_or(true),
+                ..MetricsConfig::default()
+            },
+            topology_prefix: env("AMQP_TOPOLOGY_PREFIX").unwrap_or_default(),
+            topology_suffix: env("AMQP_TOPOLOGY_SUFFIX").unwrap_or_default(),
+            reconnect: None,
+        })
+    }
+
+    #[cfg(feature = "config-toml")]
+    pub fn from_toml_file(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::InvalidConfig(format!("{}: {}", path.display(), err)))?;
+
+        toml::from_str(&content).map_err(|err| Error::InvalidConfig(format!("{}: {}", path.display(), err)))
+    }
+}
@@ -0,0 +1,211 @@
+//! Confirmation of a publish, recording its outcome once known.
+
+use crate::audit::{AuditRecord, AuditSink, ConfirmOutcome};
+use lapin::publisher_confirm::{Confirmation, PublisherConfirm};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::sync::Mutex;
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+
+/// What the publisher needs to know about a publish to record its outcome.
+pub(crate) struct PublishTracker {
+    pub(crate) exchange: String,
+    pub(crate) routing_key: String,
+    pub(crate) message_id: Option<String>,
+    pub(crate) size: usize,
+    pub(crate) audit: Option<Arc<dyn AuditSink>>,
+    /// The `amqp_publish` span, the outcome is recorded in its `confirm` field.
+    pub(crate) span: tracing::Span,
+    /// Slot in the confirm window of the publisher, freed once the outcome is known.
+    pub(crate) window_permit: Option<OwnedSemaphorePermit>,
+    /// Told the outcome, for the delivery being handled to wait for it, see `Downstream`.
+    pub(crate) downstream: Option<oneshot::Sender<ConfirmOutcome>>,
+}
+
+impl PublishTracker {
+    pub(crate) fn finish(self, confirm: ConfirmOutcome) {
+        drop(self.window_permit);
+        if let Some(downstream) = self.downstream {
+            let _ = downstream.send(confirm.clone());
+        }
+        crate::metrics::count_confirm(&self.exchange, &confirm);
+        self.span.record("confirm", confirm.as_str());
+
+        if let Some(audit) = self.audit {
+            audit.record(&AuditRecord {
+                exchange: self.exchange,
+                routing_key: self.routing_key,
+                message_id: self.message_id,
+                size: self.size,
+                confirm,
+            });
+        }
+    }
+}
+
+impl std::fmt::Debug for PublishTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PublishTracker")
+            .field("exchange", &self.exchange)
+            .field("routing_key", &self.routing_key)
+            .field("message_id", &self.message_id)
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `Error::NotConfirmed` unless the broker acked the publish to `exchange`.
+pub(crate) fn confirmed(exchange: &str, confirmation: Confirmation) -> crate::Result<()> {
+    match outcome(&Ok(confirmation)) {
+        ConfirmOutcome::Ack => Ok(()),
+        outcome => Err(crate::Error::NotConfirmed {
+            exchange: exchange.to_string(),
+            outcome: outcome.as_str(),
+        }),
+    }
+}
+
+pub(crate) fn outcome(res: &lapin::Result<Confirmation>) -> ConfirmOutcome {
+    match res {
+        Ok(Confirmation::Ack(Some(_)) | Confirmation::Nack(Some(_))) => ConfirmOutcome::Returned,
+        Ok(Confirmation::Ack(None)) => ConfirmOutcome::Ack,
+        Ok(Confirmation::Nack(None)) => ConfirmOutcome::Nack,
+        Ok(Confirmation::NotRequested) => ConfirmOutcome::NotRequested,
+        Err(err) => ConfirmOutcome::Failed(err.to_string()),
+    }
+}
+
+/// The broker's confirmation of a publish, await it exactly like lapin's `PublisherConfirm`.
+#[derive(Debug)]
+pub struct PublishConfirm {
+    /// `None` once handed to a local listener instead of the broker, see `echo::LocalEcho::Instead`.
+    inner: Option<PublisherConfirm>,
+    /// Whether the local listener acked the delivery, when handed to it instead of the broker.
+    local: Option<oneshot::Receiver<bool>>,
+    /// The broker's confirmation, awaited by a task which releases the slot of the confirm window as soon as it arrives.
+    awaited: Option<oneshot::Receiver<lapin::Result<Confirmation>>>,
+    tracker: Option<PublishTracker>,
+}
+
+impl PublishConfirm {
+    pub(crate) fn new(inner: PublisherConfirm, tracker: PublishTracker) -> Self {
+        // the window must not depend on when, or whether, the caller awaits the confirm
+        if tracker.window_permit.is_some() && crate::runtime::can_spawn() {
+            let (tx, rx) = oneshot::channel();
+            crate::tasks::spawn(crate::tasks::TaskKind::Background, "amqp-window-confirm", async move {
+                let res = inner.await;
+                tracker.finish(outcome(&res));
+                let _ = tx.send(res);
+            });
+
+            return Self {
+                inner: None,
+                local: None,
+                awaited: Some(rx),
+                tracker: None,
+            };
+        }
+
+        Self {
+            inner: Some(inner),
+            local: None,
+            awaited: None,
+            tracker: Some(tracker),
+        }
+    }
+
+    /// Resolves to an ack once the local listener acked the delivery, to a nack otherwise.
+    pub(crate) fn local(tracker: PublishTracker, acked: oneshot::Receiver<bool>) -> Self {
+        Self {
+            inner: None,
+            local: Some(acked),
+            awaited: None,
+            tracker: Some(tracker),
+        }
+    }
+}
+
+impl Future for PublishConfirm {
+    type Output = lapin::Result<Confirmation>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // already recorded by the task
+        if let Some(awaited) = self.awaited.as_mut() {
+            return Pin::new(awaited).poll(cx).map(|res| {
+                res.unwrap_or_else(|_| {
+                    Err(lapin::Error::IOError(Arc::new(std::io::Error::other("the task awaiting the confirm is gone"))))
+                })
+            });
+        }
+
+        let res = if let Some(inner) = self.inner.as_mut() {
+            match Pin::new(inner).poll(cx) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            }
+        } else if let Some(local) = self.local.as_mut() {
+            match Pin::new(local).poll(cx) {
+                Poll::Ready(Ok(true)) => Ok(Confirmation::Ack(None)),
+                // rejected, or the task handling it is gone
+                Poll::Ready(_) => Ok(Confirmation::Nack(None)),
+                Poll::Pending => return Poll::Pending,
+            }
+        } else {
+            Ok(Confirmation::Ack(None))
+        };
+
+        if let Some(tracker) = self.tracker.take() {
+            tracker.finish(outcome(&res));
+        }
+
+        Poll::Ready(res)
+    }
+}
+
+impl Drop for PublishConfirm {
+    fn drop(&mut self) {
+        let Some(tracker) = self.tracker.take() else {
+            return;
+        };
+
+        // a delivery waits for this outcome, it's still awaited once the handler dropped it
+        if tracker.downstream.is_some() && crate::runtime::can_spawn() {
+            if let Some(inner) = self.inner.take() {
+                crate::tasks::spawn(crate::tasks::TaskKind::Background, "amqp-downstream-confirm", async move {
+                    let res = inner.await;
+                    tracker.finish(outcome(&res));
+                });
+                return;
+            }
+        }
+
+        tracker.finish(ConfirmOutcome::Unknown);
+    }
+}
+
+/// The publishes made while handling a delivery, whose confirmations it waits for before being acked.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Downstream {
+    outcomes: Arc<Mutex<Vec<oneshot::Receiver<ConfirmOutcome>>>>,
+}
+
+impl Downstream {
+    pub(crate) fn track(&self) -> oneshot::Sender<ConfirmOutcome> {
+        let (tx, rx) = oneshot::channel();
+        self.outcomes.lock().unwrap().push(rx);
+        tx
+    }
+
+    /// Whether every publish so far was acked by the broker.
+    pub(crate) async fn confirmed(&self) -> bool {
+        let outcomes = std::mem::take(&mut *self.outcomes.lock().unwrap());
+
+        let mut confirmed = true;
+        for outcome in outcomes {
+            confirmed &= outcome.await == Ok(ConfirmOutcome::Ack);
+        }
+        confirmed
+    }
+}
@@ -0,0 +1,52 @@
+//! What a listener may need besides the delivery, see `BrokerListener::consume_with_context`.
+
+use crate::retry::RetryInfo;
+use crate::Publisher;
+use lapin::message::Delivery;
+use lapin::types::FieldTable;
+use lapin::Channel;
+
+#[derive(Debug)]
+pub struct ConsumeContext {
+    publisher: Publisher,
+    channel: Channel,
+    headers: FieldTable,
+    retry_info: RetryInfo,
+    content_encoding: Option<String>,
+}
+
+impl ConsumeContext {
+    pub(crate) fn new(delivery: &Delivery, publisher: Publisher, channel: Channel) -> Self {
+        Self {
+            publisher,
+            channel,
+            headers: delivery.properties.headers().clone().unwrap_or_default(),
+            retry_info: RetryInfo::from_delivery(delivery),
+            content_encoding: delivery.properties.content_encoding().as_ref().map(|encoding| encoding.to_string()),
+        }
+    }
+
+    /// The publisher of the broker (the consumer's channel when none was set up), to publish follow-up messages
+    /// through the same instrumented path.
+    pub fn publisher(&self) -> &Publisher {
+        &self.publisher
+    }
+
+    /// The main channel of the consumer.
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    pub fn headers(&self) -> &FieldTable {
+        &self.headers
+    }
+
+    pub fn retry_info(&self) -> &RetryInfo {
+        &self.retry_info
+    }
+
+    /// `content_encoding` as published, the payload is already decompressed when it's a supported one.
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.content_encoding.as_deref()
+    }
+}
@@ -0,0 +1,98 @@
+//! Consumption of a dead-letter queue: the original payload decoded, with why and where it was dead-lettered.
+//! See `Consumer::on_dead_letter`.
+
+use crate::retry::{self, DeathRecord, RetryInfo};
+use crate::{format, headers, rejection, BrokerListener, Rejection};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use lapin::message::Delivery;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::marker::PhantomData;
+
+/// A dead-lettered message, out of its payload and its `x-death` and `x-rejection-reason` headers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter<T> {
+    pub payload: T,
+    /// Set when dead-lettered by this crate, the `reason` of the most recent `x-death` entry otherwise.
+    pub reason: Option<String>,
+    /// Exchange and routing key the message was first published to.
+    pub original_exchange: Option<String>,
+    pub original_routing_key: String,
+    /// Failed attempts before it was dead-lettered.
+    pub attempts: u32,
+    pub first_dead_lettered_at: Option<DateTime<Utc>>,
+    /// Each `x-death` entry, most recent first.
+    pub deaths: Vec<DeathRecord>,
+}
+
+impl<T> DeadLetter<T> {
+    pub fn from_delivery(delivery: &Delivery, payload: T) -> Self {
+        let info = RetryInfo::from_delivery(delivery);
+        let reason = headers::get(delivery, rejection::REASON_HEADER)
+            .and_then(headers::as_string)
+            .or_else(|| info.reasons.first().cloned());
+        let original_exchange = headers::get(delivery, "x-first-death-exchange")
+            .and_then(headers::as_string)
+            .or_else(|| info.deaths.last().map(|death| death.exchange.clone()));
+
+        Self {
+            payload,
+            reason,
+            original_exchange,
+            original_routing_key: retry::original_routing_key(delivery),
+            attempts: info.attempts,
+            first_dead_lettered_at: info.first_failed_at,
+            deaths: info.deaths,
+        }
+    }
+}
+
+/// Listener of the dead-letter exchange, decoding `T` and passing it as a `DeadLetter` to the handler.
+pub struct DeadLetterListener<T, F> {
+    exchange: &'static str,
+    handler: F,
+    _payload: PhantomData<fn(T)>,
+}
+
+impl<T, F> std::fmt::Debug for DeadLetterListener<T, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeadLetterListener")
+            .field("exchange", &self.exchange)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, F> DeadLetterListener<T, F> {
+    pub fn new(exchange: &'static str, handler: F) -> Self {
+        Self {
+            exchange,
+            handler,
+            _payload: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<T, F, Fut> BrokerListener for DeadLetterListener<T, F>
+where
+    T: DeserializeOwned + Send + 'static,
+    F: Fn(DeadLetter<T>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::result::Result<(), Rejection>> + Send,
+{
+    fn exchange_name(&self) -> &'static str {
+        self.exchange
+    }
+
+    async fn consume(&self, delivery: &Delivery) -> std::result::Result<(), Rejection> {
+        let payload = match format::decode::<T>(delivery) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!(%err, exchange_name = self.exchange, "Failed to decode a dead letter");
+                return Err(Rejection::discard().with_reason("undecodable_dead_letter"));
+            }
+        };
+
+        (self.handler)(DeadLetter::from_delivery(delivery, payload)).await
+    }
+}
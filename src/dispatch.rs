@@ -0,0 +1,116 @@
+//! The rules deciding what becomes of a delivery, shared by the consumer and `testing::Dispatcher`
+//! so the property tests exercise them rather than a copy.
+
+use crate::confirm::Downstream;
+use crate::journal::{self, Journal};
+use crate::retry::{RedeliveryPolicy, RetryInfo, RetryPolicy};
+use crate::signing::{self, Signer};
+use crate::{expiry, headers, runtime, verify, Rejection};
+use lapin::message::Delivery;
+use std::future::Future;
+
+/// What's done with a delivery before its listener is called.
+#[derive(Debug)]
+pub(crate) enum Admission {
+    /// A publish probe, acked without reaching the listener.
+    Probe,
+    /// Not signed with a known key, never reaches the listener.
+    Unsigned,
+    /// Redelivered duplicate of a journaled delivery, acked.
+    Duplicate(String),
+    /// Past its expiry, acked.
+    Expired,
+    /// Handed to the listener, journaled with the key once it succeeded.
+    Consume { journal_key: Option<String> },
+}
+
+/// Check, in this order, whether `delivery` is signed by `signer`, is a probe of this process, is a duplicate
+/// in `journal` and is expired when the listener drops the expired deliveries.
+pub(crate) fn admit(delivery: &Delivery, signer: Option<&dyn Signer>, journal: Option<&dyn Journal>, drop_expired: bool) -> Admission {
+    if let Some(signer) = signer {
+        let signature = headers::get(delivery, signing::SIGNATURE_HEADER).and_then(headers::as_string);
+        if !signature.is_some_and(|signature| signer.verify(&delivery.data, &signature)) {
+            return Admission::Unsigned;
+        }
+    }
+
+    if verify::is_probe(delivery) {
+        return Admission::Probe;
+    }
+
+    let journal_key = journal.and_then(|_| journal::key(delivery));
+    if let (Some(journal), Some(key), true) = (journal, &journal_key, delivery.redelivered) {
+        if journal.contains(key) {
+            return Admission::Duplicate(key.clone());
+        }
+    }
+
+    if drop_expired && expiry::is_expired(delivery) {
+        return Admission::Expired;
+    }
+
+    Admission::Consume { journal_key }
+}
+
+/// Await `consume` unless `policy` rejects the redelivered deliveries, or bounds their handling.
+pub(crate) async fn consume_redelivered<F>(redelivered: bool, policy: RedeliveryPolicy, consume: F) -> Result<(), Rejection>
+where
+    F: Future<Output = Result<(), Rejection>>,
+{
+    let policy = if redelivered { policy } else { RedeliveryPolicy::Process };
+
+    match policy {
+        RedeliveryPolicy::Process => consume.await,
+        RedeliveryPolicy::DeadLetter => Err(Rejection::discard().with_reason("redelivered")),
+        RedeliveryPolicy::Timeout(timeout) => runtime::timeout(timeout, consume)
+            .await
+            .unwrap_or_else(|| Err(Rejection::discard().with_reason("redelivered_timeout"))),
+    }
+}
+
+/// A success whose derived messages weren't all acked becomes a requeue, the delivery is handled again rather than lost.
+pub(crate) async fn confirm_downstream(res: Result<(), Rejection>, downstream: Option<&Downstream>) -> Result<(), Rejection> {
+    match (res, downstream) {
+        (Ok(()), Some(downstream)) if !downstream.confirmed().await => {
+            Err(Rejection::requeue().with_reason("downstream_not_confirmed"))
+        }
+        (res, _) => res,
+    }
+}
+
+/// What's done with a handled delivery.
+#[derive(Debug)]
+pub(crate) enum Settle<'a> {
+    Ack,
+    /// Republished with `policy` for its `attempt`-th retry, with the reason of the rejection, then acked.
+    Retry { policy: &'a RetryPolicy, attempt: u32, reason: Option<String> },
+    /// The retries exhausted: `rejection`, without requeue, then `on_poison` with the reason of the last attempt.
+    Poison { attempt: u32, rejection: Rejection, last_error: Option<String> },
+    /// Rejected, or dead-lettered without requeue when the consumer has a dead-letter exchange.
+    Reject(Rejection),
+}
+
+/// Settle the outcome `res` of `delivery`, retried with `retry_policy` when it's rejected with requeue.
+pub(crate) fn settle<'a>(delivery: &Delivery, retry_policy: Option<&'a RetryPolicy>, res: Result<(), Rejection>) -> Settle<'a> {
+    let rejection = match res {
+        Ok(()) => return Settle::Ack,
+        Err(rejection) => rejection,
+    };
+
+    match retry_policy {
+        Some(policy) if rejection.requeue => {
+            let attempt = RetryInfo::from_delivery(delivery).attempts + 1;
+            if attempt < policy.max_attempts {
+                return Settle::Retry { policy, attempt, reason: rejection.reason };
+            }
+
+            let reason = rejection.reason.as_deref().unwrap_or("retries_exhausted");
+            Settle::Poison {
+                attempt,
+                rejection: Rejection::discard().with_reason(reason),
+                last_error: rejection.reason,
+            }
+        }
+        _ => Settle::Reject(rejection),
+    }
+}
@@ -0,0 +1,74 @@
+//! Local echo: a message published to an exchange with a listener in this process is dispatched to it directly,
+//! in addition to or instead of the broker round trip, e.g. while a monolith is split into services.
+//!
+//! The echoed delivery is the message as sent (encrypted and signed when configured), with a delivery tag of 0.
+//! Its ack and rejection don't reach any broker, a retry is still republished through it.
+//! A listener without a free permit isn't waited for, the message goes to the broker only.
+
+use crate::naming::TopologyAffixes;
+use crate::registry::ListenerRegistry;
+use crate::shedding::Running;
+use crate::tasks::{self, TaskKind};
+use lapin::acker::Acker;
+use lapin::message::Delivery;
+use lapin::BasicProperties;
+use tokio::sync::oneshot;
+
+/// See `Publisher::set_local_echo`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LocalEcho {
+    #[default]
+    Off,
+    /// Dispatch to the local listener and publish to the broker.
+    Also,
+    /// Only dispatch to the local listener, the broker is skipped when there is one.
+    /// The confirm of the publish is an ack once the listener acked the delivery, a nack when it rejected it.
+    Instead,
+}
+
+/// Hand the message to the listener of `exchange` when it has a free permit, named with `affixes` as the broker would,
+/// returns whether the listener will ack the delivery then, `None` when it didn't take it.
+pub(crate) async fn dispatch(
+    listeners: &ListenerRegistry,
+    affixes: &TopologyAffixes,
+    exchange: &str,
+    routing_key: &str,
+    payload: &[u8],
+    properties: &BasicProperties,
+) -> Option<oneshot::Receiver<bool>> {
+    // the settings, and their channel to retry, are only shared once the consumer is spawned
+    listeners.settings().channel.as_ref()?;
+    let listener = listeners.get_logical(exchange)?;
+    // named as the broker would, like any delivery
+    let exchange = &*affixes.resolve(exchange);
+    if let Err(err) = listener.start().await {
+        warn!(%err, exchange, "Listener not started, message not echoed locally");
+        return None;
+    }
+    let delivery = Delivery {
+        delivery_tag: 0,
+        exchange: exchange.into(),
+        routing_key: routing_key.into(),
+        redelivered: false,
+        properties: properties.clone(),
+        data: payload.to_vec(),
+        acker: Acker::default(),
+    };
+    // the publisher doesn't wait for the listener
+    let cost = listener.cost(&delivery);
+    let Ok(permit) = listener.semaphore.clone().try_acquire_many_owned(cost) else {
+        debug!(exchange, routing_key, "Local listener busy, message not echoed locally");
+        return None;
+    };
+    let permit = Running::new(permit, cost, None);
+    listener.metrics.task_started(permit.permits());
+    listener.stats.received();
+    debug!(exchange, routing_key, "Message echoed to the local listener");
+
+    let (acked, settled) = oneshot::channel();
+    tasks::spawn(TaskKind::Delivery, "amqp-local-echo", async move {
+        let _ = acked.send(crate::consume_async(delivery, listener, permit).await);
+    });
+
+    Some(settled)
+}
@@ -0,0 +1,116 @@
+//! End-to-end encryption of the message bodies.
+//!
+//! The publisher encrypts the payload after serialization and stores the key id in the `x-encryption-key-id` header,
+//! the consumer decrypts the payload before handing the delivery to a listener.
+
+use crate::Result;
+
+pub const KEY_ID_HEADER: &str = "x-encryption-key-id";
+
+pub trait Encryptor: Send + Sync {
+    /// Encrypt `plaintext`, return the id of the key used along with the ciphertext.
+    fn encrypt(&self, plaintext: &[u8]) -> Result<(String, Vec<u8>)>;
+
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Where the encryption keys come from, e.g. a KMS or a secret store, so they can be rotated:
+/// new messages use the current key, older ones are still decrypted with the key they name.
+pub trait KeyProvider: Send + Sync {
+    fn current_key_id(&self) -> String;
+
+    /// A 256 bits key
+    fn key(&self, key_id: &str) -> Option<[u8; 32]>;
+}
+
+/// A single key, never rotated.
+pub struct StaticKeyProvider {
+    key_id: String,
+    key: [u8; 32],
+}
+
+impl std::fmt::Debug for StaticKeyProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticKeyProvider")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StaticKeyProvider {
+    pub fn new(key_id: &str, key: [u8; 32]) -> Self {
+        Self {
+            key_id: key_id.to_string(),
+            key,
+        }
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn current_key_id(&self) -> String {
+        self.key_id.clone()
+    }
+
+    fn key(&self, key_id: &str) -> Option<[u8; 32]> {
+        (key_id == self.key_id).then_some(self.key)
+    }
+}
+
+/// AES-256-GCM, the payload is the 96 bits random nonce followed by the ciphertext.
+#[cfg(feature = "encryption")]
+#[derive(Debug)]
+pub struct AesGcmEncryptor<K> {
+    keys: K,
+}
+
+#[cfg(feature = "encryption")]
+impl<K: KeyProvider> AesGcmEncryptor<K> {
+    const NONCE_LEN: usize = 12;
+
+    pub fn new(keys: K) -> Self {
+        Self { keys }
+    }
+
+    fn cipher(&self, key_id: &str) -> Result<aes_gcm::Aes256Gcm> {
+        use aes_gcm::KeyInit;
+
+        let key = self
+            .keys
+            .key(key_id)
+            .ok_or_else(|| crate::Error::Encryption(format!("unknown key id `{}`", key_id)))?;
+
+        Ok(aes_gcm::Aes256Gcm::new(&key.into()))
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<K: KeyProvider> Encryptor for AesGcmEncryptor<K> {
+    fn encrypt(&self, plaintext: &[u8]) -> Result<(String, Vec<u8>)> {
+        use aes_gcm::aead::{Aead, AeadCore, OsRng};
+
+        let key_id = self.keys.current_key_id();
+        let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher(&key_id)?
+            .encrypt(&nonce, plaintext)
+            .map_err(|err| crate::Error::Encryption(err.to_string()))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend(ciphertext);
+
+        Ok((key_id, payload))
+    }
+
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        use aes_gcm::aead::Aead;
+
+        if ciphertext.len() < Self::NONCE_LEN {
+            return Err(crate::Error::Encryption("payload shorter than the nonce".to_string()));
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(Self::NONCE_LEN);
+
+        self.cipher(key_id)?
+            .decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext)
+            .map_err(|err| crate::Error::Encryption(err.to_string()))
+    }
+}
@@ -0,0 +1,116 @@
+//! Short-lived exclusive queues, as used for RPC replies and broadcast subscriptions.
+
+use crate::naming::{self, TopologyAffixes};
+use crate::tasks::{self, TaskKind};
+use crate::Result;
+use lapin::options::{BasicConsumeOptions, QueueBindOptions, QueueDeclareOptions, QueueDeleteOptions};
+use lapin::types::FieldTable;
+use lapin::Channel;
+
+/// An exclusive, auto-delete, server-named queue.
+///
+/// Bindings are remembered so `redeclare` can restore the queue on a new channel (e.g. after a reconnection),
+/// and the queue is deleted when this value is dropped.
+#[derive(Debug)]
+pub struct ExclusiveQueue {
+    channel: Channel,
+    name: String,
+    bindings: Vec<(String, String)>,
+    affixes: TopologyAffixes,
+}
+
+impl ExclusiveQueue {
+    pub async fn declare(channel: &Channel) -> Result<Self> {
+        Self::declare_with(channel, naming::topology_affixes()).await
+    }
+
+    /// Bound to the exchanges named with `affixes`.
+    pub(crate) async fn declare_with(channel: &Channel, affixes: TopologyAffixes) -> Result<Self> {
+        let name = Self::declare_on(channel).await?;
+
+        Ok(Self {
+            channel: channel.clone(),
+            name,
+            bindings: vec![],
+            affixes,
+        })
+    }
+
+    async fn declare_on(channel: &Channel) -> Result<String> {
+        let queue = channel
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        Ok(queue.name().to_string())
+    }
+
+    /// The name generated by the server, it changes on `redeclare`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    pub async fn bind(&mut self, exchange: &str, routing_key: &str) -> Result<()> {
+        self.channel
+            .queue_bind(&self.name, &self.affixes.resolve(exchange), routing_key, QueueBindOptions::default(), FieldTable::default())
+            .await?;
+        self.bindings.push((exchange.to_string(), routing_key.to_string()));
+
+        Ok(())
+    }
+
+    /// Declare the queue again on `channel` and restore its bindings.
+    pub async fn redeclare(&mut self, channel: &Channel) -> Result<()> {
+        self.name = Self::declare_on(channel).await?;
+        self.channel = channel.clone();
+
+        for (exchange, routing_key) in &self.bindings {
+            self.channel
+                .queue_bind(&self.name, &self.affixes.resolve(exchange), routing_key, QueueBindOptions::default(), FieldTable::default())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Start consuming the queue, on its channel.
+    pub async fn consume(&self, options: BasicConsumeOptions) -> Result<lapin::Consumer> {
+        let consumer = self
+            .channel
+            .basic_consume(&self.name, "", options, FieldTable::default())
+            .await?;
+
+        Ok(consumer)
+    }
+}
+
+impl Drop for ExclusiveQueue {
+    fn drop(&mut self) {
+        // the broker would delete it with the connection anyway, but long-lived connections would pile them up
+        if !self.channel.status().connected() {
+            return;
+        }
+        if !crate::runtime::can_spawn() {
+            return;
+        }
+
+        let channel = self.channel.clone();
+        let name = std::mem::take(&mut self.name);
+        tasks::spawn(TaskKind::Background, "amqp-exclusive-queue-delete", async move {
+            if let Err(err) = channel.queue_delete(&name, QueueDeleteOptions::default()).await {
+                debug!(%err, queue = %name, "Failed to delete the exclusive queue");
+            }
+        });
+    }
+}
@@ -0,0 +1,31 @@
+//! Expiry of the time-sensitive messages: a listener can drop the deliveries already stale when received,
+//! rather than working on expired jobs.
+
+use crate::headers::{self, as_string, as_u64};
+use chrono::{DateTime, TimeZone, Utc};
+use lapin::message::Delivery;
+
+/// Unix timestamp (seconds), or RFC 3339 date, after which the message is stale.
+pub const EXPIRES_AT_HEADER: &str = "x-expires-at";
+
+/// When `delivery` expires: its `x-expires-at` header, or else its AMQP `expiration` (ms) after its `timestamp`.
+pub fn expires_at(delivery: &Delivery) -> Option<DateTime<Utc>> {
+    if let Some(value) = headers::get(delivery, EXPIRES_AT_HEADER) {
+        return match as_u64(value) {
+            Some(secs) => Utc.timestamp_opt(secs as i64, 0).single(),
+            None => as_string(value)
+                .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                .map(|date| date.with_timezone(&Utc)),
+        };
+    }
+
+    let timestamp = Utc.timestamp_opt(*delivery.properties.timestamp().as_ref()? as i64, 0).single()?;
+    let expiration: i64 = delivery.properties.expiration().as_ref()?.as_str().parse().ok()?;
+
+    Some(timestamp + chrono::Duration::milliseconds(expiration))
+}
+
+/// Whether `delivery` is already stale.
+pub fn is_expired(delivery: &Delivery) -> bool {
+    expires_at(delivery).is_some_and(|expires_at| expires_at <= Utc::now())
+}
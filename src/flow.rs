@@ -0,0 +1,39 @@
+//! Publishing while the broker throttles the connection (`connection.blocked`, e.g. on a memory or disk alarm).
+//!
+//! lapin keeps buffering the frames of a blocked connection, so without a policy a publish and its confirmation
+//! just hang until the broker unblocks it.
+
+use crate::{runtime, Error, Result};
+use lapin::ConnectionStatus;
+use std::time::Duration;
+
+/// What a publish does while its connection is blocked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockedPolicy {
+    /// Wait until the broker unblocks the connection.
+    #[default]
+    Wait,
+    /// Fail right away with `Error::BrokerBlocked`.
+    FailFast,
+}
+
+/// Wait for `status` to be unblocked, or fail, according to `policy`.
+pub(crate) async fn check(status: &ConnectionStatus, policy: BlockedPolicy) -> Result<()> {
+    if !status.blocked() {
+        return Ok(());
+    }
+
+    match policy {
+        BlockedPolicy::FailFast => Err(Error::BrokerBlocked),
+        BlockedPolicy::Wait => {
+            warn!("Connection blocked by the broker, waiting to publish");
+            // lapin doesn't notify the unblocking
+            while status.blocked() {
+                runtime::sleep(Duration::from_millis(100)).await;
+            }
+            info!("Connection unblocked by the broker");
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Decode payloads whatever their producer: by their `content_type`, or by sniffing their first bytes when
+//! it's missing (legacy producers setting no properties). JSON needs the `json` feature, MessagePack the `msgpack` one.
+//! And encode them, with the `Codec` of the publisher or of the message.
+
+use crate::{metrics, Error, Result};
+use lapin::message::Delivery;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+#[cfg(feature = "json")]
+use serde_json::Value;
+
+/// How many bytes are looked at when sniffing.
+const SNIFF_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Json,
+    /// The `bincode` serialization of this crate's publisher, or any other binary payload.
+    Bincode,
+    /// Only told by the content type, never sniffed.
+    MsgPack,
+}
+
+impl PayloadFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PayloadFormat::Json => "json",
+            PayloadFormat::Bincode => "bincode",
+            PayloadFormat::MsgPack => "msgpack",
+        }
+    }
+
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        match mime {
+            "application/json" => Some(PayloadFormat::Json),
+            mime if mime.ends_with("+json") => Some(PayloadFormat::Json),
+            "application/octet-stream" | "application/x-bincode" => Some(PayloadFormat::Bincode),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(PayloadFormat::MsgPack),
+            _ => None,
+        }
+    }
+
+    /// JSON when, past the whitespaces, the payload opens an object or an array and stays UTF-8.
+    pub fn sniff(data: &[u8]) -> Self {
+        let head = &data[..data.len().min(SNIFF_LEN)];
+        let utf8 = match std::str::from_utf8(head) {
+            Ok(_) => true,
+            // cut in the middle of a character
+            Err(err) => err.error_len().is_none(),
+        };
+
+        match head.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{' | b'[') if utf8 => PayloadFormat::Json,
+            _ => PayloadFormat::Bincode,
+        }
+    }
+}
+
+/// The format of `delivery`, told by its content type or sniffed, counted in `amqp_payload_format_total`.
+pub fn detect(delivery: &Delivery) -> PayloadFormat {
+    let told = delivery
+        .properties
+        .content_type()
+        .as_ref()
+        .and_then(|content_type| PayloadFormat::from_content_type(content_type.as_str()));
+
+    let (format, source) = match told {
+        Some(format) => (format, "content_type"),
+        None => (PayloadFormat::sniff(&delivery.data), "sniffed"),
+    };
+    metrics::count_payload_format(delivery.exchange.as_str(), format.as_str(), source);
+
+    format
+}
+
+/// Deserialize the payload of `delivery` after its detected format.
+pub fn decode<T: DeserializeOwned>(delivery: &Delivery) -> Result<T> {
+    detect(delivery).decode(&delivery.data)
+}
+
+/// Serialization of the payloads, see `Publisher::set_format` and `Publisher::publish_with_codec`.
+/// Other formats can be plugged in by implementing it, and set on a publisher as a `DynCodec`.
+pub trait Codec {
+    /// Set as the `content_type` of the messages published.
+    fn content_type(&self) -> &'static str;
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn content_type(&self) -> &'static str {
+        "application/x-bincode"
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|err| Error::Encode(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        serde_json::from_slice(data).map_err(|err| Error::Decode(err.to_string()))
+    }
+}
+
+/// MessagePack, through the serde values: the binaries come back as arrays of bytes.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPackCodec {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        let value = serde_json::to_value(value).map_err(|err| Error::Encode(err.to_string()))?;
+        crate::msgpack::encode(&value)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        serde_json::from_value(crate::msgpack::decode(data)?).map_err(|err| Error::Decode(err.to_string()))
+    }
+}
+
+/// The object safe `Codec`, over the serde values, so it can be plugged in a publisher with `Publisher::set_codec`.
+/// Every `Codec` is one, only the self-describing formats can decode though: not bincode.
+#[cfg(feature = "json")]
+pub trait DynCodec: Send + Sync + std::fmt::Debug {
+    /// `Codec::content_type`, named apart so both traits can be in scope.
+    fn media_type(&self) -> &'static str;
+
+    fn encode_value(&self, value: &Value) -> Result<Vec<u8>>;
+
+    fn decode_value(&self, data: &[u8]) -> Result<Value>;
+}
+
+#[cfg(feature = "json")]
+impl<C: Codec + Send + Sync + std::fmt::Debug> DynCodec for C {
+    fn media_type(&self) -> &'static str {
+        self.content_type()
+    }
+
+    fn encode_value(&self, value: &Value) -> Result<Vec<u8>> {
+        self.encode(value)
+    }
+
+    fn decode_value(&self, data: &[u8]) -> Result<Value> {
+        self.decode(data)
+    }
+}
+
+#[cfg(feature = "json")]
+impl Codec for dyn DynCodec {
+    fn content_type(&self) -> &'static str {
+        self.media_type()
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        let value = serde_json::to_value(value).map_err(|err| Error::Encode(err.to_string()))?;
+        self.encode_value(&value)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        serde_json::from_value(self.decode_value(data)?).map_err(|err| Error::Decode(err.to_string()))
+    }
+}
+
+/// The codec of the format, JSON needing the `json` feature and MessagePack the `msgpack` one.
+impl Codec for PayloadFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            PayloadFormat::Json => "application/json",
+            PayloadFormat::Bincode => BincodeCodec.content_type(),
+            PayloadFormat::MsgPack => "application/msgpack",
+        }
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            PayloadFormat::Bincode => BincodeCodec.encode(value),
+            #[cfg(feature = "json")]
+            PayloadFormat::Json => JsonCodec.encode(value),
+            #[cfg(not(feature = "json"))]
+            PayloadFormat::Json => Err(Error::Encode("encoding JSON needs the `json` feature".to_string())),
+            #[cfg(feature = "msgpack")]
+            PayloadFormat::MsgPack => MsgPackCodec.encode(value),
+            #[cfg(not(feature = "msgpack"))]
+            PayloadFormat::MsgPack => Err(Error::Encode("encoding MessagePack needs the `msgpack` feature".to_string())),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        match self {
+            PayloadFormat::Bincode => BincodeCodec.decode(data),
+            #[cfg(feature = "json")]
+            PayloadFormat::Json => JsonCodec.decode(data),
+            #[cfg(not(feature = "json"))]
+            PayloadFormat::Json => Err(Error::Decode("JSON payload, decoding it needs the `json` feature".to_string())),
+            #[cfg(feature = "msgpack")]
+            PayloadFormat::MsgPack => MsgPackCodec.decode(data),
+            #[cfg(not(feature = "msgpack"))]
+            PayloadFormat::MsgPack => Err(Error::Decode(
+                "MessagePack payload, decoding it needs the `msgpack` feature".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_short_payloads() {
+        assert_eq!(PayloadFormat::sniff(b""), PayloadFormat::Bincode);
+        assert_eq!(PayloadFormat::sniff(b" \n\t"), PayloadFormat::Bincode);
+        assert_eq!(PayloadFormat::sniff(b"{"), PayloadFormat::Json);
+        assert_eq!(PayloadFormat::sniff(b"\n [1]"), PayloadFormat::Json);
+        assert_eq!(PayloadFormat::sniff(b"1"), PayloadFormat::Bincode);
+    }
+
+    #[test]
+    fn sniff_json_from_binary() {
+        assert_eq!(PayloadFormat::sniff(br#"{"id": 1}"#), PayloadFormat::Json);
+        // a brace followed by bytes which aren't UTF-8
+        assert_eq!(PayloadFormat::sniff(b"{\xff\xfe\x00"), PayloadFormat::Bincode);
+        // the bincode of a `u32` and a string
+        assert_eq!(PayloadFormat::sniff(&[7, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, b'a', b'b', b'c']), PayloadFormat::Bincode);
+    }
+
+    #[test]
+    fn sniff_only_reads_the_head() {
+        // invalid UTF-8 past the sniffed bytes
+        let mut data = format!("[{}", " ".repeat(SNIFF_LEN)).into_bytes();
+        data.extend([0xff, 0xfe]);
+        assert_eq!(PayloadFormat::sniff(&data), PayloadFormat::Json);
+
+        // a character cut by the end of the head
+        let data = format!("[\"{}é\"]", "a".repeat(SNIFF_LEN - 3));
+        assert!(!data.is_char_boundary(SNIFF_LEN));
+        assert_eq!(PayloadFormat::sniff(data.as_bytes()), PayloadFormat::Json);
+
+        // but not invalid bytes within it
+        let mut data = b"[\xff".to_vec();
+        data.extend(b" ".repeat(SNIFF_LEN));
+        assert_eq!(PayloadFormat::sniff(&data), PayloadFormat::Bincode);
+    }
+
+    #[test]
+    fn content_types() {
+        assert_eq!(PayloadFormat::from_content_type("application/json; charset=utf-8"), Some(PayloadFormat::Json));
+        assert_eq!(PayloadFormat::from_content_type("application/vnd.orders+json"), Some(PayloadFormat::Json));
+        assert_eq!(PayloadFormat::from_content_type("application/octet-stream"), Some(PayloadFormat::Bincode));
+        assert_eq!(PayloadFormat::from_content_type("text/plain"), None);
+    }
+}
@@ -0,0 +1,41 @@
+//! Startup barrier: the consumer doesn't pull any delivery before the application opens the gate,
+//! e.g. once its HTTP server, caches and migrations are ready.
+
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Closed until `open` is called, on any of its clones. See `Consumer::set_consume_gate`.
+#[derive(Clone, Debug)]
+pub struct ConsumeGate {
+    open: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ConsumeGate {
+    fn default() -> Self {
+        Self {
+            open: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl ConsumeGate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let the consumers start pulling deliveries, it can't be closed again.
+    pub fn open(&self) {
+        self.open.send_replace(true);
+    }
+
+    pub fn is_open(&self) -> bool {
+        *self.open.borrow()
+    }
+
+    /// Resolve once the gate is open.
+    pub async fn wait_open(&self) {
+        let mut open = self.open.subscribe();
+        // the sender is kept by `self`, it can't be dropped while waiting
+        let _ = open.wait_for(|open| *open).await;
+    }
+}
@@ -0,0 +1,52 @@
+//! Reading and writing AMQP headers.
+
+use lapin::message::Delivery;
+use lapin::types::AMQPValue;
+use lapin::BasicProperties;
+
+pub(crate) fn get<'a>(delivery: &'a Delivery, name: &str) -> Option<&'a AMQPValue> {
+    delivery
+        .properties
+        .headers()
+        .as_ref()?
+        .inner()
+        .get(name)
+}
+
+pub(crate) fn get_property<'a>(properties: &'a BasicProperties, name: &str) -> Option<&'a AMQPValue> {
+    properties.headers().as_ref()?.inner().get(name)
+}
+
+/// Return `properties` with the header `name` set to `value`.
+pub(crate) fn insert(properties: BasicProperties, name: &str, value: AMQPValue) -> BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    headers.insert(name.into(), value);
+
+    properties.with_headers(headers)
+}
+
+pub(crate) fn long_string(value: &str) -> AMQPValue {
+    AMQPValue::LongString(value.into())
+}
+
+pub(crate) fn as_u64(value: &AMQPValue) -> Option<u64> {
+    match value {
+        AMQPValue::ShortShortInt(v) => u64::try_from(*v).ok(),
+        AMQPValue::ShortShortUInt(v) => Some(*v as u64),
+        AMQPValue::ShortInt(v) => u64::try_from(*v).ok(),
+        AMQPValue::ShortUInt(v) => Some(*v as u64),
+        AMQPValue::LongInt(v) => u64::try_from(*v).ok(),
+        AMQPValue::LongUInt(v) => Some(*v as u64),
+        AMQPValue::LongLongInt(v) => u64::try_from(*v).ok(),
+        AMQPValue::Timestamp(v) => Some(*v),
+        _ => None,
+    }
+}
+
+pub(crate) fn as_string(value: &AMQPValue) -> Option<String> {
+    match value {
+        AMQPValue::ShortString(v) => Some(v.to_string()),
+        AMQPValue::LongString(v) => Some(String::from_utf8_lossy(v.as_bytes()).into_owned()),
+        _ => None,
+    }
+}
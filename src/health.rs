@@ -0,0 +1,82 @@
+//! Health of the downstream dependencies of the listeners: while one of them is unhealthy the consumer stops
+//! pulling deliveries, rather than failing and retrying all of them.
+
+use crate::runtime::{self, JoinHandle};
+use crate::tasks::{self, TaskKind};
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Shared handle on the dependencies currently unhealthy, see `Consumer::health`.
+#[derive(Clone, Debug)]
+pub struct Health {
+    unhealthy: Arc<Mutex<HashSet<String>>>,
+    healthy: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            unhealthy: Arc::default(),
+            healthy: Arc::new(watch::channel(true).0),
+        }
+    }
+}
+
+impl Health {
+    pub fn set_unhealthy(&self, dependency: &str) {
+        let mut unhealthy = self.unhealthy.lock().unwrap();
+        if unhealthy.insert(dependency.to_string()) {
+            warn!(dependency, "Dependency unhealthy, pausing the consumption");
+        }
+        self.healthy.send_replace(false);
+    }
+
+    pub fn set_healthy(&self, dependency: &str) {
+        let mut unhealthy = self.unhealthy.lock().unwrap();
+        if unhealthy.remove(dependency) {
+            info!(dependency, "Dependency healthy again");
+        }
+        self.healthy.send_replace(unhealthy.is_empty());
+    }
+
+    /// Whether every dependency is healthy.
+    pub fn is_healthy(&self) -> bool {
+        *self.healthy.borrow()
+    }
+
+    /// The dependencies currently unhealthy.
+    pub fn unhealthy(&self) -> Vec<String> {
+        self.unhealthy.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Resolve once every dependency is healthy.
+    pub async fn wait_healthy(&self) {
+        let mut healthy = self.healthy.subscribe();
+        // the sender is kept by `self`, it can't be dropped while waiting
+        let _ = healthy.wait_for(|healthy| *healthy).await;
+    }
+
+    /// Run `check` every `interval` and mark `dependency` healthy or not according to its result.
+    pub fn spawn_check<F, Fut>(&self, dependency: &'static str, interval: Duration, check: F) -> JoinHandle<()>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = bool> + Send,
+    {
+        let health = self.clone();
+
+        tasks::spawn(TaskKind::Background, "amqp-health-check", async move {
+            loop {
+                if check().await {
+                    health.set_healthy(dependency);
+                } else {
+                    health.set_unhealthy(dependency);
+                }
+
+                runtime::sleep(interval).await;
+            }
+        })
+    }
+}
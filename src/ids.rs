@@ -0,0 +1,93 @@
+//! Generation of the message ids of the publishes without one: random UUIDv4 by default,
+//! or time-sortable ids, which keep the logs in order and the keys of a dedup store close together.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub trait IdGenerator: Send + Sync + fmt::Debug {
+    fn generate(&self) -> String;
+}
+
+static GENERATOR: RwLock<Option<Arc<dyn IdGenerator>>> = RwLock::new(None);
+
+/// Replace the message id generator of the whole crate, `None` goes back to `UuidV4`.
+pub fn set_id_generator(generator: Option<Arc<dyn IdGenerator>>) {
+    *GENERATOR.write().unwrap() = generator;
+}
+
+pub(crate) fn generate() -> String {
+    match GENERATOR.read().unwrap().as_ref() {
+        Some(generator) => generator.generate(),
+        None => Uuid::new_v4().to_string(),
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UuidV4;
+
+impl IdGenerator for UuidV4 {
+    fn generate(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// UUIDv7: the unix time in milliseconds followed by random bits.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UuidV7;
+
+impl IdGenerator for UuidV7 {
+    fn generate(&self) -> String {
+        // the random bits and the variant of a v4, with the timestamp and the version of a v7
+        let mut bytes = *Uuid::new_v4().as_bytes();
+        bytes[..6].copy_from_slice(&unix_millis().to_be_bytes()[2..]);
+        bytes[6] = (bytes[6] & 0x0f) | 0x70;
+
+        Uuid::from_bytes(bytes).to_string()
+    }
+}
+
+/// Milliseconds since 2020-01-01 of the snowflakes.
+const SNOWFLAKE_EPOCH: u64 = 1_577_836_800_000;
+
+/// Snowflake, in decimal: 41 bits of milliseconds since 2020, 10 bits of node id and a 12 bits sequence.
+/// Give every publishing instance its own node id for the ids to be unique.
+#[derive(Debug)]
+pub struct Snowflake {
+    node_id: u64,
+    /// Millisecond and sequence of the last id.
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflake {
+    /// Only the 10 lower bits of `node_id` are used.
+    pub fn new(node_id: u16) -> Self {
+        Self {
+            node_id: u64::from(node_id) & 0x3ff,
+            last: Mutex::new((0, 0)),
+        }
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn generate(&self) -> String {
+        let mut last = self.last.lock().unwrap();
+        let now = unix_millis().saturating_sub(SNOWFLAKE_EPOCH);
+
+        // the clock going back or the sequence running out, borrow from the next millisecond
+        *last = if now > last.0 {
+            (now, 0)
+        } else if last.1 < 0xfff {
+            (last.0, last.1 + 1)
+        } else {
+            (last.0 + 1, 0)
+        };
+
+        ((last.0 << 22) | (self.node_id << 12) | last.1).to_string()
+    }
+}
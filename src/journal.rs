@@ -0,0 +1,213 @@
+//! Journal of the handled deliveries, by exchange and message id, so a consumer which crashed between the success
+//! of a handler and its ack skips the redelivered duplicate instead of handling it twice.
+//!
+//! A delivery is journaled once its handler succeeded and before it's acked, only the redelivered ones are looked up.
+//! The deliveries without a message id aren't journaled.
+
+use lapin::message::Delivery;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Where the handled deliveries are recorded, it has to survive the restarts to be of any use: a file, a database...
+pub trait Journal: Send + Sync + fmt::Debug {
+    fn contains(&self, key: &str) -> bool;
+
+    fn record(&self, key: &str);
+}
+
+/// Journal key of `delivery`, `None` without message id.
+pub fn key(delivery: &Delivery) -> Option<String> {
+    let message_id = delivery.properties.message_id().as_ref()?;
+
+    Some(format!("{}/{}", delivery.exchange, message_id))
+}
+
+/// The last `capacity` keys recorded.
+#[derive(Debug)]
+struct Recent {
+    capacity: usize,
+    order: VecDeque<String>,
+    keys: HashSet<String>,
+}
+
+impl Recent {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            order: VecDeque::new(),
+            keys: HashSet::new(),
+        }
+    }
+
+    fn insert(&mut self, key: &str) -> bool {
+        if !self.keys.insert(key.to_string()) {
+            return false;
+        }
+        self.order.push_back(key.to_string());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// In memory only: it doesn't survive a restart, but covers a consumer whose channel was closed after the success.
+#[derive(Debug)]
+pub struct MemoryJournal {
+    recent: Mutex<Recent>,
+}
+
+impl MemoryJournal {
+    /// Remember the last `capacity` deliveries.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            recent: Mutex::new(Recent::new(capacity)),
+        }
+    }
+}
+
+impl Journal for MemoryJournal {
+    fn contains(&self, key: &str) -> bool {
+        self.recent.lock().unwrap().keys.contains(key)
+    }
+
+    fn record(&self, key: &str) {
+        self.recent.lock().unwrap().insert(key);
+    }
+}
+
+/// A file, one key per line, appended on every record. Only the last `capacity` keys are kept when it's opened.
+#[derive(Debug)]
+pub struct FileJournal {
+    path: PathBuf,
+    recent: Mutex<Recent>,
+    file: Mutex<File>,
+}
+
+impl FileJournal {
+    /// Open or create the journal at `path`, rewritten with the last `capacity` keys.
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut recent = Recent::new(capacity);
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                recent.insert(&line?);
+            }
+        }
+
+        // compacted, so the file doesn't grow across the restarts
+        let mut file = File::create(&path)?;
+        for key in &recent.order {
+            writeln!(file, "{key}")?;
+        }
+        file.sync_data()?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+
+        Ok(Self {
+            path,
+            recent: Mutex::new(recent),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Journal for FileJournal {
+    fn contains(&self, key: &str) -> bool {
+        self.recent.lock().unwrap().keys.contains(key)
+    }
+
+    fn record(&self, key: &str) {
+        if !self.recent.lock().unwrap().insert(key) {
+            return;
+        }
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = writeln!(file, "{key}").and_then(|()| file.sync_data()) {
+            error!(%err, path = %self.path.display(), "Failed to write to the delivery journal");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::acker::Acker;
+    use lapin::BasicProperties;
+
+    fn delivery(message_id: Option<&str>) -> Delivery {
+        let mut properties = BasicProperties::default();
+        if let Some(message_id) = message_id {
+            properties = properties.with_message_id(message_id.into());
+        }
+        Delivery {
+            delivery_tag: 1,
+            exchange: "orders".into(),
+            routing_key: "created".into(),
+            redelivered: true,
+            properties,
+            data: vec![],
+            acker: Acker::default(),
+        }
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(key(&delivery(Some("42"))).as_deref(), Some("orders/42"));
+        assert_eq!(key(&delivery(None)), None);
+    }
+
+    #[test]
+    fn seen_deliveries_are_detected() {
+        let journal = MemoryJournal::new(10);
+        let key = key(&delivery(Some("42"))).unwrap();
+        assert!(!journal.contains(&key));
+        journal.record(&key);
+        assert!(journal.contains(&key));
+        assert!(!journal.contains("orders/43"));
+    }
+
+    #[test]
+    fn oldest_keys_are_evicted() {
+        let journal = MemoryJournal::new(2);
+        journal.record("a");
+        journal.record("b");
+        // recording a key again doesn't refresh it nor evict another one
+        journal.record("a");
+        assert!(journal.contains("a") && journal.contains("b"));
+
+        journal.record("c");
+        assert!(!journal.contains("a"));
+        assert!(journal.contains("b") && journal.contains("c"));
+    }
+
+    #[test]
+    fn file_journal_survives_reopening() {
+        let path = std::env::temp_dir().join(format!("journal-{}", uuid::Uuid::new_v4()));
+
+        let journal = FileJournal::open(&path, 2).unwrap();
+        for key in ["a", "b", "b", "c"] {
+            journal.record(key);
+        }
+        assert!(!journal.contains("a"));
+        drop(journal);
+
+        // compacted to the last keys when reopened
+        let journal = FileJournal::open(&path, 2).unwrap();
+        assert!(!journal.contains("a"));
+        assert!(journal.contains("b") && journal.contains("c"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "b\nc\n");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
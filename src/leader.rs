@@ -0,0 +1,98 @@
+//! Leader election over an exclusive queue: the instance whose connection declared it holds the lock, until its
+//! connection closes and the broker deletes the queue. Only the leader of a fleet runs e.g. the schedulers.
+
+use crate::health::Health;
+use crate::runtime::{self, JoinHandle};
+use crate::tasks::{self, TaskKind};
+use lapin::options::QueueDeclareOptions;
+use lapin::types::FieldTable;
+use lapin::Connection;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Whether this instance is the leader, see `Broker::elect_leader`.
+#[derive(Clone, Debug)]
+pub struct LeaderElection {
+    leader: Arc<watch::Sender<bool>>,
+}
+
+impl LeaderElection {
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    /// Notified on every change of leadership.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.leader.subscribe()
+    }
+
+    /// Resolve once this instance is the leader.
+    pub async fn wait_leader(&self) {
+        let mut leader = self.leader.subscribe();
+        // the sender is kept by `self`, it can't be dropped while waiting
+        let _ = leader.wait_for(|leader| *leader).await;
+    }
+
+    /// Keep the `leader` dependency of `health` unhealthy while this instance isn't the leader,
+    /// so a consumer of leader-only listeners only pulls deliveries on the leader.
+    pub fn pause_unless_leader(&self, health: Health) -> JoinHandle<()> {
+        let mut leader = self.leader.subscribe();
+
+        tasks::spawn(TaskKind::Background, "amqp-leader-health", async move {
+            loop {
+                if *leader.borrow_and_update() {
+                    health.set_healthy("leader");
+                } else {
+                    health.set_unhealthy("leader");
+                }
+                if leader.changed().await.is_err() {
+                    return;
+                }
+            }
+        })
+    }
+}
+
+/// Try every `interval` to declare the exclusive queue `lock`, as named on the broker, on `conn` until it succeeds, then hold it
+/// for as long as `conn` is connected. Leadership isn't regained once lost: elect again on a new connection.
+pub(crate) fn spawn(conn: Connection, lock: &str, interval: Duration) -> LeaderElection {
+    let election = LeaderElection {
+        leader: Arc::new(watch::channel(false).0),
+    };
+    let leader = election.leader.clone();
+    let lock = lock.to_string();
+
+    tasks::spawn(TaskKind::Background, "amqp-leader-election", async move {
+        // kept open, the lock is held by the connection and its channel
+        let mut held = None;
+        while conn.status().connected() {
+            if held.is_none() {
+                // a queue locked by another connection closes the channel, a new one is needed for each try
+                match conn.create_channel().await {
+                    Ok(channel) => {
+                        let options = QueueDeclareOptions {
+                            exclusive: true,
+                            auto_delete: true,
+                            ..QueueDeclareOptions::default()
+                        };
+                        if channel.queue_declare(&lock, options, FieldTable::default()).await.is_ok() {
+                            info!(lock = lock.as_str(), "Elected leader");
+                            leader.send_replace(true);
+                            held = Some(channel);
+                        }
+                    }
+                    Err(err) => warn!(%err, "Failed to open a channel for the leader election"),
+                }
+            }
+
+            runtime::sleep(interval).await;
+        }
+
+        if leader.send_replace(false) {
+            warn!(lock = lock.as_str(), "Leadership lost, the connection closed");
+        }
+    });
+
+    election
+}
@@ -1,11 +1,10 @@
-#[forbid(unsafe_code)]
+#![forbid(unsafe_code)]
 #[macro_use]
 extern crate tracing;
 
-use std::path::Path;
 pub use lapin::{
     message::Delivery, options::*, types::*, BasicProperties, Channel, Connection,
-    ConnectionProperties, ExchangeKind, Queue,
+    ExchangeKind, Queue,
 };
 
 pub mod message {
@@ -20,56 +19,117 @@ pub mod types {
     pub use lapin::types::*;
 }
 
+pub mod admin;
+pub mod audit;
+pub mod backlog;
+pub mod batch;
+pub mod canary;
+pub mod clock;
+mod compression;
+pub mod config;
+mod confirm;
+pub mod context;
+pub mod dead_letter;
+mod dispatch;
+pub mod echo;
+pub mod encryption;
+pub mod expiry;
+pub mod flow;
+pub mod format;
+pub mod gate;
+mod exclusive_queue;
+mod headers;
+pub mod health;
+pub mod ids;
+pub mod journal;
+pub mod leader;
+#[cfg(feature = "management")]
+pub mod management;
+mod merge;
+pub mod metrics;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+pub mod naming;
+pub mod partition;
+pub mod pipeline;
+pub mod reconnect;
+pub mod redact;
+pub mod republish;
+mod registry;
+pub mod rejection;
+pub mod replay;
+pub mod retry;
+pub mod rpc;
+pub mod saga;
+pub mod sequence;
+pub mod runtime;
+pub mod shared;
+pub mod shutdown;
+pub mod shedding;
+pub mod stream_end;
+pub mod signing;
+pub mod stats;
+pub mod tap;
+pub mod tasks;
+pub mod tenant;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "testing")]
+pub mod test_util;
+pub mod topology;
+pub mod trace;
+pub mod typed;
+pub mod verify;
+pub mod watchdog;
+
 use async_trait::async_trait;
-use bincode::ErrorKind;
-use futures_lite::StreamExt;
-use lapin::publisher_confirm::{Confirmation, PublisherConfirm};
+use futures_lite::{Stream, StreamExt};
+pub use exclusive_queue::ExclusiveQueue;
+pub use merge::{ConsumerStream, PriorityLanes};
+pub use registry::ListenerRegistry;
+use naming::{NamingStrategy, TopologyAffixes};
+use retry::{RedeliveryPolicy, RetryPolicy};
+use audit::{AuditSink, ConfirmOutcome};
+pub use confirm::PublishConfirm;
+pub use context::ConsumeContext;
+pub use rejection::{RejectMethod, Rejection};
+use confirm::{Downstream, PublishTracker};
+use dispatch::{Admission, Settle};
+use echo::LocalEcho;
+use encryption::Encryptor;
+use journal::Journal;
+use signing::{SignatureFailureAction, Signer};
+use reconnect::ReconnectPolicy;
+use redact::Redactor;
+use runtime::JoinHandle;
+use shared::ConnectionLease;
+use shutdown::ShutdownTimeouts;
+use stats::{ConsumerStats, StatsRecorder};
+use gate::ConsumeGate;
+use health::Health;
+use flow::BlockedPolicy;
+use format::{Codec, PayloadFormat};
+use sequence::Sequence;
+use shedding::{InFlightTimes, LoadShedding, PermitAcquisition, Running};
+use stream_end::{StreamEndAction, StreamErrorBackoff};
+use config::{BrokerConfig, RetryConfig, TlsConfig};
+use topology::Topology;
+use tasks::TaskKind;
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use serde::Serialize;
-use std::sync::Arc;
-use once_cell::sync::Lazy;
-use prometheus::{Histogram, HistogramVec, IntGaugeVec, opts, register_histogram, register_histogram_vec, register_int_gauge_vec};
-use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore, SemaphorePermit};
-use tokio::task;
-use tokio::task::JoinHandle;
-use tokio_amqp::*;
+use std::fmt;
+use tracing::Instrument;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use metrics::{ListenerMetrics, MetricsCategory};
+use tokio::sync::{AcquireError, Semaphore};
 
 pub type Requeue = bool;
 
 pub type Result<E> = std::result::Result<E, Error>;
 pub type ConsumeResult<E> = std::result::Result<E, Requeue>;
 
-static STAT_CONCURRENT_TASK: Lazy<IntGaugeVec> = Lazy::new(|| {
-    register_int_gauge_vec!(
-        opts!(
-            "amqp_consumer_concurrent_tasks",
-            "Current/Max concurrent check",
-        ),
-        &["exchange_name", "kind"],
-    ).unwrap()
-});
-
-const EXPONENTIAL_SECONDS: &[f64] = &[
-    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
-];
-
-static STAT_CONSUMER_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
-    register_histogram_vec!(
-        "amqp_consumer_duration",
-        "The duration of the consumer",
-        &["exchange_name"],
-        EXPONENTIAL_SECONDS.to_vec(),
-    ).unwrap()
-});
-
-static STAT_PUBLISHER_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
-    register_histogram_vec!(
-        "amqp_publisher_duration",
-        "The duration of the publisher",
-        &["exchange_name", "routing_key"],
-        EXPONENTIAL_SECONDS.to_vec(),
-    ).unwrap()
-});
-
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("acquire-semaphore: {0}")]
@@ -87,10 +147,71 @@ pub enum Error {
     #[error("Bincode: {0}")]
     Bincode(#[from] bincode::Error),
 
+    #[error("Payload of {size} bytes exceeds the maximum of {max} bytes")]
+    PayloadTooLarge { size: usize, max: usize },
+
+    #[error("Encryption: {0}")]
+    Encryption(String),
+
+    #[error("No reply within {0:?}")]
+    RpcTimeout(std::time::Duration),
+
+    #[error("The reply consumer stopped")]
+    RpcClosed,
+
+    #[error("Shutdown stage `{0}` timed out")]
+    ShutdownTimeout(&'static str),
+
+    #[error("{in_flight} deliveries still in flight after the drain timeout")]
+    DrainTimeout { in_flight: u64 },
+
+    #[error("The connection is blocked by the broker")]
+    BrokerBlocked,
+
+    #[error("Publish to `{exchange}` not confirmed: {outcome}")]
+    NotConfirmed { exchange: String, outcome: &'static str },
+
+    #[error("The consumer has no republish channel, see `Consumer::set_republish_channel`")]
+    NoRepublishChannel,
+
+    #[error("Decode: {0}")]
+    Decode(String),
+
+    #[error("Encode: {0}")]
+    Encode(String),
+
+    #[error("Decompression: {0}")]
+    Decompression(String),
+
+    #[error("Management API: {0}")]
+    Management(String),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Invalid tenant id `{0}`")]
+    InvalidTenant(String),
+
+    #[error("Listener of `{exchange}` failed to start: {source}")]
+    ListenerStart {
+        exchange: &'static str,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("The consumer stream ended")]
+    ConsumerEnded,
+
     #[error("Consumer: {0}")]
     ConsumerError(#[from] Box<dyn std::error::Error + Send + Sync>),
 }
 
+static DEFAULT_MAX_CONCURRENT_TASKS: AtomicUsize = AtomicUsize::new(1);
+
+/// Replace the default of `BrokerListener::max_concurrent_tasks` (1), for the listeners added from now on.
+pub fn set_default_max_concurrent_tasks(max: usize) {
+    DEFAULT_MAX_CONCURRENT_TASKS.store(max.max(1), Ordering::Relaxed);
+}
+
 /// Tag an object as Publishable
 #[async_trait]
 pub trait BrokerPublish {
@@ -103,21 +224,114 @@ pub trait BrokerListener: Send + Sync {
     /// Bind the queue & struct to this exchange name
     fn exchange_name(&self) -> &'static str;
 
+    /// Permits a delivery takes out of `max_concurrent_tasks`, e.g. after a size header, so a huge message
+    /// doesn't weigh as much as a tiny one. Capped to `max_concurrent_tasks`.
+    fn cost(&self, _delivery: &Delivery) -> u32 {
+        1
+    }
+
     /// How to process the Messages queue
     ///  - X: by spawning a task for each of them, up to some concurrent limit X (use semaphore internally)
     fn max_concurrent_tasks(&self) -> usize {
-        1
+        DEFAULT_MAX_CONCURRENT_TASKS.load(Ordering::Relaxed)
+    }
+
+    /// Republish retryable failures (`Rejection::requeue()`) to a retry exchange instead of requeueing them,
+    /// up to a maximum number of attempts
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        None
+    }
+
+    /// Record the durations of this exchange with these buckets, in `amqp_consumer_duration_custom_buckets` rather than
+    /// `amqp_consumer_duration`, e.g. for handlers running minutes (`metrics::set_duration_buckets` changes the default ones)
+    fn duration_buckets(&self) -> Option<Vec<f64>> {
+        None
+    }
+
+    /// Log a warning and count the deliveries whose handling takes longer than this
+    fn slow_threshold(&self) -> Option<std::time::Duration> {
+        None
     }
 
+    /// Reject with requeue the deliveries waiting for a permit once these thresholds are exceeded,
+    /// rather than accepting more than the listener keeps up with
+    fn load_shedding(&self) -> Option<LoadShedding> {
+        None
+    }
+
+    /// How to handle the deliveries already delivered once, e.g. to dead-letter them rather than crash again
+    fn redelivery_policy(&self) -> RedeliveryPolicy {
+        RedeliveryPolicy::Process
+    }
+
+    /// Ack without handling them the deliveries already expired when received, see `expiry::expires_at`
+    fn drop_expired(&self) -> bool {
+        false
+    }
+
+    /// Ack a handled delivery only once the messages published through its `ConsumeContext` publisher were confirmed,
+    /// requeue it otherwise
+    fn ack_after_downstream_confirm(&self) -> bool {
+        false
+    }
+
+    /// Whether the consumer waits for a permit before reading the next delivery (default), or lets the delivery
+    /// wait for it in its own task, so the deliveries of the other listeners go through while this one is busy
+    fn permit_acquisition(&self) -> PermitAcquisition {
+        PermitAcquisition::Fifo
+    }
+
+    /// `basic.reject` (default) or `basic.nack` for the failed deliveries
+    fn reject_method(&self) -> RejectMethod {
+        RejectMethod::Reject
+    }
+
+    /// Awaited before the first delivery is handed to the listener, e.g. to warm caches or open DB pools.
+    /// An error stops the consumer with `Error::ListenerStart`
+    async fn on_start(&self) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(())
+    }
+
+    /// Called during a graceful shutdown once the in-flight deliveries of the listener completed,
+    /// e.g. to flush buffers or close resources
+    async fn on_stop(&self) {}
+
+    /// Called once the shard queues consumed by a `partition::PartitionedConsumer` changed,
+    /// e.g. to load or flush the state kept per shard
+    async fn on_partitions_changed(&self, _assigned: &[String], _revoked: &[String]) {}
+
+    /// Called when a delivery exhausted its retry budget and is rejected for good (dead-lettered when configured),
+    /// e.g. to page someone or open a ticket about this specific payload
+    async fn on_poison(&self, _delivery: &Delivery, _attempts: u32, _last_error: Option<&str>) {}
+
     /// The method that will be called in the struct impl on every messages received
-    /// Err(false): reject.requeue = false
-    /// Err(true): reject.requeue = true
-    async fn consume(&self, delivery: &Delivery) -> std::result::Result<(), bool>;
+    /// Err(Rejection::discard()) or Err(false.into()): reject.requeue = false
+    /// Err(Rejection::requeue()) or Err(true.into()): reject.requeue = true
+    async fn consume(&self, delivery: &Delivery) -> std::result::Result<(), Rejection>;
+
+    /// Called instead of `consume` when overridden, for the listeners publishing follow-up messages
+    /// or looking at the retry info (`consume` can then be left `unreachable!()`)
+    async fn consume_with_context(
+        &self,
+        delivery: &Delivery,
+        _context: &ConsumeContext,
+    ) -> std::result::Result<(), Rejection> {
+        self.consume(delivery).await
+    }
 }
 
 /// AMQP Client
+#[derive(Debug)]
 pub struct Broker {
-    conn: Option<Connection>,
+    conn: Option<ConnectionLease>,
+    publisher_conn: Option<Connection>,
+    separate_connections: bool,
+    tls: Option<TlsConfig>,
+    /// Connected to by `init`, tried in order on reconnection.
+    uris: Vec<String>,
+    reconnect: Option<ReconnectPolicy>,
+    /// Of its own helpers, given to its publisher and consumer too.
+    affixes: TopologyAffixes,
     publisher: Publisher,
     consumer: Consumer,
 }
@@ -126,26 +340,240 @@ impl Broker {
     pub fn new() -> Self {
         Self {
             conn: None,
+            publisher_conn: None,
+            separate_connections: false,
+            tls: None,
+            uris: vec![],
+            reconnect: None,
+            affixes: naming::topology_affixes(),
             publisher: Publisher::new(),
             consumer: Consumer::new(),
         }
     }
 
+    /// `new`, `init`, `setup_publisher` then `setup_consumer`, for the applications (and tests)
+    /// which don't need to configure anything in between.
+    pub async fn connected(uri: &str) -> Result<Self> {
+        let mut broker = Self::new();
+        broker.init(uri).await?;
+        broker.setup_publisher().await?;
+        broker.setup_consumer().await?;
+
+        Ok(broker)
+    }
+
+    /// A broker set up after `config`: connected to its first URI accepting the connection,
+    /// with the publisher and the consumer set up.
+    pub async fn from_config(config: &BrokerConfig) -> Result<Self> {
+        config.metrics.apply();
+        if let Some(max) = config.max_concurrent_tasks {
+            set_default_max_concurrent_tasks(max);
+        }
+
+        let mut broker = Self::new();
+        broker.set_topology_affixes(TopologyAffixes::new(&config.topology_prefix, &config.topology_suffix));
+        broker.set_separate_connections(config.separate_connections);
+        broker.tls = config.tls.clone();
+        broker.reconnect = config.reconnect;
+
+        let mut res = Err(Error::InvalidConfig("no URI".to_string()));
+        for uri in &config.uris {
+            res = broker.init(uri).await;
+            match &res {
+                Ok(()) => break,
+                Err(err) => warn!(%err, "Failed to connect, trying the next URI"),
+            }
+        }
+        res?;
+        broker.uris = config.uris.clone();
+
+        broker.setup_publisher().await?;
+        let consumer = broker.setup_consumer().await?;
+        consumer.set_default_retry_policy(config.retry.as_ref().map(RetryConfig::policy));
+        if let Some(prefetch) = config.prefetch {
+            consumer.set_prefetch(prefetch).await?;
+        }
+
+        Ok(broker)
+    }
+
+    /// Name the topology of this broker, its publisher and its consumer with `affixes` rather than the ones of
+    /// `naming::set_topology_affixes`. To be set before anything is declared.
+    pub fn set_topology_affixes(&mut self, affixes: TopologyAffixes) {
+        self.publisher.set_topology_affixes(affixes.clone());
+        self.consumer.set_topology_affixes(affixes.clone());
+        if let Some(publisher) = self.consumer.publisher.as_mut() {
+            publisher.set_topology_affixes(affixes.clone());
+        }
+        self.affixes = affixes;
+    }
+
+    /// Connect over TLS with these certificates. Must be set before `init`.
+    pub fn set_tls(&mut self, tls: TlsConfig) {
+        self.tls = Some(tls);
+    }
+
+    /// Adopt a connection managed by the application (or shared with other libraries), instead of `init`.
+    pub fn with_connection(conn: Connection) -> Self {
+        Self {
+            conn: Some(ConnectionLease::owned(conn)),
+            ..Self::new()
+        }
+    }
+
+    /// On a connection shared with other brokers, see `shared::SharedConnection::broker`.
+    pub(crate) fn with_lease(conn: ConnectionLease) -> Self {
+        Self {
+            conn: Some(conn),
+            ..Self::new()
+        }
+    }
+
+    /// Reconnect with this policy once the connection died, see `run`.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect = policy;
+    }
+
+    /// Use a dedicated connection for the publisher, so a broker-initiated flow control
+    /// on publishing can never stall the consumption. Must be set before `init`.
+    pub fn set_separate_connections(&mut self, separate: bool) {
+        self.separate_connections = separate;
+    }
+
+    /// What the publisher does while the broker blocks its connection. Must be set before `setup_publisher`.
+    pub fn set_blocked_policy(&mut self, policy: BlockedPolicy) {
+        self.publisher.set_blocked_policy(policy);
+    }
+
+    /// Format of the entities published, see `Publisher::set_format`. Must be set before `setup_publisher`.
+    pub fn set_format(&mut self, format: PayloadFormat) {
+        self.publisher.set_format(format);
+    }
+
+    /// Codec of the entities published, see `Publisher::set_codec`. Must be set before `setup_publisher`.
+    #[cfg(feature = "json")]
+    pub fn set_codec(&mut self, codec: Option<Arc<dyn format::DynCodec>>) {
+        self.publisher.set_codec(codec);
+    }
+
+    /// Naming convention of the queues declared by the helpers of this crate.
+    pub fn set_naming(&mut self, naming: NamingStrategy) {
+        self.consumer.naming = naming;
+    }
+
     /// Connect `Broker` to the AMQP endpoint, then declare Proxy's queue.
     pub async fn init(&mut self, uri: &str) -> Result<()> {
-        let conn = Connection::connect(uri, ConnectionProperties::default().with_tokio()).await?;
+        let conn = self.connect(uri).await?;
 
         info!("Broker connected.");
 
-        self.conn = Some(conn);
+        if self.separate_connections {
+            let publisher_conn = self.connect(uri).await?;
+
+            info!("Broker publisher connected.");
+
+            self.publisher_conn = Some(publisher_conn);
+        }
+
+        self.conn = Some(ConnectionLease::owned(conn));
+        if !self.uris.iter().any(|known| known == uri) {
+            self.uris.push(uri.to_string());
+        }
 
         Ok(())
     }
 
-    /// Setup publisher
+    /// Spawn the consumer and wait for it to end. With a reconnect policy, once the connection died it reconnects,
+    /// declares again the exchanges, queues and bindings, sets the publisher and the consumer up again and resumes
+    /// consuming. Only the publisher of the broker (and the ones handed to the listeners) is set up again, not its clones.
+    /// The consumers of exclusive queues aren't resumed.
+    pub async fn run(&mut self) -> Result<()> {
+        loop {
+            let res = self.consumer.spawn().await;
+
+            let Some(policy) = self.reconnect else {
+                return res;
+            };
+            if self.consumer.listeners.is_cancelled() || self.is_connected() {
+                return res;
+            }
+
+            warn!(?res, "Connection lost, reconnecting");
+            self.reconnect(&policy).await?;
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        [self.conn.as_deref(), self.publisher_conn.as_ref()]
+            .into_iter()
+            .flatten()
+            .all(|conn| conn.status().connected())
+    }
+
+    async fn reconnect(&mut self, policy: &ReconnectPolicy) -> Result<()> {
+        let topologies: Vec<_> = [self.conn.as_deref(), self.publisher_conn.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(Connection::topology)
+            .collect();
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            runtime::sleep(policy.delay(attempt)).await;
+
+            match self.reconnect_once(&topologies).await {
+                Ok(()) => {
+                    info!(attempt, "Broker reconnected.");
+                    metrics::count_reconnection("succeeded");
+                    return Ok(());
+                }
+                Err(err) if policy.exhausted(attempt) => {
+                    error!(%err, attempt, "Failed to reconnect, giving up");
+                    metrics::count_reconnection("gave_up");
+                    return Err(err);
+                }
+                Err(err) => {
+                    warn!(%err, attempt, "Failed to reconnect");
+                    metrics::count_reconnection("failed");
+                }
+            }
+        }
+    }
+
+    async fn reconnect_once(&mut self, topologies: &[lapin::topology::TopologyDefinition]) -> Result<()> {
+        let mut res = Err(Error::InvalidConfig("no URI".to_string()));
+        for uri in self.uris.clone() {
+            res = self.init(&uri).await;
+            if res.is_ok() {
+                break;
+            }
+        }
+        res?;
+
+        self.setup_publisher().await?;
+        self.consumer.reconnect(self.conn.as_ref().unwrap()).await?;
+        for topology in topologies {
+            reconnect::redeclare(self.consumer.channel(), topology).await?;
+        }
+        self.consumer.consume_again().await
+    }
+
+    async fn connect(&self, uri: &str) -> Result<Connection> {
+        let tls = self.tls.as_ref().map(TlsConfig::load).transpose()?.unwrap_or_default();
+        let conn = Connection::connect_with_config(uri, runtime::connection_properties(), tls).await?;
+
+        Ok(conn)
+    }
+
+    /// Setup publisher, on its own connection if `set_separate_connections` was enabled
     pub async fn setup_publisher(&mut self) -> Result<&Publisher> {
-        let channel = self.conn.as_ref().unwrap().create_channel().await?;
+        let conn = self.publisher_conn.as_ref().or(self.conn.as_deref());
+        let conn = conn.unwrap();
+        let channel = conn.create_channel().await?;
         self.publisher.channel = Some(channel);
+        self.publisher.set_connection(conn);
+        self.consumer.publisher = Some(self.publisher.clone());
 
         Ok(&self.publisher)
     }
@@ -154,242 +582,1661 @@ impl Broker {
     pub async fn setup_consumer(&mut self) -> Result<&mut Consumer> {
         let channel = self.conn.as_ref().unwrap().create_channel().await?;
         self.consumer.channel = Some(channel);
+        let republish_channel = self.conn.as_ref().unwrap().create_channel().await?;
+        self.consumer.set_republish_channel(republish_channel).await?;
+
+        Ok(&mut self.consumer)
+    }
+
+    /// Init the consumer with `count` channels, each of them can run its own `basic_consume`
+    /// on the same queue (see `Consumer::basic_consume_all`), their deliveries are merged.
+    pub async fn setup_consumer_channels(&mut self, count: usize) -> Result<&mut Consumer> {
+        self.setup_consumer().await?;
+
+        for _ in 1..count {
+            let channel = self.conn.as_ref().unwrap().create_channel().await?;
+            self.consumer.extra_channels.push(channel);
+        }
 
         Ok(&mut self.consumer)
     }
 
-    pub async fn publish<P>(&self, entity: &P, routing_key: &str) -> Result<PublisherConfirm>
+    pub async fn publish<P>(&self, entity: &P, routing_key: &str) -> Result<PublishConfirm>
     where
         P: BrokerPublish + Serialize,
     {
         self.publisher.publish(entity, routing_key).await
     }
 
-    pub async fn publish_raw(
+    /// Poll every `interval` the depth of the queues of `thresholds`, on a channel of its own as polling a missing queue
+    /// closes the channel, and call `on_backlog(queue, depth)` while one is above its threshold.
+    pub async fn spawn_backlog_alarm<F>(
         &self,
-        exchange: &str,
-        routing_key: &str,
-        msg: &[u8],
-    ) -> Result<PublisherConfirm> {
-        self.publisher.publish_raw(exchange, routing_key, msg).await
+        thresholds: Vec<backlog::BacklogThreshold>,
+        interval: std::time::Duration,
+        on_backlog: F,
+    ) -> Result<JoinHandle<()>>
+    where
+        F: Fn(&str, u32) + Send + Sync + 'static,
+    {
+        let channel = self.conn.as_ref().unwrap().create_channel().await?;
+
+        Ok(backlog::spawn(channel, self.affixes.clone(), thresholds, interval, on_backlog))
     }
-}
 
-pub struct Publisher {
-    channel: Option<Channel>,
-}
+    /// See `Consumer::partitioned`.
+    pub async fn partitioned_consumer(&self, prefetch: u16) -> Result<partition::PartitionedConsumer> {
+        self.consumer.partitioned(self.conn.as_ref().unwrap(), prefetch).await
+    }
 
-impl Publisher {
-    pub fn new() -> Self {
-        Self { channel: None }
+    /// Compete for the leadership of the fleet over the exclusive queue `lock`, on a new connection to `uri`:
+    /// the lock is released as soon as this instance goes away. A follower tries again every `interval`.
+    pub async fn elect_leader(&self, uri: &str, lock: &str, interval: std::time::Duration) -> Result<leader::LeaderElection> {
+        let conn = self.connect(uri).await?;
+
+        Ok(leader::spawn(conn, &self.affixes.resolve(lock), interval))
     }
 
-    pub fn channel(&self) -> &Channel {
-        self.channel.as_ref().expect("Publisher's channel is None")
+    /// Dispatch the messages published by the broker to the listeners of its consumer too, or instead of publishing them.
+    pub fn set_local_echo(&mut self, echo: LocalEcho) {
+        self.publisher.set_local_echo(echo, &self.consumer.listeners);
+        if let Some(publisher) = self.consumer.publisher.as_mut() {
+            publisher.set_local_echo(echo, &self.consumer.listeners);
+        }
     }
 
-    /// Push item into amqp
-    pub async fn publish<P>(&self, entity: &P, routing_key: &str) -> Result<PublisherConfirm>
-    where
-        P: BrokerPublish + Serialize,
-    {
-        let serialized = bincode::serialize(entity)?;
+    /// Add `listener` to the consumer, consuming `queue` on a channel of its own with `prefetch`,
+    /// see `Consumer::add_listener_with_channel`. The consumer has to be set up first.
+    pub async fn add_isolated_listener(&mut self, listener: Arc<dyn BrokerListener>, queue: &str, prefetch: u16) -> Result<()> {
+        let channel = self.conn.as_ref().unwrap().create_channel().await?;
 
-        // start prometheus duration timer
-        let histogram_timer = STAT_PUBLISHER_DURATION.with_label_values(&[entity.exchange_name(), routing_key]).start_timer();
+        self.consumer.add_listener_with_channel(channel, listener, queue, prefetch).await
+    }
 
-        let res = self
-            .channel()
-            .basic_publish(
-                entity.exchange_name(),
-                routing_key,
-                BasicPublishOptions::default(),
-                serialized.as_slice(),
-                BasicProperties::default(),
-            )
-            .await;
+    /// Stop in order, each stage within its timeout: cancel the consumers and wait for their in-flight deliveries,
+    /// run the `on_stop` hook of the listeners, wait for the confirmations of the pending publishes, then close the channels and the connections.
+    /// Every stage is run, the first error is returned.
+    pub async fn shutdown(&mut self, timeouts: ShutdownTimeouts) -> Result<()> {
+        let mut first_error = None;
+
+        if self.consumer.channel.is_some() {
+            let res = self.consumer.drain(timeouts.drain).await;
+            record_stage(&mut first_error, "drain", Some(res));
+
+            let stop = async {
+                self.consumer.stop_listeners().await;
+                Ok(())
+            };
+            let res = runtime::timeout(timeouts.stop, stop).await;
+            record_stage(&mut first_error, "stop", res);
+        }
 
-        // finish and compute the duration to prometheus
-        histogram_timer.observe_duration();
+        let flush = async {
+            if let Some(channel) = self.publisher.channel.as_ref() {
+                channel.wait_for_confirms().await?;
+            }
+            Ok(())
+        };
+        let res = runtime::timeout(timeouts.flush, flush).await;
+        record_stage(&mut first_error, "flush", res);
+
+        // a shared connection is only closed by the last broker shut down
+        let conn = self.conn.take();
+        let close = async {
+            let consumer_channels = self.consumer.channel.iter().chain(self.consumer.extra_channels.iter());
+            for channel in consumer_channels.chain(self.publisher.channel.iter()) {
+                if channel.status().connected() {
+                    channel.close(200, "Shutdown").await?;
+                }
+            }
+            for conn in self.publisher_conn.iter() {
+                if conn.status().connected() {
+                    conn.close(200, "Shutdown").await?;
+                }
+            }
+            if let Some(conn) = conn {
+                conn.release().await?;
+            }
+            Ok(())
+        };
+        let res = runtime::timeout(timeouts.close, close).await;
+        record_stage(&mut first_error, "close", res);
+
+        info!("Broker shut down.");
 
-        res.map_err(|e| Error::Amqp(e))
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Publish a probe to `exchange` with `routing_key`, mandatory with confirms, and tell whether the exchange routed it,
+    /// e.g. at startup to catch a missing binding before the traffic does. See `verify`.
+    pub async fn verify_publish(&self, exchange: &str, routing_key: &str) -> Result<bool> {
+        let exchange = self.affixes.resolve(exchange);
+        verify::verify_publish(self.conn.as_ref().unwrap(), &exchange, routing_key, self.publisher.signer.as_deref()).await
+    }
+
+    /// Feed the deliveries of the stream `queue` to `listener` from a given offset or timestamp,
+    /// see `replay::replay`.
+    pub async fn replay(
+        &self,
+        queue: &str,
+        listener: &dyn BrokerListener,
+        options: replay::ReplayOptions,
+    ) -> Result<replay::ReplaySummary> {
+        let publisher = self.publisher.channel.as_ref().map(|_| &self.publisher);
+        replay::replay_with(self.conn.as_ref().unwrap(), &self.affixes, publisher, queue, listener, options).await
+    }
+
+    /// Exchanges, queues, bindings, consumers and listeners declared or registered so far.
+    pub fn topology(&self) -> Topology {
+        Topology {
+            declared: self.conn.as_deref().map(Connection::topology).unwrap_or_default(),
+            publisher_declared: self.publisher_conn.as_ref().map(Connection::topology),
+            listeners: self.consumer.listeners.exchanges(),
+        }
+    }
+
+    /// Consumption statistics of every listener, by exchange name.
+    pub fn consumer_stats(&self) -> Vec<(&'static str, ConsumerStats)> {
+        self.consumer.stats()
+    }
+
+    /// Report the status and open channels of the connections in the `amqp_connection` gauges,
+    /// e.g. before each scrape.
+    pub fn update_connection_metrics(&self) {
+        let connections = [("main", self.conn.as_deref()), ("publisher", self.publisher_conn.as_ref())];
+        for (name, conn) in connections {
+            if let Some(conn) = conn {
+                metrics::observe_connection(name, conn.status(), conn.topology().channels.len());
+            }
+        }
+    }
+
+    /// Turn every metric recording on or off at runtime, e.g. during an incident involving the metrics pipeline.
+    pub fn set_metrics_enabled(&self, enabled: bool) {
+        metrics::set_enabled(enabled);
+    }
+
+    /// Turn the recording of a single metrics category on or off at runtime.
+    pub fn set_metrics_category_enabled(&self, category: MetricsCategory, enabled: bool) {
+        metrics::set_category_enabled(category, enabled);
     }
 
-    /// Push without serializing
     pub async fn publish_raw(
         &self,
         exchange: &str,
         routing_key: &str,
         msg: &[u8],
-    ) -> Result<PublisherConfirm> {
-        // start prometheus duration timer
-        let histogram_timer = STAT_PUBLISHER_DURATION.with_label_values(&[exchange, routing_key]).start_timer();
+    ) -> Result<PublishConfirm> {
+        self.publisher.publish_raw(exchange, routing_key, msg).await
+    }
 
-        let res = self
-            .channel()
-            .basic_publish(
-                exchange,
-                routing_key,
-                BasicPublishOptions::default(),
-                msg,
-                BasicProperties::default(),
-            )
-            .await;
+    /// See `Publisher::publish_from_delivery`.
+    pub async fn publish_from_delivery(&self, delivery: &Delivery, overrides: &republish::Overrides) -> Result<PublishConfirm> {
+        self.publisher.publish_from_delivery(delivery, overrides).await
+    }
 
-        // finish and compute the duration to prometheus
-        histogram_timer.observe_duration();
+    /// See `Publisher::publish_stream`.
+    pub async fn publish_stream<S>(&self, exchange: &str, routing_key: &str, stream: S, window: usize) -> Result<u64>
+    where
+        S: Stream,
+        S::Item: AsRef<[u8]>,
+    {
+        self.publisher.publish_stream(exchange, routing_key, stream, window).await
+    }
 
-        // let res = res.await?;
-        res.map_err(|e| Error::Amqp(e))
+    /// See `Publisher::publish_paced`.
+    pub async fn publish_paced<S>(&self, exchange: &str, routing_key: &str, stream: S, per_second: u32) -> Result<u64>
+    where
+        S: Stream,
+        S::Item: AsRef<[u8]>,
+    {
+        self.publisher.publish_paced(exchange, routing_key, stream, per_second).await
     }
 }
 
-impl Clone for Publisher {
-    fn clone(&self) -> Self {
-        Self {
-            channel: self.channel.clone(),
-        }
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub struct Listener {
-    inner: Arc<dyn BrokerListener>,  // Replace Box with Arc, because a Box can not be cloned.
-    semaphore: Arc<Semaphore>,
+pub struct Publisher {
+    channel: Option<Channel>,
+    archive_exchange: Option<String>,
+    audit: Option<Arc<dyn AuditSink>>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+    signer: Option<Arc<dyn Signer>>,
+    max_payload_size: Option<usize>,
+    /// Trace id of the delivery this publisher was handed to in a `ConsumeContext`.
+    trace_id: Option<String>,
+    /// Of the connection of `channel`, to know when the broker blocks it.
+    connection_status: Option<lapin::ConnectionStatus>,
+    blocked_policy: BlockedPolicy,
+    /// Shared with the clones, so they all number the same sequence.
+    sequence: Option<Arc<Sequence>>,
+    /// Weak, as the listeners hold a clone of the publisher.
+    local_echo: Option<(LocalEcho, Weak<ListenerRegistry>)>,
+    /// Publishes not confirmed yet, shared with the clones.
+    confirm_window: Option<Arc<Semaphore>>,
+    /// Of the delivery this publisher was handed to, when it waits for the confirmation of its publishes.
+    downstream: Option<Downstream>,
+    /// Of the entities published with `publish`.
+    format: PayloadFormat,
+    /// Of the entities published with `publish` instead of `format`, when set.
+    #[cfg(feature = "json")]
+    codec: Option<Arc<dyn format::DynCodec>>,
+    /// Have the broker return the unroutable messages, so they aren't confirmed as an ack.
+    mandatory: bool,
+    affixes: TopologyAffixes,
 }
 
-impl Clone for Listener {
-    fn clone(&self) -> Self {
+impl Publisher {
+    pub fn new() -> Self {
         Self {
-            inner: self.inner.clone(),
-            semaphore: self.semaphore.clone(),
+            channel: None,
+            archive_exchange: None,
+            audit: None,
+            encryptor: None,
+            signer: None,
+            max_payload_size: None,
+            trace_id: None,
+            connection_status: None,
+            blocked_policy: BlockedPolicy::default(),
+            sequence: None,
+            local_echo: None,
+            confirm_window: None,
+            downstream: None,
+            format: PayloadFormat::Bincode,
+            #[cfg(feature = "json")]
+            codec: None,
+            mandatory: false,
+            affixes: naming::topology_affixes(),
         }
     }
-}
 
-impl Listener {
-    pub fn new(listener: Arc<dyn BrokerListener>) -> Self {
+    /// Publish on a channel created by the application, without a `Broker`.
+    pub fn with_channel(channel: Channel) -> Self {
         Self {
-            semaphore: Arc::new(Semaphore::new(listener.max_concurrent_tasks())),
-            inner: listener,
+            channel: Some(channel),
+            ..Self::new()
         }
     }
 
-    fn listener(&self) -> &Arc<dyn BrokerListener> {
-        &self.inner
+    pub fn channel(&self) -> &Channel {
+        self.channel.as_ref().expect("Publisher's channel is None")
     }
 
-    fn max_concurrent_tasks(&self) -> usize {
-        self.inner.max_concurrent_tasks()
+    /// Copy every published message to this exchange too (fire-and-forget).
+    pub fn set_archive_exchange(&mut self, archive_exchange: Option<String>) {
+        self.archive_exchange = archive_exchange;
     }
-}
 
-pub struct Consumer {
-    channel: Option<Channel>,
-    consumer: Option<lapin::Consumer>,
-    listeners: Option<Vec<Listener>>,
-}
+    /// Record every publish and its confirmation in this sink, e.g. `audit::TracingAuditSink`.
+    /// Confirms are enabled on the next publish.
+    pub fn set_audit_sink(&mut self, audit: Option<Arc<dyn AuditSink>>) {
+        self.audit = audit;
+    }
 
-impl Consumer {
-    pub fn new() -> Self {
-        Self {
-            channel: None,
-            consumer: None,
-            listeners: Some(vec![]),
-        }
+    /// Encrypt every payload, after serialization.
+    pub fn set_encryptor(&mut self, encryptor: Option<Arc<dyn Encryptor>>) {
+        self.encryptor = encryptor;
     }
 
-    pub fn channel(&self) -> &Channel {
-        self.channel.as_ref().expect("Consumer's channel is None")
+    /// Sign every payload as sent, i.e. after encryption.
+    pub fn set_signer(&mut self, signer: Option<Arc<dyn Signer>>) {
+        self.signer = signer;
     }
 
-    pub fn set_consumer(&mut self, consumer: lapin::Consumer) {
-        self.consumer = Some(consumer);
+    /// Fail publishes whose payload, as sent, exceeds `max` bytes with `Error::PayloadTooLarge`.
+    pub fn set_max_payload_size(&mut self, max: Option<usize>) {
+        self.max_payload_size = max;
     }
 
-    /// Add and store listeners
-    /// When a listener is added, it will bind the queue to the specified exchange name.
-    pub fn add_listener(&mut self, listener: Arc<dyn BrokerListener>) {
-        self.listeners.as_mut().expect("No listeners found").push(Listener::new(listener));
+    /// Follow whether the broker blocks `conn`, the connection of the channel. Set by `Broker::setup_publisher`.
+    pub fn set_connection(&mut self, conn: &Connection) {
+        self.connection_status = Some(conn.status().clone());
     }
 
-    /// Will spawn the Consumer automatically
-    pub fn spawn(&mut self) -> JoinHandle<Result<()>> {
-        let consumer = self
-            .consumer
-            .as_ref()
-            .expect("A consumer hasn't been set.")
-            .clone();
-        let listeners = self.listeners.take().expect("No listeners found");
+    /// Stamp a publisher id and an incrementing sequence number on every message, see `sequence::SequenceChecker`.
+    pub fn set_sequence_numbers(&mut self, enabled: bool) {
+        self.sequence = enabled.then(|| Arc::new(Sequence::new()));
+    }
 
-        let handle = task::spawn(Consumer::consume(consumer, listeners));
+    /// Dispatch the messages to the listeners of `listeners` too, or instead of the broker, once their consumer is spawned.
+    /// See `echo::LocalEcho`.
+    pub fn set_local_echo(&mut self, echo: LocalEcho, listeners: &Arc<ListenerRegistry>) {
+        self.local_echo = (echo != LocalEcho::Off).then(|| (echo, Arc::downgrade(listeners)));
+    }
 
-        info!("Consumer has been launched in background.");
+    /// Cap the publishes not confirmed yet by the broker to `max`, a publish waits for room in the window.
+    /// A slot is freed once the broker's confirm arrives, whether the `PublishConfirm` is awaited or dropped.
+    /// Confirms are enabled on the next publish.
+    pub fn set_confirm_window(&mut self, max: Option<usize>) {
+        self.confirm_window = max.map(|max| Arc::new(Semaphore::new(max.max(1))));
+    }
 
-        handle
+    /// What to do when publishing while the broker blocks the connection, see `set_connection`.
+    pub fn set_blocked_policy(&mut self, policy: BlockedPolicy) {
+        self.blocked_policy = policy;
     }
 
-    /// In order to spawn it manually.
-    pub fn get_consumer(&mut self) -> (lapin::Consumer, Vec<Listener>) {
-        let consumer = self
-            .consumer
-            .as_ref()
-            .expect("A consumer hasn't been set.")
-            .clone();
-        let listeners = self.listeners.take().expect("No listeners found");
+    /// Put the channel in confirm mode, so the `PublishConfirm`s resolve to the broker's ack or nack
+    /// rather than `Confirmation::NotRequested`. Done on first use by `publish_confirmed` and the audited publishes,
+    /// fire-and-forget publishers don't pay for the confirms.
+    pub async fn enable_confirms(&self) -> Result<()> {
+        if !self.channel().status().confirm() {
+            self.channel().confirm_select(ConfirmSelectOptions::default()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish then wait for the broker's ack, fails with `Error::NotConfirmed` on a nack or a returned message.
+    pub async fn publish_confirmed<P>(&self, entity: &P, routing_key: &str) -> Result<()>
+    where
+        P: BrokerPublish + Serialize,
+    {
+        self.enable_confirms().await?;
+        check_confirmed(entity.exchange_name(), self.publish(entity, routing_key).await?).await
+    }
+
+    /// Push item into amqp, serialized with the codec of the publisher, else its format.
+    pub async fn publish<P>(&self, entity: &P, routing_key: &str) -> Result<PublishConfirm>
+    where
+        P: BrokerPublish + Serialize,
+    {
+        #[cfg(feature = "json")]
+        if let Some(codec) = self.codec.as_deref() {
+            return self.publish_with_codec(entity, routing_key, codec).await;
+        }
+
+        self.publish_with_codec(entity, routing_key, &self.format).await
+    }
+
+    /// Push item into amqp, serialized with `codec` whatever the format of the publisher, its content type set.
+    pub async fn publish_with_codec<P, C>(&self, entity: &P, routing_key: &str, codec: &C) -> Result<PublishConfirm>
+    where
+        P: BrokerPublish + Serialize,
+        C: Codec + ?Sized,
+    {
+        let serialized = codec.encode(entity)?;
+        let properties = BasicProperties::default().with_content_type(codec.content_type().into());
+
+        self.publish_with(entity.exchange_name(), routing_key, &serialized, properties).await
+    }
+
+    /// Serialize the entities with `format` in `publish`, bincode by default.
+    /// JSON, for the consumers which aren't written in Rust, needs the `json` feature.
+    pub fn set_format(&mut self, format: PayloadFormat) {
+        self.format = format;
+    }
+
+    /// Serialize the entities with `codec` in `publish` rather than the format, e.g. `format::MsgPackCodec`
+    /// or one of the application.
+    #[cfg(feature = "json")]
+    pub fn set_codec(&mut self, codec: Option<Arc<dyn format::DynCodec>>) {
+        self.codec = codec;
+    }
+
+    /// Publish to the exchanges named with `affixes` rather than the ones of `naming::set_topology_affixes`.
+    pub fn set_topology_affixes(&mut self, affixes: TopologyAffixes) {
+        self.affixes = affixes;
+    }
+
+    /// Push without serializing
+    pub async fn publish_raw(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        msg: &[u8],
+    ) -> Result<PublishConfirm> {
+        self.publish_with(exchange, routing_key, msg, BasicProperties::default()).await
+    }
+
+    /// Publish again the payload and properties of `delivery`, to its own exchange and routing key
+    /// unless `overrides` changes them.
+    pub async fn publish_from_delivery(&self, delivery: &Delivery, overrides: &republish::Overrides) -> Result<PublishConfirm> {
+        let (exchange, routing_key, properties) = overrides.apply(delivery, &self.affixes);
+
+        self.publish_with(&exchange, &routing_key, &delivery.data, properties).await
+    }
+
+    /// Publish the payloads of `stream` as they come, pausing it while `window` publishes wait for their confirmation.
+    /// Fails with `Error::NotConfirmed` on the first one not acked, returns how many were published otherwise.
+    pub async fn publish_stream<S>(&self, exchange: &str, routing_key: &str, stream: S, window: usize) -> Result<u64>
+    where
+        S: Stream,
+        S::Item: AsRef<[u8]>,
+    {
+        self.publish_stream_paced(exchange, routing_key, stream, window, None).await
+    }
+
+    /// Drip-feed: publish the payloads of `stream` at `per_second` at most, e.g. to backfill downstream consumers
+    /// without a thundering herd. Up to a second of publishes wait for their confirmation, see `publish_stream`.
+    pub async fn publish_paced<S>(&self, exchange: &str, routing_key: &str, stream: S, per_second: u32) -> Result<u64>
+    where
+        S: Stream,
+        S::Item: AsRef<[u8]>,
+    {
+        let per_second = per_second.max(1);
+        let pace = std::time::Duration::from_secs(1) / per_second;
+
+        self.publish_stream_paced(exchange, routing_key, stream, per_second as usize, Some(pace)).await
+    }
+
+    /// Publish a payload every `pace` at most.
+    async fn publish_stream_paced<S>(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        stream: S,
+        window: usize,
+        pace: Option<std::time::Duration>,
+    ) -> Result<u64>
+    where
+        S: Stream,
+        S::Item: AsRef<[u8]>,
+    {
+        self.enable_confirms().await?;
+        futures_lite::pin!(stream);
+
+        // a channel confirms in publish order, the oldest is always the first to wait for
+        let mut unconfirmed = VecDeque::with_capacity(window);
+        let mut published = 0;
+        // scheduled from the start, so slow publishes are caught up on rather than slowing the pace down
+        let started_at = clock::now();
+        while let Some(payload) = stream.next().await {
+            if let Some(pace) = pace {
+                let due = pace.saturating_mul(u32::try_from(published).unwrap_or(u32::MAX));
+                let wait = due.saturating_sub(clock::elapsed(started_at));
+                if !wait.is_zero() {
+                    runtime::sleep(wait).await;
+                }
+            }
+            if unconfirmed.len() >= window.max(1) {
+                check_confirmed(exchange, unconfirmed.pop_front().unwrap()).await?;
+            }
+            unconfirmed.push_back(self.publish_raw(exchange, routing_key, payload.as_ref()).await?);
+            published += 1;
+        }
+
+        for confirm in unconfirmed {
+            check_confirmed(exchange, confirm).await?;
+        }
+
+        Ok(published)
+    }
+
+    /// The same publisher, reporting the outcome of its publishes to `downstream`.
+    pub(crate) fn with_downstream(mut self, downstream: Option<Downstream>) -> Self {
+        self.downstream = downstream;
+        self
+    }
+
+    /// The same publisher, propagating `trace_id` to the messages it publishes.
+    pub(crate) fn with_trace_id(mut self, trace_id: Option<String>) -> Self {
+        self.trace_id = trace_id;
+        self
+    }
+
+    /// The same publisher, publishing its messages as mandatory.
+    pub(crate) fn with_mandatory(mut self, mandatory: bool) -> Self {
+        self.mandatory = mandatory;
+        self
+    }
+
+    /// Every publish goes through here, in an `amqp_publish` span.
+    pub(crate) async fn publish_with(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<PublishConfirm> {
+        if let Some(status) = &self.connection_status {
+            flow::check(status, self.blocked_policy).await?;
+        }
+
+        let trace_id = headers::get_property(&properties, trace::TRACE_ID_HEADER)
+            .and_then(headers::as_string)
+            .or_else(|| self.trace_id.clone())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let properties = headers::insert(properties, trace::TRACE_ID_HEADER, headers::long_string(&trace_id));
+
+        let span = info_span!(
+            "amqp_publish",
+            exchange,
+            routing_key,
+            trace_id,
+            size = tracing::field::Empty,
+            confirm = tracing::field::Empty,
+        );
+
+        self.publish_in_span(exchange, routing_key, payload, properties, span.clone())
+            .instrument(span)
+            .await
+    }
+
+    async fn publish_in_span(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        mut properties: BasicProperties,
+        span: tracing::Span,
+    ) -> Result<PublishConfirm> {
+        // an audit without the outcome of the publishes would be pointless, and so would be a window
+        if self.audit.is_some() || self.confirm_window.is_some() || self.downstream.is_some() {
+            self.enable_confirms().await?;
+        }
+        let window_permit = match &self.confirm_window {
+            Some(window) => Some(window.clone().acquire_owned().await?),
+            None => None,
+        };
+
+        // start prometheus duration timer
+        let histogram_timer = metrics::publisher_timer(exchange, routing_key);
+
+        if properties.message_id().is_none() {
+            properties = properties.with_message_id(ids::generate().into());
+        }
+
+        if let Some(sequence) = self.sequence.as_ref() {
+            properties = headers::insert(properties, sequence::PUBLISHER_ID_HEADER, headers::long_string(&sequence.publisher_id));
+            properties = headers::insert(properties, sequence::SEQUENCE_HEADER, AMQPValue::LongLongInt(sequence.next() as i64));
+        }
+
+        let original = payload;
+        let mut payload = Cow::Borrowed(payload);
+        if let Some(encryptor) = self.encryptor.as_ref() {
+            let (key_id, encrypted) = encryptor.encrypt(&payload)?;
+            properties = headers::insert(properties, encryption::KEY_ID_HEADER, headers::long_string(&key_id));
+            payload = Cow::Owned(encrypted);
+        }
+
+        if let Some(max) = self.max_payload_size {
+            if payload.len() > max {
+                return Err(Error::PayloadTooLarge { size: payload.len(), max });
+            }
+        }
+
+        if let Some(signer) = self.signer.as_ref() {
+            properties = headers::insert(properties, signing::SIGNATURE_HEADER, headers::long_string(&signer.sign(&payload)));
+        }
+
+        span.record("size", payload.len());
+        let tracker = PublishTracker {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            message_id: properties.message_id().as_ref().map(|id| id.to_string()),
+            size: payload.len(),
+            audit: self.audit.clone(),
+            span,
+            window_permit,
+            downstream: self.downstream.as_ref().map(Downstream::track),
+        };
+
+        if let Some(archive_exchange) = self.archive_exchange.as_deref() {
+            tap::archive(self.channel(), &self.affixes, archive_exchange, exchange, routing_key, &payload, &properties);
+        }
+
+        if let Some((echo, listeners)) = self.local_echo.as_ref() {
+            let acked = match listeners.upgrade() {
+                Some(listeners) => echo::dispatch(&listeners, &self.affixes, exchange, routing_key, &payload, &properties).await,
+                None => None,
+            };
+            if let (Some(acked), LocalEcho::Instead) = (acked, echo) {
+                tap::mirror(exchange, routing_key, original, &properties);
+                return Ok(PublishConfirm::local(tracker, acked));
+            }
+        }
+
+        let mirrored = properties.clone();
+        let res = self
+            .channel()
+            .basic_publish(
+                &self.affixes.resolve(exchange),
+                routing_key,
+                BasicPublishOptions {
+                    mandatory: self.mandatory,
+                    ..BasicPublishOptions::default()
+                },
+                &payload,
+                properties,
+            )
+            .await;
+
+        // finish and compute the duration to prometheus
+        if let Some(histogram_timer) = histogram_timer {
+            histogram_timer.observe_duration();
+        }
+
+        match res {
+            Ok(confirm) => {
+                tap::mirror(exchange, routing_key, original, &mirrored);
+                metrics::count_payload_bytes("out", tracker.size);
+                metrics::observe_payload_size(exchange, "out", tracker.size);
+                Ok(PublishConfirm::new(confirm, tracker))
+            }
+            Err(err) => {
+                tracker.finish(ConfirmOutcome::Failed(err.to_string()));
+                Err(Error::Amqp(err))
+            }
+        }
+    }
+}
+
+impl Default for Publisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Publisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Publisher");
+        debug
+            .field("channel", &self.channel)
+            .field("archive_exchange", &self.archive_exchange)
+            .field("audit", &self.audit.is_some())
+            .field("encryptor", &self.encryptor.is_some())
+            .field("signer", &self.signer.is_some())
+            .field("max_payload_size", &self.max_payload_size)
+            .field("trace_id", &self.trace_id)
+            .field("connection_status", &self.connection_status)
+            .field("blocked_policy", &self.blocked_policy)
+            .field("sequence", &self.sequence)
+            .field("local_echo", &self.local_echo.as_ref().map(|(echo, _)| echo))
+            .field("confirm_window", &self.confirm_window.as_ref().map(|window| window.available_permits()))
+            .field("downstream", &self.downstream.is_some())
+            .field("format", &self.format);
+        #[cfg(feature = "json")]
+        debug.field("codec", &self.codec);
+        debug.field("mandatory", &self.mandatory).field("affixes", &self.affixes).finish()
+    }
+}
+
+impl Clone for Publisher {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+            archive_exchange: self.archive_exchange.clone(),
+            audit: self.audit.clone(),
+            encryptor: self.encryptor.clone(),
+            signer: self.signer.clone(),
+            max_payload_size: self.max_payload_size,
+            trace_id: self.trace_id.clone(),
+            connection_status: self.connection_status.clone(),
+            blocked_policy: self.blocked_policy,
+            sequence: self.sequence.clone(),
+            local_echo: self.local_echo.clone(),
+            confirm_window: self.confirm_window.clone(),
+            downstream: self.downstream.clone(),
+            format: self.format,
+            #[cfg(feature = "json")]
+            codec: self.codec.clone(),
+            mandatory: self.mandatory,
+            affixes: self.affixes.clone(),
+        }
+    }
+}
+
+/// Settings of a consumer needed when dispatching deliveries to its listeners.
+#[derive(Clone, Default)]
+struct ConsumerSettings {
+    /// Used to publish the archive copies.
+    channel: Option<Channel>,
+    /// In confirm mode, the retries and dead-letters are published on it and acked once confirmed.
+    republish_channel: Option<Channel>,
+    affixes: TopologyAffixes,
+    /// Given to the listeners in their `ConsumeContext`.
+    publisher: Option<Publisher>,
+    archive_exchange: Option<String>,
+    dead_letter_exchange: Option<String>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+    signer: Option<Arc<dyn Signer>>,
+    signature_failure: SignatureFailureAction,
+    redactor: Redactor,
+    max_payload_size: Option<usize>,
+    unhandled_nack: BasicNackOptions,
+    /// Deliveries are only pulled while healthy.
+    health: Health,
+    /// No delivery is pulled before it's open.
+    gate: Option<ConsumeGate>,
+    /// Of the listeners without their own retry policy.
+    default_retry_policy: Option<RetryPolicy>,
+    stream_end: StreamEndAction,
+    /// Errors read from the stream stop the loop when `None`.
+    stream_error_backoff: Option<StreamErrorBackoff>,
+    journal: Option<Arc<dyn Journal>>,
+}
+
+pub struct Listener {
+    inner: Arc<dyn BrokerListener>,  // Replace Box with Arc, because a Box can not be cloned.
+    semaphore: Arc<Semaphore>,
+    metrics: ListenerMetrics,
+    retry_policy: Option<RetryPolicy>,
+    settings: Arc<ConsumerSettings>,
+    stats: Arc<StatsRecorder>,
+    load_shedding: Option<LoadShedding>,
+    in_flight_times: Arc<InFlightTimes>,
+    /// Whether `on_start` succeeded, shared with the clones.
+    started: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Listener")
+            .field("exchange_name", &self.inner.exchange_name())
+            .field("max_concurrent_tasks", &self.inner.max_concurrent_tasks())
+            .field("available_permits", &self.semaphore.available_permits())
+            .field("retry_policy", &self.retry_policy)
+            .field("load_shedding", &self.load_shedding)
+            .field("stats", &self.stats.snapshot())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Clone for Listener {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            semaphore: self.semaphore.clone(),
+            metrics: self.metrics.clone(),
+            retry_policy: self.retry_policy.clone(),
+            settings: self.settings.clone(),
+            stats: self.stats.clone(),
+            load_shedding: self.load_shedding,
+            in_flight_times: self.in_flight_times.clone(),
+            started: self.started.clone(),
+        }
+    }
+}
+
+impl Listener {
+    pub fn new(listener: Arc<dyn BrokerListener>) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(listener.max_concurrent_tasks())),
+            metrics: ListenerMetrics::new(
+                listener.exchange_name(),
+                listener.max_concurrent_tasks(),
+                listener.duration_buckets(),
+            ),
+            retry_policy: listener.retry_policy(),
+            settings: Arc::default(),
+            stats: Arc::default(),
+            load_shedding: listener.load_shedding(),
+            in_flight_times: Arc::default(),
+            started: Arc::default(),
+            inner: listener,
+        }
+    }
+
+    fn listener(&self) -> &Arc<dyn BrokerListener> {
+        &self.inner
+    }
+
+    fn max_concurrent_tasks(&self) -> usize {
+        self.inner.max_concurrent_tasks()
+    }
+
+    /// Permits `delivery` takes, within the ones of the listener so it can always get them.
+    fn cost(&self, delivery: &Delivery) -> u32 {
+        self.inner.cost(delivery).clamp(1, self.max_concurrent_tasks().max(1) as u32)
+    }
+
+    /// Run `on_start` unless it already succeeded.
+    async fn start(&self) -> Result<()> {
+        if self.started.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        self.inner.on_start().await.map_err(|source| Error::ListenerStart {
+            exchange: self.inner.exchange_name(),
+            source,
+        })?;
+        self.started.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ConsumerStats {
+        self.stats.snapshot()
+    }
+}
+
+pub struct Consumer {
+    channel: Option<Channel>,
+    extra_channels: Vec<Channel>,
+    /// See `set_republish_channel`.
+    republish_channel: Option<Channel>,
+    consumers: Vec<(lapin::Consumer, u32)>,
+    /// Served before `consumers`, see `add_priority_consumer`.
+    priority_consumers: Vec<lapin::Consumer>,
+    /// Index of the channel (0 for the main one) and tag of each consumer, to cancel them.
+    consumer_tags: Vec<(usize, ShortString)>,
+    /// Shared with the spawned loop, so listeners can be added and removed while consuming.
+    listeners: Arc<ListenerRegistry>,
+    publisher: Option<Publisher>,
+    /// Kept alive for as long as the consumer, see `subscribe_broadcast`.
+    broadcast_queues: Vec<Arc<ExclusiveQueue>>,
+    naming: NamingStrategy,
+    archive_exchange: Option<String>,
+    dead_letter_exchange: Option<String>,
+    encryptor: Option<Arc<dyn Encryptor>>,
+    signer: Option<Arc<dyn Signer>>,
+    signature_failure: SignatureFailureAction,
+    redactor: Redactor,
+    max_payload_size: Option<usize>,
+    unhandled_nack: BasicNackOptions,
+    health: Health,
+    gate: Option<ConsumeGate>,
+    default_retry_policy: Option<RetryPolicy>,
+    stream_end: StreamEndAction,
+    stream_error_backoff: Option<StreamErrorBackoff>,
+    journal: Option<Arc<dyn Journal>>,
+    /// `basic.qos` prefetch count by channel index, applied again on reconnection.
+    prefetch: Vec<(usize, u16)>,
+    affixes: TopologyAffixes,
+}
+
+impl Consumer {
+    pub fn new() -> Self {
+        Self {
+            channel: None,
+            extra_channels: vec![],
+            republish_channel: None,
+            consumers: vec![],
+            priority_consumers: vec![],
+            consumer_tags: vec![],
+            listeners: Arc::default(),
+            publisher: None,
+            broadcast_queues: vec![],
+            naming: NamingStrategy::default(),
+            archive_exchange: None,
+            dead_letter_exchange: None,
+            encryptor: None,
+            signer: None,
+            signature_failure: SignatureFailureAction::default(),
+            redactor: Redactor::default(),
+            max_payload_size: None,
+            unhandled_nack: BasicNackOptions::default(),
+            health: Health::default(),
+            gate: None,
+            default_retry_policy: None,
+            stream_end: StreamEndAction::default(),
+            stream_error_backoff: None,
+            journal: None,
+            prefetch: vec![],
+            affixes: naming::topology_affixes(),
+        }
+    }
+
+    /// Consume on a channel created by the application, without a `Broker`.
+    /// The retries and dead-letters need `set_republish_channel` too.
+    pub fn with_channel(channel: Channel) -> Self {
+        Self {
+            channel: Some(channel),
+            ..Self::new()
+        }
+    }
+
+    pub fn channel(&self) -> &Channel {
+        self.channel.as_ref().expect("Consumer's channel is None")
+    }
+
+    /// Set the `basic.qos` prefetch count of every channel of the consumer, applied again on reconnection.
+    pub async fn set_prefetch(&mut self, prefetch: u16) -> Result<()> {
+        for channel in self.channels() {
+            channel.basic_qos(prefetch, BasicQosOptions::default()).await?;
+        }
+        self.prefetch = (0..=self.extra_channels.len()).map(|index| (index, prefetch)).collect();
+
+        Ok(())
+    }
+
+    /// Create the channels again on `conn`, with their prefetch count.
+    async fn reconnect(&mut self, conn: &Connection) -> Result<()> {
+        self.channel = Some(conn.create_channel().await?);
+        for index in 0..self.extra_channels.len() {
+            self.extra_channels[index] = conn.create_channel().await?;
+        }
+        if self.republish_channel.is_some() {
+            self.set_republish_channel(conn.create_channel().await?).await?;
+        }
+
+        for (index, prefetch) in &self.prefetch {
+            if let Some(channel) = self.channels().nth(*index) {
+                channel.basic_qos(*prefetch, BasicQosOptions::default()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Consume again the queues of the consumers, each on the new channel of the same index.
+    async fn consume_again(&mut self) -> Result<()> {
+        let tags = std::mem::take(&mut self.consumer_tags);
+        let consumers = std::mem::take(&mut self.consumers);
+        let priority_consumers = std::mem::take(&mut self.priority_consumers);
+
+        for (consumer, weight) in consumers {
+            if let Some(consumer) = self.consume_again_one(&tags, &consumer).await? {
+                self.consumers.push((consumer, weight));
+            }
+        }
+        for consumer in priority_consumers {
+            if let Some(consumer) = self.consume_again_one(&tags, &consumer).await? {
+                self.priority_consumers.push(consumer);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn consume_again_one(&mut self, tags: &[(usize, ShortString)], consumer: &lapin::Consumer) -> Result<Option<lapin::Consumer>> {
+        let queue = consumer.queue();
+        if queue.as_str().starts_with("amq.gen-") {
+            warn!(%queue, "Consumer of an exclusive queue not resumed");
+            return Ok(None);
+        }
+
+        let index = tags.iter().find(|(_, tag)| *tag == consumer.tag()).map_or(0, |(index, _)| *index);
+        let channel = self.channels().nth(index).unwrap_or(self.channel());
+        let resumed = channel
+            .basic_consume(queue.as_str(), "", BasicConsumeOptions::default(), FieldTable::default())
+            .await?;
+        self.consumer_tags.push((index, resumed.tag()));
+
+        Ok(Some(resumed))
+    }
+
+    /// Every channel of the consumer, the main one first.
+    pub fn channels(&self) -> impl Iterator<Item = &Channel> {
+        std::iter::once(self.channel()).chain(self.extra_channels.iter())
+    }
+
+    pub fn naming(&self) -> &NamingStrategy {
+        &self.naming
+    }
+
+    pub fn set_naming(&mut self, naming: NamingStrategy) {
+        self.naming = naming;
+    }
+
+    /// Declare a durable queue named after the naming convention, bind it to `exchange` with `routing_key`
+    /// then return its name, without the topology affixes like any name given to this crate.
+    pub async fn declare_bound_queue(&self, exchange: &str, routing_key: &str) -> Result<String> {
+        let queue = self.naming.queue_name(exchange, routing_key);
+
+        self.channel()
+            .queue_declare(
+                &self.affixes.resolve(&queue),
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        self.channel()
+            .queue_bind(&self.affixes.resolve(&queue), &self.affixes.resolve(exchange), routing_key, QueueBindOptions::default(), FieldTable::default())
+            .await?;
+
+        Ok(queue)
+    }
+
+    /// Route the messages of `source` matching `routing_key` to the exchange `destination` as well,
+    /// to chain exchanges for fan-in or fan-out. Both have to be declared.
+    pub async fn bind_exchange(&self, destination: &str, source: &str, routing_key: &str) -> Result<()> {
+        self.channel()
+            .exchange_bind(
+                &self.affixes.resolve(destination),
+                &self.affixes.resolve(source),
+                routing_key,
+                ExchangeBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove a binding made by `bind_exchange`.
+    pub async fn unbind_exchange(&self, destination: &str, source: &str, routing_key: &str) -> Result<()> {
+        self.channel()
+            .exchange_unbind(
+                &self.affixes.resolve(destination),
+                &self.affixes.resolve(source),
+                routing_key,
+                ExchangeUnbindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Declare the TTL holding queues of a backoff `schedule` (e.g. `[5s, 1m, 10m]`) for the listeners of `exchange`
+    /// consuming `queue`, the retries go back to `queue` only. Return the retry policy to use in `BrokerListener::retry_policy`.
+    pub async fn declare_backoff_ladder(
+        &self,
+        exchange: &str,
+        queue: &str,
+        schedule: &[std::time::Duration],
+        max_attempts: u32,
+    ) -> Result<RetryPolicy> {
+        retry::declare_backoff_ladder(self.channel(), &self.naming, &self.affixes, exchange, queue, schedule, max_attempts).await
+    }
+
+    /// Declare an exclusive, auto-delete queue on the consumer's channel, deleted once dropped.
+    pub async fn declare_exclusive_queue(&self) -> Result<ExclusiveQueue> {
+        ExclusiveQueue::declare_with(self.channel(), self.affixes.clone()).await
+    }
+
+    /// Every instance gets every message of the listener's exchange: declare it as a fanout exchange,
+    /// bind an exclusive queue to it, consume the queue and register the listener.
+    pub async fn subscribe_broadcast(&mut self, listener: Arc<dyn BrokerListener>) -> Result<()> {
+        let exchange = listener.exchange_name();
+        self.channel()
+            .exchange_declare(&self.affixes.resolve(exchange), ExchangeKind::Fanout, ExchangeDeclareOptions::default(), FieldTable::default())
+            .await?;
+
+        let mut queue = self.declare_exclusive_queue().await?;
+        queue.bind(exchange, "").await?;
+        self.add_consumer(queue.consume(BasicConsumeOptions::default()).await?);
+        self.add_listener(listener);
+        self.broadcast_queues.push(Arc::new(queue));
+
+        Ok(())
+    }
+
+    /// Replace every consumer with this one.
+    pub fn set_consumer(&mut self, consumer: lapin::Consumer) {
+        self.consumer_tags = vec![(0, consumer.tag())];
+        self.consumers = vec![(consumer, 1)];
+        self.priority_consumers.clear();
+    }
+
+    /// Add one more consumer, its deliveries get merged with the ones of the others.
+    pub fn add_consumer(&mut self, consumer: lapin::Consumer) {
+        self.add_weighted_consumer(consumer, 1);
+    }
+
+    /// Add one more consumer, up to `weight` of its ready deliveries are dispatched
+    /// before moving to the next consumer. It's expected to be on the main channel.
+    pub fn add_weighted_consumer(&mut self, consumer: lapin::Consumer, weight: u32) {
+        self.consumer_tags.push((0, consumer.tag()));
+        self.consumers.push((consumer, weight));
+    }
+
+    /// Add a consumer whose ready deliveries are always dispatched before the ones of the consumers
+    /// added with `add_consumer`, the priority consumers being served in the order they were added.
+    /// It's expected to be on the main channel.
+    pub fn add_priority_consumer(&mut self, consumer: lapin::Consumer) {
+        self.consumer_tags.push((0, consumer.tag()));
+        self.priority_consumers.push(consumer);
+    }
+
+    /// Consume `{queue}.priority` in preference to `queue`, both on the main channel.
+    /// Both have to be bound to the exchange of the same listener, e.g. the priority one with its own routing key.
+    /// The preference only applies to the deliveries already prefetched, keep the prefetch count low for it to matter.
+    pub async fn consume_priority_lanes(
+        &mut self,
+        queue: &str,
+        lanes: PriorityLanes,
+        options: BasicConsumeOptions,
+        arguments: FieldTable,
+    ) -> Result<()> {
+        let priority_queue = self.affixes.resolve(&format!("{}.priority", queue)).into_owned();
+        let queue = &*self.affixes.resolve(queue);
+
+        match lanes {
+            PriorityLanes::Weighted(weight) => {
+                self.consume_queues(&[(&priority_queue, weight), (queue, 1)], options, arguments).await?;
+            }
+            PriorityLanes::Strict => {
+                let priority = self.channel().basic_consume(&priority_queue, "", options, arguments.clone()).await?;
+                self.add_priority_consumer(priority);
+                let normal = self.channel().basic_consume(queue, "", options, arguments).await?;
+                self.add_consumer(normal);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start a `basic_consume` on the main channel for each `(queue, weight)`.
+    /// Deliveries are dispatched by exchange name, so queues bound to the same exchange
+    /// (e.g. `orders.high` and `orders.low`) all feed the same listener, interleaved by their weight.
+    pub async fn consume_queues(
+        &mut self,
+        queues: &[(&str, u32)],
+        options: BasicConsumeOptions,
+        arguments: FieldTable,
+    ) -> Result<()> {
+        for (queue, weight) in queues {
+            let consumer = self.channel().basic_consume(&self.affixes.resolve(queue), "", options, arguments.clone()).await?;
+            self.add_weighted_consumer(consumer, *weight);
+        }
+
+        Ok(())
+    }
+
+    /// Start a `basic_consume` of `queue` on each channel of the consumer.
+    pub async fn basic_consume_all(
+        &mut self,
+        queue: &str,
+        options: BasicConsumeOptions,
+        arguments: FieldTable,
+    ) -> Result<()> {
+        let queue = &*self.affixes.resolve(queue);
+        let mut consumers = vec![];
+        for channel in self.channels() {
+            // let the server generate the consumer tags
+            consumers.push(channel.basic_consume(queue, "", options, arguments.clone()).await?);
+        }
+        for (index, consumer) in consumers.into_iter().enumerate() {
+            self.consumer_tags.push((index, consumer.tag()));
+            self.consumers.push((consumer, 1));
+        }
+
+        Ok(())
+    }
+
+    /// Copy every successfully consumed message to this exchange too (fire-and-forget).
+    pub fn set_archive_exchange(&mut self, archive_exchange: Option<String>) {
+        self.archive_exchange = archive_exchange;
+    }
+
+    /// Republish the deliveries rejected without requeue to this exchange, with their rejection reason
+    /// in the `x-rejection-reason` header, instead of leaving them to the queue's dead-letter exchange.
+    pub fn set_dead_letter_exchange(&mut self, dead_letter_exchange: Option<String>) {
+        self.dead_letter_exchange = dead_letter_exchange;
+    }
+
+    /// Channel the retries and dead-letters are published on, put in confirm mode: a delivery is acked once its copy
+    /// is confirmed, requeued otherwise. Set by `Broker::setup_consumer`, without it they're requeued.
+    pub async fn set_republish_channel(&mut self, channel: Channel) -> Result<()> {
+        channel.confirm_select(ConfirmSelectOptions::default()).await?;
+        self.republish_channel = Some(channel);
+
+        Ok(())
+    }
+
+    /// Consume and declare the queues and exchanges named with `affixes` rather than the ones of `naming::set_topology_affixes`.
+    pub fn set_topology_affixes(&mut self, affixes: TopologyAffixes) {
+        self.affixes = affixes;
+    }
+
+    pub fn topology_affixes(&self) -> &TopologyAffixes {
+        &self.affixes
+    }
+
+    /// Publisher handed to the listeners in their `ConsumeContext`, set by `Broker::setup_publisher`.
+    pub fn set_publisher(&mut self, publisher: Option<Publisher>) {
+        self.publisher = publisher;
+    }
+
+    /// Decrypt the payloads carrying an encryption key id, before handing them to the listeners.
+    pub fn set_encryptor(&mut self, encryptor: Option<Arc<dyn Encryptor>>) {
+        self.encryptor = encryptor;
+    }
+
+    /// Verify the signature of every delivery, `on_failure` is applied to the ones not signed by `signer`.
+    pub fn set_signer(&mut self, signer: Option<Arc<dyn Signer>>, on_failure: SignatureFailureAction) {
+        self.signer = signer;
+        self.signature_failure = on_failure;
+    }
+
+    /// Headers whose value is never logged, payloads never are.
+    pub fn set_redactor(&mut self, redactor: Redactor) {
+        self.redactor = redactor;
+    }
+
+    /// Reject without requeue (dead-letter when configured) the deliveries larger than `max` bytes,
+    /// before they reach any listener.
+    pub fn set_max_payload_size(&mut self, max: Option<usize>) {
+        self.max_payload_size = max;
+    }
+
+    /// Record the handled deliveries in `journal`, and ack without handling them the redelivered ones it contains.
+    pub fn set_journal(&mut self, journal: Option<Arc<dyn Journal>>) {
+        self.journal = journal;
+    }
+
+    /// Retry policy of the listeners whose `BrokerListener::retry_policy` is `None`.
+    pub fn set_default_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.default_retry_policy = policy;
+    }
+
+    /// What the loop does when its stream of deliveries ends without the consumer being drained.
+    pub fn set_stream_end_action(&mut self, action: StreamEndAction) {
+        self.stream_end = action;
+    }
+
+    /// Keep consuming after errors read from the stream, e.g. a transient channel hiccup, instead of stopping on the first one.
+    pub fn set_stream_error_backoff(&mut self, backoff: Option<StreamErrorBackoff>) {
+        self.stream_error_backoff = backoff;
+    }
+
+    /// How the deliveries of an exchange without listener are nacked, before the consumer panics.
+    /// Keep `requeue` off when several instances share the queue, or they would bounce the delivery forever.
+    pub fn set_unhandled_nack_options(&mut self, options: BasicNackOptions) {
+        self.unhandled_nack = options;
+    }
+
+    /// Don't pull any delivery before `gate` is opened by the application.
+    pub fn set_consume_gate(&mut self, gate: Option<ConsumeGate>) {
+        self.gate = gate;
+    }
+
+    /// Mark the dependencies of the listeners unhealthy to stop pulling deliveries until they recover,
+    /// the ones already received are still consumed.
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Hand the settings of this consumer to its listeners, the current ones and the ones added later.
+    fn share_listeners(&self) -> Arc<ListenerRegistry> {
+        let settings = Arc::new(ConsumerSettings {
+            channel: self.channel.clone(),
+            republish_channel: self.republish_channel.clone(),
+            affixes: self.affixes.clone(),
+            publisher: self.publisher.clone(),
+            archive_exchange: self.archive_exchange.clone(),
+            dead_letter_exchange: self.dead_letter_exchange.clone(),
+            encryptor: self.encryptor.clone(),
+            signer: self.signer.clone(),
+            signature_failure: self.signature_failure.clone(),
+            redactor: self.redactor.clone(),
+            max_payload_size: self.max_payload_size,
+            unhandled_nack: self.unhandled_nack,
+            health: self.health.clone(),
+            gate: self.gate.clone(),
+            default_retry_policy: self.default_retry_policy.clone(),
+            stream_end: self.stream_end.clone(),
+            stream_error_backoff: self.stream_error_backoff,
+            journal: self.journal.clone(),
+        });
+
+        self.listeners.set_settings(settings);
+
+        self.listeners.clone()
+    }
+
+    fn consumer_stream(&self) -> ConsumerStream {
+        if self.consumers.is_empty() && self.priority_consumers.is_empty() {
+            panic!("A consumer hasn't been set.");
+        }
+
+        ConsumerStream::with_priority(self.priority_consumers.clone(), self.consumers.clone())
+    }
+
+    /// Add and store listeners
+    /// When a listener is added, it will bind the queue to the specified exchange name.
+    /// Can be called once spawned, the listener gets the deliveries received from then on.
+    pub fn add_listener(&mut self, listener: Arc<dyn BrokerListener>) {
+        self.listeners.insert(Listener::new(listener));
+    }
+
+    /// Add a listener of typed messages, see `typed::TypedBrokerListener`.
+    pub fn add_typed_listener<T, L>(&mut self, listener: L)
+    where
+        L: typed::TypedBrokerListener<T> + 'static,
+        T: serde::de::DeserializeOwned + Send + 'static,
+    {
+        self.add_listener(Arc::new(typed::Typed::new(listener)));
+    }
+
+    /// Add a listener of typed messages decoded with `codec`, whatever their content type.
+    pub fn add_typed_listener_with_codec<T, L, C>(&mut self, listener: L, codec: C)
+    where
+        L: typed::TypedBrokerListener<T> + 'static,
+        T: serde::de::DeserializeOwned + Send + 'static,
+        C: Codec + Send + Sync + 'static,
+    {
+        self.add_listener(Arc::new(typed::Typed::with_codec(listener, codec)));
+    }
+
+    /// Add `listener` and consume `queue` on `channel` with `prefetch`, so a noisy listener neither takes the prefetch
+    /// of the others nor stalls them when its channel is blocked. Its deliveries are merged with the ones of the other consumers.
+    pub async fn add_listener_with_channel(
+        &mut self,
+        channel: Channel,
+        listener: Arc<dyn BrokerListener>,
+        queue: &str,
+        prefetch: u16,
+    ) -> Result<()> {
+        channel.basic_qos(prefetch, BasicQosOptions::default()).await?;
+        let consumer = channel
+            .basic_consume(&self.affixes.resolve(queue), "", BasicConsumeOptions::default(), FieldTable::default())
+            .await?;
+
+        self.extra_channels.push(channel);
+        self.prefetch.push((self.extra_channels.len(), prefetch));
+        self.consumer_tags.push((self.extra_channels.len(), consumer.tag()));
+        self.consumers.push((consumer, 1));
+        self.add_listener(listener);
+
+        Ok(())
+    }
+
+    /// Deregister the listener of `exchange`, its in-flight deliveries are still consumed.
+    /// Unbind its queue first: once spawned, a delivery of an exchange without listener is nacked and stops the consumer.
+    pub fn remove_listener(&mut self, exchange: &str) -> Option<Arc<dyn BrokerListener>> {
+        self.listeners.remove(exchange).map(|listener| listener.inner.clone())
+    }
+
+    /// Answer the requests published to `exchange`: decode `Req`, run `handler` within `timeout`,
+    /// then publish its `rpc::RpcReply` to `reply_to`.
+    pub fn respond_with<Req, Resp, E, F, Fut>(&mut self, exchange: &'static str, timeout: std::time::Duration, handler: F)
+    where
+        Req: serde::de::DeserializeOwned + Send + 'static,
+        Resp: Serialize + Send + 'static,
+        E: Serialize + Send + 'static,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = std::result::Result<Resp, E>> + Send,
+    {
+        self.add_listener(Arc::new(rpc::Responder::new(exchange, timeout, handler)));
+    }
+
+    /// Consume the dead-letter queue `queue`, bound to `dead_letter_exchange`, on the main channel:
+    /// `handler` gets the original payload decoded as `T` along with its `x-death` metadata.
+    /// An undecodable payload is rejected without requeue.
+    pub async fn on_dead_letter<T, F, Fut>(
+        &mut self,
+        dead_letter_exchange: &'static str,
+        queue: &str,
+        handler: F,
+    ) -> Result<()>
+    where
+        T: serde::de::DeserializeOwned + Send + 'static,
+        F: Fn(dead_letter::DeadLetter<T>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = std::result::Result<(), Rejection>> + Send,
+    {
+        let consumer = self
+            .channel()
+            .basic_consume(&self.affixes.resolve(queue), "", BasicConsumeOptions::default(), FieldTable::default())
+            .await?;
+        self.add_consumer(consumer);
+        self.add_listener(Arc::new(dead_letter::DeadLetterListener::new(dead_letter_exchange, handler)));
+
+        Ok(())
+    }
+
+    /// Fetch a single delivery of `queue` with `basic_get` and consume it with its listener, like the spawned loop would.
+    /// `false` when the queue was empty. For low volume queues, where a dedicated consumer is overkill.
+    pub async fn get_one(&self, queue: &str) -> Result<bool> {
+        let listeners = self.share_listeners();
+
+        let Some(message) = self.channel().basic_get(&self.affixes.resolve(queue), BasicGetOptions::default()).await? else {
+            return Ok(false);
+        };
+        let delivery = message.delivery;
+
+        let Some(listener) = listeners.get_logical(&self.affixes.logical(delivery.exchange.as_str())) else {
+            metrics::count_payload_bytes("in", delivery.data.len());
+            metrics::observe_payload_size(delivery.exchange.as_str(), "in", delivery.data.len());
+            error!(
+                exchange_name = delivery.exchange.as_str(),
+                queue, "Can't find any registered listeners, nacking the delivery"
+            );
+            delivery.nack(self.unhandled_nack).await?;
+            return Ok(true);
+        };
+
+        listener.metrics.observe_payload(delivery.data.len());
+        listener.start().await?;
+        let permit = acquire_or_requeue(&delivery, &listener).await?;
+        listener.metrics.task_started(permit.permits());
+        listener.stats.received();
+        consume_async(delivery, listener, permit).await;
+
+        Ok(true)
+    }
+
+    /// `get_one` up to `max` times, stopping once `queue` is empty. Returns the number of deliveries consumed.
+    pub async fn poll(&self, queue: &str, max: usize) -> Result<usize> {
+        for consumed in 0..max {
+            if !self.get_one(queue).await? {
+                return Ok(consumed);
+            }
+        }
+
+        Ok(max)
+    }
+
+    /// Stop fetching new deliveries then wait, up to `timeout`, for the in-flight ones to be acked or rejected.
+    /// The channels stay open, e.g. for the publisher during a deployment.
+    pub async fn drain(&self, timeout: std::time::Duration) -> Result<()> {
+        self.cancel().await?;
+
+        let drained = runtime::timeout(timeout, async {
+            while self.in_flight() > 0 {
+                runtime::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+
+        match drained {
+            Some(()) => Ok(()),
+            None => Err(Error::DrainTimeout {
+                in_flight: self.in_flight(),
+            }),
+        }
+    }
+
+    /// Run the `on_stop` hook of the listeners without in-flight deliveries, after a `drain`.
+    /// The ones still busy are skipped.
+    pub async fn stop_listeners(&self) {
+        for listener in self.listeners.all() {
+            let in_flight = listener.stats().in_flight;
+            if in_flight > 0 {
+                warn!(exchange_name = listener.inner.exchange_name(), in_flight, "Listener still busy, not stopped");
+                continue;
+            }
+
+            listener.inner.on_stop().await;
+        }
+    }
+
+    /// Stop the broker from sending new deliveries, the ones already received are still consumed.
+    async fn cancel(&self) -> Result<()> {
+        self.listeners.set_cancelled();
+        for (index, tag) in &self.consumer_tags {
+            if let Some(channel) = self.channels().nth(*index) {
+                channel.basic_cancel(tag.as_str(), BasicCancelOptions::default()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deliveries received by the listeners and not acked or rejected yet.
+    pub fn in_flight(&self) -> u64 {
+        self.listeners.in_flight()
+    }
+
+    /// Consumption statistics of every listener, by exchange name, still readable once spawned.
+    pub fn stats(&self) -> Vec<(&'static str, ConsumerStats)> {
+        self.listeners.stats()
+    }
+
+    /// Consume with the listeners of this consumer the shard queues assigned to this instance, on a channel of `conn`.
+    pub async fn partitioned(&self, conn: &Connection, prefetch: u16) -> Result<partition::PartitionedConsumer> {
+        partition::PartitionedConsumer::new(conn, self.share_listeners(), prefetch).await
+    }
+
+    /// Dead-man's switch: check every `interval` whether the consumer received or finished nothing during `stall_after`,
+    /// or lost one of its channels, and call `on_stall` when so.
+    /// Pick `stall_after` above the longest quiet period of the queues, or pair it with a `canary::Canary`.
+    pub fn spawn_watchdog<F>(&self, interval: std::time::Duration, stall_after: std::time::Duration, on_stall: F) -> JoinHandle<()>
+    where
+        F: Fn(watchdog::Stall) + Send + Sync + 'static,
+    {
+        watchdog::spawn(self.channels().cloned().collect(), self.listeners.clone(), interval, stall_after, on_stall)
+    }
+
+    /// Will spawn the Consumer automatically
+    pub fn spawn(&mut self) -> JoinHandle<Result<()>> {
+        let consumer = self.consumer_stream();
+        let listeners = self.share_listeners();
+
+        let handle = tasks::spawn(TaskKind::ConsumerLoop, "amqp-consumer", async move {
+            let mut consumer = consumer;
+            loop {
+                let res = Consumer::consume(consumer, listeners.clone()).await;
+                let StreamEndAction::Recover(recover) = &listeners.settings().stream_end else {
+                    return res;
+                };
+                if !matches!(res, Err(Error::ConsumerEnded)) {
+                    return res;
+                }
+
+                info!("Recovering the consumer stream");
+                consumer = recover().await?;
+            }
+        });
+
+        info!("Consumer has been launched in background.");
+
+        handle
+    }
+
+    /// In order to spawn it manually.
+    pub fn get_consumer(&mut self) -> (ConsumerStream, Arc<ListenerRegistry>) {
+        let consumer = self.consumer_stream();
+        let listeners = self.share_listeners();
 
         (consumer, listeners)
     }
 
     /// Consume messages by finding the appropriated listener.
-    pub async fn consume(
-        mut consumer: lapin::Consumer,
-        listeners: Vec<Listener>,
-    ) -> Result<()> {
+    pub async fn consume<S>(
+        consumer: S,
+        listeners: Arc<ListenerRegistry>,
+    ) -> Result<()>
+    where
+        S: Stream<Item = lapin::Result<Delivery>> + Unpin,
+    {
+        Consumer::consume_until(consumer, listeners, None).await
+    }
+
+    /// `consume`, the end of the stream being expected once `cancelled` is set.
+    async fn consume_until<S>(
+        mut consumer: S,
+        listeners: Arc<ListenerRegistry>,
+        cancelled: Option<Arc<AtomicBool>>,
+    ) -> Result<()>
+    where
+        S: Stream<Item = lapin::Result<Delivery>> + Unpin,
+    {
+        if let Some(gate) = listeners.settings().gate.clone() {
+            if !gate.is_open() {
+                info!("Waiting for the consume gate to open");
+                gate.wait_open().await;
+            }
+        }
+
+        // warm up every listener before the first delivery, the ones added later on their first delivery
+        for listener in listeners.all() {
+            listener.start().await?;
+        }
+
         debug!("Broker consuming...");
-        while let Some(message) = consumer.next().await {
+        let affixes = listeners.settings().affixes.clone();
+        let mut stream_errors = 0;
+        loop {
+            let health = listeners.settings().health.clone();
+            if !health.is_healthy() {
+                info!(unhealthy = ?health.unhealthy(), "Consumption paused");
+                health.wait_healthy().await;
+                info!("Consumption resumed");
+            }
+
+            let Some(message) = consumer.next().await else {
+                if listeners.is_cancelled() || cancelled.is_some_and(|cancelled| cancelled.load(Ordering::Acquire)) {
+                    break;
+                }
+                return match &listeners.settings().stream_end {
+                    StreamEndAction::Stop => {
+                        warn!("Consumer stream ended, no more deliveries are consumed");
+                        Ok(())
+                    }
+                    StreamEndAction::Fail | StreamEndAction::Recover(_) => {
+                        error!("Consumer stream ended");
+                        Err(Error::ConsumerEnded)
+                    }
+                    StreamEndAction::Callback(callback) => {
+                        error!("Consumer stream ended");
+                        callback();
+                        Err(Error::ConsumerEnded)
+                    }
+                };
+            };
+
             match message {
                 Ok(delivery) => {
+                    stream_errors = 0;
                     // info!("received message: {:?}", delivery);
-                    let listener = listeners
-                        .iter()
-                        .find(|listener| listener.listener().exchange_name() == delivery.exchange.as_str());
+                    let listener = listeners.get_logical(&affixes.logical(delivery.exchange.as_str()));
 
                     if let Some(listener) = listener {
                         // Listener found, try to consume the delivery
-                        let listener = listener.clone();
+                        listener.metrics.observe_payload(delivery.data.len());
+                        listener.start().await?;
                         let permits_available = listener.semaphore.available_permits() as i64; // i64 for prometheus
-                        debug!("waiting for a permit ({}/{} available)", permits_available, permits_max = listener.max_concurrent_tasks());
-                        STAT_CONCURRENT_TASK
-                            .with_label_values(&[delivery.exchange.as_str(), "max"])
-                            .set(listener.max_concurrent_tasks() as i64);
-
-                        let permit = listener.semaphore.clone();
-                        let permit = permit.acquire_owned().await?;
-                        debug!("Got a permit, we can start to check");
-
-                        STAT_CONCURRENT_TASK
-                            .with_label_values(&[delivery.exchange.as_str(), "permits_used"])
-                            .inc();
+                        debug!("waiting for a permit ({}/{} available)", permits_available, listener.max_concurrent_tasks());
+
+                        let shedding = listener.load_shedding.unwrap_or_default();
+                        let overloaded = shedding.max_in_flight_time.is_some_and(|max| {
+                            listener.semaphore.available_permits() < listener.cost(&delivery) as usize
+                                && listener.in_flight_times.oldest().is_some_and(|oldest| oldest > max)
+                        });
+                        if overloaded {
+                            shed_delivery(&delivery, &listener, "in_flight_time").await;
+                            continue;
+                        }
 
-                        // consume the delivery asynchronously
-                        task::spawn(consume_async(delivery, listener, permit));
+                        match listener.inner.permit_acquisition() {
+                            PermitAcquisition::Fifo => acquire_and_consume(delivery, listener, shedding).await,
+                            PermitAcquisition::Detached => {
+                                tasks::spawn(TaskKind::Delivery, "amqp-permit-wait", acquire_and_consume(delivery, listener, shedding));
+                            }
+                        }
                     } else {
                         // No listener found for that exchange
-                        if let Err(err) = delivery.nack(BasicNackOptions::default())
+                        metrics::count_payload_bytes("in", delivery.data.len());
+                        metrics::observe_payload_size(delivery.exchange.as_str(), "in", delivery.data.len());
+                        let settings = listeners.settings();
+                        let redactor = &settings.redactor;
+                        if let Err(err) = delivery.nack(settings.unhandled_nack)
                             .await
                         {
-                            panic!("Can't find any registered listeners for `{}` exchange: {:?} + Failed to send nack: {}", &delivery.exchange, &delivery, err);
+                            panic!("Can't find any registered listeners for `{}` exchange: {:?} + Failed to send nack: {}", &delivery.exchange, redactor.delivery(&delivery), err);
                         } else {
                             panic!(
                                 "Can't find any registered listeners for `{}` exchange: {:?}",
-                                &delivery.exchange, &delivery
+                                &delivery.exchange, redactor.delivery(&delivery)
                             );
                         }
                     }
                 }
                 Err(err) => {
                     error!(%err, "Error when receiving a delivery");
-                    Err(err)? // force the binary to shutdown on any AMQP error received
+                    let Some(backoff) = listeners.settings().stream_error_backoff else {
+                        Err(err)? // force the binary to shutdown on any AMQP error received
+                    };
+
+                    stream_errors += 1;
+                    if stream_errors > backoff.max_retries {
+                        error!(stream_errors, "Too many errors in a row when receiving deliveries, giving up");
+                        Err(err)?
+                    }
+                    let delay = backoff.delay(stream_errors);
+                    warn!(stream_errors, ?delay, "Reading the consumer stream again after a delay");
+                    runtime::sleep(delay).await;
                 }
             }
         }
@@ -397,12 +2244,70 @@ impl Consumer {
     }
 }
 
+impl Default for Consumer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Consumer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Consumer")
+            .field("channel", &self.channel)
+            .field("extra_channels", &self.extra_channels)
+            .field("republish_channel", &self.republish_channel)
+            .field("consumer_tags", &self.consumer_tags)
+            .field("listeners", &self.listeners)
+            .field("naming", &self.naming)
+            .field("archive_exchange", &self.archive_exchange)
+            .field("dead_letter_exchange", &self.dead_letter_exchange)
+            .field("encryptor", &self.encryptor.is_some())
+            .field("signer", &self.signer.is_some())
+            .field("signature_failure", &self.signature_failure)
+            .field("redactor", &self.redactor)
+            .field("max_payload_size", &self.max_payload_size)
+            .field("unhandled_nack", &self.unhandled_nack)
+            .field("health", &self.health)
+            .field("gate", &self.gate)
+            .field("default_retry_policy", &self.default_retry_policy)
+            .field("stream_end", &self.stream_end)
+            .field("stream_error_backoff", &self.stream_error_backoff)
+            .field("journal", &self.journal)
+            .field("prefetch", &self.prefetch)
+            .field("affixes", &self.affixes)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Clone for Consumer {
     fn clone(&self) -> Self {
         Self {
             channel: self.channel.clone(),
-            consumer: self.consumer.clone(),
+            extra_channels: self.extra_channels.clone(),
+            republish_channel: self.republish_channel.clone(),
+            consumers: self.consumers.clone(),
+            priority_consumers: self.priority_consumers.clone(),
+            consumer_tags: self.consumer_tags.clone(),
             listeners: self.listeners.clone(),
+            publisher: self.publisher.clone(),
+            broadcast_queues: self.broadcast_queues.clone(),
+            naming: self.naming.clone(),
+            archive_exchange: self.archive_exchange.clone(),
+            dead_letter_exchange: self.dead_letter_exchange.clone(),
+            encryptor: self.encryptor.clone(),
+            signer: self.signer.clone(),
+            signature_failure: self.signature_failure.clone(),
+            redactor: self.redactor.clone(),
+            max_payload_size: self.max_payload_size,
+            unhandled_nack: self.unhandled_nack,
+            health: self.health.clone(),
+            gate: self.gate.clone(),
+            default_retry_policy: self.default_retry_policy.clone(),
+            stream_end: self.stream_end.clone(),
+            stream_error_backoff: self.stream_error_backoff,
+            journal: self.journal.clone(),
+            prefetch: self.prefetch.clone(),
+            affixes: self.affixes.clone(),
         }
     }
 }
@@ -412,45 +2317,462 @@ impl Clone for Consumer {
 //     listener: Arc<L>,
 //     channel: Channel,
 // ) {
-/// Consume the delivery async
+/// `Error::NotConfirmed` unless the broker acked `confirm`.
+async fn check_confirmed(exchange: &str, confirm: PublishConfirm) -> Result<()> {
+    confirm::confirmed(exchange, confirm.await?)
+}
+
+fn record_stage(first_error: &mut Option<Error>, stage: &'static str, res: Option<Result<()>>) {
+    let err = match res {
+        Some(Ok(())) => return,
+        Some(Err(err)) => err,
+        None => Error::ShutdownTimeout(stage),
+    };
+
+    error!(%err, stage, "Shutdown stage failed");
+    first_error.get_or_insert(err);
+}
+
+/// Undo what the publisher did to the payload, before it reaches the listener.
+fn decode_delivery(delivery: &mut Delivery, settings: &ConsumerSettings) -> Result<()> {
+    if let Some(max) = settings.max_payload_size {
+        if delivery.data.len() > max {
+            return Err(Error::PayloadTooLarge { size: delivery.data.len(), max });
+        }
+    }
+
+    if let Some(encryptor) = settings.encryptor.as_ref() {
+        let key_id = headers::get(delivery, encryption::KEY_ID_HEADER).and_then(headers::as_string);
+        if let Some(key_id) = key_id {
+            delivery.data = encryptor.decrypt(&key_id, &delivery.data)?;
+        }
+    }
+
+    if let Some(encoding) = delivery.properties.content_encoding().clone() {
+        if let Some(decompressed) = compression::decompress(encoding.as_str(), &delivery.data)? {
+            delivery.data = decompressed;
+        }
+    }
+
+    Ok(())
+}
+
+fn consume_context(delivery: &Delivery, settings: &ConsumerSettings, downstream: Option<Downstream>) -> ConsumeContext {
+    let channel = settings.channel.clone().expect("Listener's channel is None");
+    let publisher = settings
+        .publisher
+        .clone()
+        .unwrap_or_else(|| Publisher::with_channel(channel.clone()))
+        .with_trace_id(trace::trace_id(delivery))
+        .with_downstream(downstream);
+
+    ConsumeContext::new(delivery, publisher, channel)
+}
+
+/// Reject with requeue a delivery which didn't get a permit, `threshold` being the one exceeded.
+async fn shed_delivery(delivery: &Delivery, listener: &Listener, threshold: &'static str) {
+    warn!(exchange_name = listener.inner.exchange_name(), threshold, "Overloaded, shedding a delivery");
+    metrics::count_rejection(listener.inner.exchange_name(), &Rejection::requeue().with_reason("load_shed"));
+
+    if let Err(err) = listener.inner.reject_method().apply(delivery, true).await {
+        error!(%err, "Failed to reject a shed delivery");
+    }
+}
+
+/// Wait for a permit of `listener`, within the load shedding limits, then consume `delivery` in its own task.
+async fn acquire_and_consume(delivery: Delivery, listener: Arc<Listener>, shedding: LoadShedding) {
+    let waiting_since = std::time::Instant::now();
+    let cost = listener.cost(&delivery);
+    let permit = listener.semaphore.clone().acquire_many_owned(cost);
+    let permit = match shedding.max_wait {
+        Some(max_wait) => match runtime::timeout(max_wait, permit).await {
+            Some(permit) => permit,
+            None => {
+                shed_delivery(&delivery, &listener, "wait").await;
+                return;
+            }
+        },
+        None => permit.await,
+    };
+    let permit = match permit {
+        Ok(permit) => permit,
+        Err(err) => {
+            requeue_without_permit(&delivery, &listener, &err).await;
+            return;
+        }
+    };
+    metrics::observe_permit_wait(listener.inner.exchange_name(), waiting_since.elapsed());
+    let permit = Running::new(permit, cost, shedding.max_in_flight_time.map(|_| &listener.in_flight_times));
+    debug!("Got a permit, we can start to check");
+
+    listener.metrics.task_started(permit.permits());
+    listener.stats.received();
+
+    // consume the delivery asynchronously
+    tasks::spawn(TaskKind::Delivery, "amqp-delivery", consume_async(delivery, listener, permit));
+}
+
+/// The permits of `delivery`, else the error once the delivery is requeued.
+async fn acquire_or_requeue(delivery: &Delivery, listener: &Listener) -> Result<Running> {
+    let cost = listener.cost(delivery);
+    match listener.semaphore.clone().acquire_many_owned(cost).await {
+        Ok(permit) => Ok(Running::new(permit, cost, None)),
+        Err(err) => {
+            requeue_without_permit(delivery, listener, &err).await;
+            Err(err.into())
+        }
+    }
+}
+
+/// Requeue a delivery which can't get a permit, the semaphore of its listener being closed,
+/// rather than stopping the consumer with the delivery unacked.
+async fn requeue_without_permit(delivery: &Delivery, listener: &Listener, err: &AcquireError) {
+    error!(%err, exchange_name = listener.inner.exchange_name(), "No permit for a delivery, requeued");
+    metrics::count_rejection(listener.inner.exchange_name(), &Rejection::requeue().with_reason("permit_unavailable"));
+    listener.stats.received();
+    listener.stats.rejected(true);
+
+    if let Err(err) = listener.inner.reject_method().apply(delivery, true).await {
+        error!(%err, "Failed to requeue a delivery without permit");
+    }
+}
+
+/// Consume the delivery async, returns whether it was acked (republished for retry included).
 async fn consume_async(
-    delivery: Delivery,
-    listener: Listener,
-    permit: OwnedSemaphorePermit,
-) {
+    mut delivery: Delivery,
+    listener: Arc<Listener>,
+    permit: Running,
+) -> bool {
+    let admission = dispatch::admit(
+        &delivery,
+        listener.settings.signer.as_deref(),
+        listener.settings.journal.as_deref(),
+        listener.inner.drop_expired(),
+    );
+    let journal_key = match admission {
+        Admission::Consume { journal_key } => journal_key,
+        admission => {
+            let permits = permit.permits();
+            drop(permit);
+            listener.metrics.task_finished(permits);
+            return skip_delivery(&delivery, &listener, admission).await;
+        }
+    };
+
     // start prometheus duration timer
-    let histogram_timer = STAT_CONSUMER_DURATION.with_label_values(&[listener.inner.exchange_name()]).start_timer();
+    let histogram_timer = listener.metrics.start_timer();
+    let started_at = clock::now();
+
+    // launch the consumer, a delivery which can't be decoded will never be, don't requeue it
+    let res = match decode_delivery(&mut delivery, &listener.settings) {
+        Ok(()) => {
+            let downstream = listener.inner.ack_after_downstream_confirm().then(Downstream::default);
+            let context = consume_context(&delivery, &listener.settings, downstream.clone());
+            let span = info_span!(
+                "amqp_consume",
+                exchange = delivery.exchange.as_str(),
+                routing_key = delivery.routing_key.as_str(),
+                trace_id = trace::trace_id(&delivery),
+            );
+            let consume = listener
+                .listener()
+                .consume_with_context(&delivery, &context)
+                .instrument(span);
 
-    // launch the consumer
-    let res = listener.listener().consume(&delivery).await;
+            let res = dispatch::consume_redelivered(delivery.redelivered, listener.inner.redelivery_policy(), consume).await;
+            dispatch::confirm_downstream(res, downstream.as_ref()).await
+        }
+        Err(err) => {
+            error!(%err, exchange_name = listener.inner.exchange_name(), "Failed to decode a delivery");
+            Err(Rejection::discard().with_reason("undecodable"))
+        }
+    };
+    let permits = permit.permits();
     drop(permit); // release the permit immediately
 
-    STAT_CONCURRENT_TASK
-        .with_label_values(&[delivery.exchange.as_str(), "permits_used"])
-        .dec();
+    listener.metrics.task_finished(permits);
+
+    let elapsed = clock::elapsed(started_at);
+    if listener.inner.slow_threshold().is_some_and(|threshold| elapsed > threshold) {
+        warn!(
+            exchange_name = listener.inner.exchange_name(),
+            routing_key = delivery.routing_key.as_str(),
+            message_id = delivery.properties.message_id().as_ref().map(|id| id.as_str()),
+            ?elapsed,
+            "Slow handler"
+        );
+        metrics::count_slow_handler(listener.inner.exchange_name());
+    }
 
     // finish and compute the duration to prometheus
-    histogram_timer.observe_duration();
+    if let Some(histogram_timer) = histogram_timer {
+        let seconds = histogram_timer.observe_duration();
+        listener.metrics.observe_header_duration(&delivery, seconds);
+    }
 
-    if let Err(requeue) = res {
-        let mut options = BasicRejectOptions::default();
-        options.requeue = requeue;
+    match &res {
+        Ok(()) => listener.stats.acked(),
+        Err(rejection) => {
+            listener.stats.rejected(rejection.requeue);
+            metrics::count_rejection(listener.inner.exchange_name(), rejection);
+
+            let redactor = &listener.settings.redactor;
+            if redactor.has_payload_preview() {
+                warn!(exchange_name = listener.inner.exchange_name(), %rejection, delivery = ?redactor.delivery(&delivery), "Failed delivery");
+            }
+        }
+    }
+
+    let retry_policy = listener.retry_policy.as_ref().or(listener.settings.default_retry_policy.as_ref());
+    match dispatch::settle(&delivery, retry_policy, res) {
+        Settle::Ack => {
+            if let (Some(journal), Some(key)) = (&listener.settings.journal, &journal_key) {
+                journal.record(key);
+            }
+
+            // Consumption went fine, we send ACK
+            if let Err(err) = delivery.ack( BasicAckOptions::default()).await {
+                error!(
+                    %err, "Delivery consumed, but failed to send ACK back to the broker",
+                );
+            }
 
-        if let Err(err_reject) = delivery.reject(options).await {
-            error!(requeue, %err_reject, "Broker failed to send REJECT");
-        } else {
+            if let (Some(channel), Some(archive_exchange)) = (&listener.settings.channel, &listener.settings.archive_exchange) {
+                tap::archive(channel, &listener.settings.affixes, archive_exchange, delivery.exchange.as_str(), delivery.routing_key.as_str(), &delivery.data, &delivery.properties);
+            }
+            true
+        }
+        Settle::Retry { policy, attempt, reason } => retry_delivery(&delivery, &listener, policy, attempt, reason.as_deref()).await,
+        Settle::Poison { attempt, rejection, last_error } => {
             let exchange_name = listener.inner.exchange_name();
-            let routing_key = delivery.routing_key;
-            let redelivered = delivery.redelivered;
+            let routing_key = delivery.routing_key.as_str();
+            warn!(%exchange_name, %routing_key, attempt, reason = last_error.as_deref(), "Retries exhausted, rejected without requeue");
+            reject_delivery(&delivery, &listener, &rejection).await;
+            listener.inner.on_poison(&delivery, attempt, last_error.as_deref()).await;
+            false
+        }
+        Settle::Reject(rejection) => {
+            reject_delivery(&delivery, &listener, &rejection).await;
+            false
+        }
+    }
+}
+
+/// Settle a delivery which didn't reach the listener, after its `admission`. Returns whether it was acked.
+async fn skip_delivery(delivery: &Delivery, listener: &Listener, admission: Admission) -> bool {
+    let exchange_name = listener.inner.exchange_name();
+    let routing_key = delivery.routing_key.as_str();
+
+    let what = match admission {
+        Admission::Unsigned => {
+            reject_unsigned(delivery, listener).await;
+            listener.stats.rejected(false);
+            return false;
+        }
+        Admission::Probe => {
+            debug!(exchange_name, routing_key, "Publish probe acked");
+            "a publish probe"
+        }
+        Admission::Duplicate(key) => {
+            warn!(exchange_name, key = key.as_str(), "Duplicate of a handled delivery, acked");
+            "a duplicate delivery"
+        }
+        Admission::Expired => {
+            debug!(exchange_name, routing_key, "Expired delivery dropped");
+            metrics::count_expired(exchange_name);
+            "an expired delivery"
+        }
+        Admission::Consume { .. } => unreachable!("consumed deliveries aren't skipped"),
+    };
 
-            warn!(requeue, %exchange_name, %routing_key, %redelivered, "Error during consumption of a delivery, `REJECT` sent");
+    if let Err(err) = delivery.ack(BasicAckOptions::default()).await {
+        error!(%err, "Failed to ack {what}");
+    }
+    listener.stats.acked();
+    true
+}
+
+/// Reject `delivery`, or dead-letter it with its reason when the consumer has a dead-letter exchange.
+async fn reject_delivery(delivery: &Delivery, listener: &Listener, rejection: &Rejection) {
+    let exchange_name = listener.inner.exchange_name();
+    let routing_key = delivery.routing_key.as_str();
+    let redelivered = delivery.redelivered;
+    let requeue = rejection.requeue;
+    let reason = rejection.reason.as_deref();
+
+    if !requeue {
+        if let Some(dead_letter_exchange) = listener.settings.dead_letter_exchange.as_deref() {
+            if dead_letter(delivery, listener, dead_letter_exchange, reason).await {
+                warn!(%exchange_name, %routing_key, %redelivered, reason, %dead_letter_exchange, "Error during consumption of a delivery, dead-lettered");
+            }
+            return;
         }
+    }
+
+    if let Err(err_reject) = listener.inner.reject_method().apply(delivery, requeue).await {
+        error!(requeue, %err_reject, "Broker failed to send REJECT");
     } else {
-        // Consumption went fine, we send ACK
-        if let Err(err) = delivery.ack( BasicAckOptions::default()).await {
-            error!(
-                %err, "Delivery consumed, but failed to send ACK back to the broker",
-            );
+        warn!(requeue, %exchange_name, %routing_key, %redelivered, reason, "Error during consumption of a delivery, `REJECT` sent");
+    }
+}
+
+/// Republish `delivery` to `dead_letter_exchange` then ack it once the copy is confirmed, else requeue it.
+/// Whether it was dead-lettered, it's settled either way.
+async fn dead_letter(delivery: &Delivery, listener: &Listener, dead_letter_exchange: &str, reason: Option<&str>) -> bool {
+    let mut overrides = republish::Overrides::default().with_exchange(dead_letter_exchange);
+    if let Some(reason) = reason {
+        overrides = overrides.with_string_header(rejection::REASON_HEADER, reason);
+    }
+    let affixes = &listener.settings.affixes;
+    let (exchange, routing_key, properties) = overrides.apply(delivery, affixes);
+
+    let res = match listener.settings.republish_channel.as_ref() {
+        Some(channel) => publish_confirmed(channel, &affixes.resolve(&exchange), &routing_key, &delivery.data, properties).await,
+        None => Err(Error::NoRepublishChannel),
+    };
+
+    match res {
+        Ok(()) => {
+            if let Err(err) = delivery.ack(BasicAckOptions::default()).await {
+                error!(%err, "Delivery dead-lettered, but failed to send ACK back to the broker");
+            }
+            true
+        }
+        Err(err) => {
+            error!(%err, %dead_letter_exchange, "Failed to dead-letter a delivery, `REJECT` sent with requeue");
+            if let Err(err_reject) = listener.inner.reject_method().apply(delivery, true).await {
+                error!(%err_reject, "Broker failed to send REJECT");
+            }
+            false
+        }
+    }
+}
+
+/// Publish on `channel`, in confirm mode, to `exchange` as named on the broker and wait for the broker's ack.
+async fn publish_confirmed(channel: &Channel, exchange: &str, routing_key: &str, payload: &[u8], properties: BasicProperties) -> Result<()> {
+    let confirmation = channel
+        .basic_publish(exchange, routing_key, BasicPublishOptions::default(), payload, properties)
+        .await?
+        .await?;
+
+    confirm::confirmed(exchange, confirmation)
+}
+
+/// Apply the `SignatureFailureAction` of the consumer.
+async fn reject_unsigned(delivery: &Delivery, listener: &Listener) {
+    let exchange_name = listener.inner.exchange_name();
+    let routing_key = delivery.routing_key.as_str();
+    warn!(%exchange_name, %routing_key, "Delivery with a missing or invalid signature");
+
+    match &listener.settings.signature_failure {
+        SignatureFailureAction::DeadLetter(dead_letter_exchange) => {
+            dead_letter(delivery, listener, dead_letter_exchange, Some("invalid_signature")).await;
+            return;
+        }
+        SignatureFailureAction::Callback(callback) => callback(delivery),
+        SignatureFailureAction::Reject => {}
+    }
+
+    if let Err(err_reject) = listener.inner.reject_method().apply(delivery, false).await {
+        error!(%err_reject, "Broker failed to send REJECT");
+    }
+}
+
+/// Republish a retryable failure to the retry exchange for its `attempt`-th retry, returns whether it was.
+async fn retry_delivery(delivery: &Delivery, listener: &Listener, policy: &RetryPolicy, attempt: u32, reason: Option<&str>) -> bool {
+    let exchange_name = listener.inner.exchange_name();
+    let routing_key = delivery.routing_key.as_str();
+
+    let res = match listener.settings.republish_channel.as_ref() {
+        Some(channel) => retry::republish(channel, &listener.settings.affixes, policy, delivery, attempt, reason).await,
+        None => Err(Error::NoRepublishChannel),
+    };
+    match res {
+        Ok(()) => {
+            warn!(%exchange_name, %routing_key, attempt, retry_exchange = %policy.exchange_for(attempt), "Error during consumption of a delivery, republished for retry");
+            if let Err(err) = delivery.ack(BasicAckOptions::default()).await {
+                error!(%err, "Delivery republished for retry, but failed to send ACK back to the broker");
+            }
+            true
+        }
+        Err(err) => {
+            error!(%err, %exchange_name, %routing_key, "Failed to republish for retry, `REJECT` sent with requeue");
+            if let Err(err_reject) = listener.inner.reject_method().apply(delivery, true).await {
+                error!(%err_reject, "Broker failed to send REJECT");
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+
+    struct Closed;
+
+    #[async_trait]
+    impl BrokerListener for Closed {
+        fn exchange_name(&self) -> &'static str {
+            "test.permit_unavailable"
+        }
+
+        async fn consume(&self, _delivery: &Delivery) -> std::result::Result<(), Rejection> {
+            unreachable!("no permit, no consume")
+        }
+    }
+
+    fn closed_listener() -> Listener {
+        let listener = Listener::new(Arc::new(Closed));
+        listener.semaphore.close();
+        listener
+    }
+
+    fn delivery() -> Delivery {
+        Delivery {
+            delivery_tag: 1,
+            exchange: "test.permit_unavailable".into(),
+            routing_key: "".into(),
+            redelivered: false,
+            properties: BasicProperties::default(),
+            data: vec![],
+            acker: lapin::acker::Acker::default(),
+        }
+    }
+
+    #[test]
+    fn closed_semaphore_requeues_before_the_error() {
+        let listener = closed_listener();
+        let delivery = delivery();
+
+        let res = block_on(acquire_or_requeue(&delivery, &listener));
+
+        assert!(matches!(res, Err(Error::AcquireSemaphore(_))));
+        // already settled, by the requeue
+        assert!(block_on(delivery.ack(BasicAckOptions::default())).is_err());
+    }
+
+    #[test]
+    fn closed_semaphore_requeue_is_counted() {
+        let listener = closed_listener();
+
+        block_on(acquire_and_consume(delivery(), Arc::new(listener.clone()), LoadShedding::default()));
+
+        let stats = listener.stats.snapshot();
+        assert_eq!((stats.received, stats.rejected_requeue, stats.in_flight), (1, 1, 0));
+
+        #[cfg(feature = "prometheus")]
+        {
+            let rejections = prometheus::gather()
+                .into_iter()
+                .find(|family| family.get_name() == "amqp_consumer_rejections_total")
+                .expect("rejections registered");
+            let requeued = rejections.get_metric().iter().find(|metric| {
+                metric.get_label().iter().any(|label| label.get_value() == "test.permit_unavailable")
+                    && metric.get_label().iter().any(|label| label.get_value() == "permit_unavailable")
+            });
+            assert_eq!(requeued.map(|metric| metric.get_counter().get_value()), Some(1.0));
         }
     }
 }
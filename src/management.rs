@@ -0,0 +1,153 @@
+//! Vhosts and permissions through the RabbitMQ management HTTP API, which AMQP can't manage,
+//! e.g. to provision a vhost per test run or per tenant.
+//!
+//! Plain HTTP only, one connection per request.
+
+use crate::redact::base64;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Permissions of a user on a vhost, as regular expressions on the resource names.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions {
+    pub configure: String,
+    pub write: String,
+    pub read: String,
+}
+
+impl Permissions {
+    /// Everything on every resource.
+    pub fn full() -> Self {
+        Self {
+            configure: ".*".to_string(),
+            write: ".*".to_string(),
+            read: ".*".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct UserPermissions {
+    pub user: String,
+    pub vhost: String,
+    #[serde(flatten)]
+    pub permissions: Permissions,
+}
+
+#[derive(Deserialize)]
+struct Vhost {
+    name: String,
+}
+
+#[derive(Clone)]
+pub struct ManagementClient {
+    /// `host:port`
+    address: String,
+    authorization: String,
+}
+
+impl std::fmt::Debug for ManagementClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManagementClient").field("address", &self.address).finish_non_exhaustive()
+    }
+}
+
+impl ManagementClient {
+    /// `url` of the management plugin, e.g. `http://localhost:15672`.
+    pub fn new(url: &str, user: &str, password: &str) -> Result<Self> {
+        let address = url
+            .strip_prefix("http://")
+            .ok_or_else(|| Error::Management(format!("unsupported URL `{url}`, only http:// is")))?
+            .trim_end_matches('/');
+
+        Ok(Self {
+            address: address.to_string(),
+            authorization: format!("Basic {}", base64(format!("{user}:{password}").as_bytes())),
+        })
+    }
+
+    pub async fn list_vhosts(&self) -> Result<Vec<String>> {
+        let body = self.request("GET", "/api/vhosts", None).await?;
+        let vhosts: Vec<Vhost> = parse(&body)?;
+
+        Ok(vhosts.into_iter().map(|vhost| vhost.name).collect())
+    }
+
+    /// Create `vhost`, or leave it as is when it exists.
+    pub async fn create_vhost(&self, vhost: &str) -> Result<()> {
+        self.request("PUT", &format!("/api/vhosts/{}", encode(vhost)), Some("{}".to_string())).await?;
+        Ok(())
+    }
+
+    /// Delete `vhost` along with everything it contains.
+    pub async fn delete_vhost(&self, vhost: &str) -> Result<()> {
+        self.request("DELETE", &format!("/api/vhosts/{}", encode(vhost)), None).await?;
+        Ok(())
+    }
+
+    pub async fn list_permissions(&self, vhost: &str) -> Result<Vec<UserPermissions>> {
+        let body = self.request("GET", &format!("/api/vhosts/{}/permissions", encode(vhost)), None).await?;
+        parse(&body)
+    }
+
+    pub async fn set_permissions(&self, vhost: &str, user: &str, permissions: &Permissions) -> Result<()> {
+        let body = serde_json::to_string(permissions).map_err(|err| Error::Management(err.to_string()))?;
+        self.request("PUT", &format!("/api/permissions/{}/{}", encode(vhost), encode(user)), Some(body)).await?;
+        Ok(())
+    }
+
+    pub async fn clear_permissions(&self, vhost: &str, user: &str) -> Result<()> {
+        self.request("DELETE", &format!("/api/permissions/{}/{}", encode(vhost), encode(user)), None).await?;
+        Ok(())
+    }
+
+    /// Send the request and return the body of a 2xx response.
+    async fn request(&self, method: &str, path: &str, body: Option<String>) -> Result<String> {
+        let io = |err: std::io::Error| Error::Management(err.to_string());
+
+        let mut stream = TcpStream::connect(&self.address).await.map_err(io)?;
+        // HTTP/1.0 so the response is neither chunked nor kept alive, it ends with the connection
+        let body = body.unwrap_or_default();
+        let request = format!(
+            "{method} {path} HTTP/1.0\r\nHost: {}\r\nAuthorization: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            self.address,
+            self.authorization,
+            body.len(),
+        );
+        stream.write_all(request.as_bytes()).await.map_err(io)?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.map_err(io)?;
+        let response = String::from_utf8(response)?;
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| Error::Management(format!("invalid response to {method} {path}")))?;
+
+        if !(200..300).contains(&status) {
+            return Err(Error::Management(format!("{method} {path}: {status} {body}")));
+        }
+
+        Ok(body.to_string())
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(body: &str) -> Result<T> {
+    serde_json::from_str(body).map_err(|err| Error::Management(err.to_string()))
+}
+
+/// Percent-encode a path segment, e.g. the default vhost `/`.
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
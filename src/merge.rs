@@ -0,0 +1,119 @@
+//! Merge the deliveries of several `basic_consume` into the single stream read by the dispatch loop.
+
+use futures_lite::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[derive(Debug)]
+struct Source {
+    consumer: Option<lapin::Consumer>,
+    weight: u32,
+}
+
+/// How a `{queue}.priority` lane is preferred over `{queue}`, see `Consumer::consume_priority_lanes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriorityLanes {
+    /// Up to this many ready deliveries of the priority queue for each one of the normal queue.
+    Weighted(u32),
+    /// The normal queue is only served while the priority one has nothing ready.
+    Strict,
+}
+
+/// Deliveries of several lapin consumers, interleaved by weighted round-robin:
+/// up to `weight` ready deliveries are taken from a consumer before moving to the next one,
+/// so none of them can starve the others. Ends once every underlying consumer has ended.
+///
+/// Priority consumers are polled first, in order, and starve the others for as long as they have deliveries ready.
+#[derive(Debug)]
+pub struct ConsumerStream {
+    priority: Vec<Source>,
+    sources: Vec<Source>,
+    current: usize,
+    served: u32,
+}
+
+impl ConsumerStream {
+    /// Every consumer with the same weight.
+    pub fn new(consumers: Vec<lapin::Consumer>) -> Self {
+        Self::weighted(consumers.into_iter().map(|consumer| (consumer, 1)).collect())
+    }
+
+    /// Each consumer with its own weight, a weight of 0 is handled as 1.
+    pub fn weighted(consumers: Vec<(lapin::Consumer, u32)>) -> Self {
+        Self {
+            priority: vec![],
+            sources: consumers
+                .into_iter()
+                .map(|(consumer, weight)| Source {
+                    consumer: Some(consumer),
+                    weight: weight.max(1),
+                })
+                .collect(),
+            current: 0,
+            served: 0,
+        }
+    }
+
+    /// `priority` consumers, in decreasing priority, are always served before the weighted ones.
+    pub fn with_priority(priority: Vec<lapin::Consumer>, consumers: Vec<(lapin::Consumer, u32)>) -> Self {
+        Self {
+            priority: priority
+                .into_iter()
+                .map(|consumer| Source {
+                    consumer: Some(consumer),
+                    weight: 1,
+                })
+                .collect(),
+            ..Self::weighted(consumers)
+        }
+    }
+
+    fn advance(&mut self) {
+        self.current = (self.current + 1) % self.sources.len();
+        self.served = 0;
+    }
+}
+
+impl Stream for ConsumerStream {
+    type Item = lapin::Result<lapin::message::Delivery>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        for source in this.priority.iter_mut() {
+            if let Some(consumer) = source.consumer.as_mut() {
+                match Pin::new(consumer).poll_next(cx) {
+                    Poll::Ready(Some(delivery)) => return Poll::Ready(Some(delivery)),
+                    Poll::Ready(None) => source.consumer = None,
+                    Poll::Pending => {}
+                }
+            }
+        }
+
+        for _ in 0..this.sources.len() {
+            let source = &mut this.sources[this.current];
+
+            if let Some(consumer) = source.consumer.as_mut() {
+                match Pin::new(consumer).poll_next(cx) {
+                    Poll::Ready(Some(delivery)) => {
+                        this.served += 1;
+                        if this.served >= source.weight {
+                            this.advance();
+                        }
+                        return Poll::Ready(Some(delivery));
+                    }
+                    Poll::Ready(None) => source.consumer = None,
+                    Poll::Pending => {}
+                }
+            }
+
+            this.advance();
+        }
+
+        if this.priority.iter().chain(this.sources.iter()).all(|source| source.consumer.is_none()) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,984 @@
+//! Metrics recorded by the consumer and the publisher.
+//!
+//! Two backends are available, both can be enabled at the same time:
+//!  - `prometheus` (default): registered in the prometheus default registry
+//!  - `metrics`: emitted through the `metrics` crate facade, to whatever recorder/exporter is installed
+// without any backend, every handle is a no-op
+#![cfg_attr(not(any(feature = "prometheus", feature = "metrics")), allow(unused_variables, dead_code))]
+
+#[cfg(feature = "prometheus")]
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+#[cfg(feature = "prometheus")]
+use prometheus::{
+    opts, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, HistogramVec, IntCounterVec,
+    IntGaugeVec,
+};
+use lapin::message::Delivery;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+/// Group of metrics that can be switched on and off at runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsCategory {
+    /// `amqp_consumer_duration`, `amqp_consumer_duration_by_header` and `amqp_consumer_slow_total`
+    ConsumerDuration,
+    /// `amqp_consumer_concurrent_tasks` and `amqp_consumer_permit_wait`
+    ConcurrentTasks,
+    /// `amqp_publisher_duration`
+    PublisherDuration,
+    /// `amqp_canary_round_trip` and `amqp_consumer_watchdog`
+    Canary,
+    /// `amqp_connection`, `amqp_payload_bytes_total` and `amqp_reconnections_total`
+    Connection,
+    /// `amqp_consumer_rejections_total` and `amqp_consumer_expired_total`
+    Rejections,
+    /// `amqp_publisher_confirms_total`
+    PublisherConfirms,
+    /// `amqp_payload_size_bytes` and `amqp_payload_format_total`
+    PayloadSize,
+    /// `amqp_tenant_messages_total`
+    Tenants,
+    /// `amqp_batch_size` and `amqp_batch_flushes_total`
+    Batches,
+}
+
+impl MetricsCategory {
+    fn index(self) -> usize {
+        match self {
+            MetricsCategory::ConsumerDuration => 0,
+            MetricsCategory::ConcurrentTasks => 1,
+            MetricsCategory::PublisherDuration => 2,
+            MetricsCategory::Canary => 3,
+            MetricsCategory::Connection => 4,
+            MetricsCategory::Rejections => 5,
+            MetricsCategory::PublisherConfirms => 6,
+            MetricsCategory::PayloadSize => 7,
+            MetricsCategory::Tenants => 8,
+            MetricsCategory::Batches => 9,
+        }
+    }
+}
+
+static METRICS_ENABLED: AtomicBool = AtomicBool::new(true);
+static CATEGORIES_ENABLED: [AtomicBool; 10] = [
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+];
+
+/// Turn every metric recording on or off, without touching the per-category switches.
+pub fn set_enabled(enabled: bool) {
+    METRICS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Turn the recording of a single category on or off.
+pub fn set_category_enabled(category: MetricsCategory, enabled: bool) {
+    CATEGORIES_ENABLED[category.index()].store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the given category is currently recorded.
+pub fn is_enabled(category: MetricsCategory) -> bool {
+    METRICS_ENABLED.load(Ordering::Relaxed)
+        && CATEGORIES_ENABLED[category.index()].load(Ordering::Relaxed)
+}
+
+const CONCURRENT_TASK: &str = "amqp_consumer_concurrent_tasks";
+const CONSUMER_DURATION: &str = "amqp_consumer_duration";
+const CONSUMER_DURATION_CUSTOM: &str = "amqp_consumer_duration_custom_buckets";
+const PUBLISHER_DURATION: &str = "amqp_publisher_duration";
+const CANARY_ROUND_TRIP: &str = "amqp_canary_round_trip";
+const CONNECTION: &str = "amqp_connection";
+const PAYLOAD_BYTES: &str = "amqp_payload_bytes_total";
+const RECONNECTIONS: &str = "amqp_reconnections_total";
+const REJECTIONS: &str = "amqp_consumer_rejections_total";
+const PUBLISHER_CONFIRMS: &str = "amqp_publisher_confirms_total";
+const PAYLOAD_SIZE: &str = "amqp_payload_size_bytes";
+const WATCHDOG: &str = "amqp_consumer_watchdog";
+const TENANT_MESSAGES: &str = "amqp_tenant_messages_total";
+const SLOW_HANDLERS: &str = "amqp_consumer_slow_total";
+const PAYLOAD_FORMATS: &str = "amqp_payload_format_total";
+const EXPIRED: &str = "amqp_consumer_expired_total";
+const PERMIT_WAIT: &str = "amqp_consumer_permit_wait";
+const HEADER_DURATION: &str = "amqp_consumer_duration_by_header";
+const BATCH_SIZE: &str = "amqp_batch_size";
+const BATCH_FLUSHES: &str = "amqp_batch_flushes_total";
+
+#[cfg(feature = "prometheus")]
+static STAT_CONCURRENT_TASK: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        opts!(
+            CONCURRENT_TASK,
+            "Current/Max concurrent check",
+        ),
+        &["exchange_name", "kind"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+const EXPONENTIAL_SECONDS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static DURATION_BUCKETS: OnceCell<Vec<f64>> = OnceCell::new();
+
+/// Replace the default buckets (5ms to 10s) of the duration histograms, whether it was applied.
+/// It has to be done before any listener is registered and anything is published: the buckets are fixed
+/// by the first duration histogram, `false` is returned afterwards, as on a second call.
+/// The `metrics` backend takes its buckets from the exporter configuration instead.
+#[must_use]
+pub fn set_duration_buckets(buckets: Vec<f64>) -> bool {
+    DURATION_BUCKETS.set(buckets).is_ok()
+}
+
+#[cfg(feature = "prometheus")]
+fn duration_buckets() -> Vec<f64> {
+    DURATION_BUCKETS.get_or_init(|| EXPONENTIAL_SECONDS.to_vec()).clone()
+}
+
+#[cfg(feature = "prometheus")]
+static STAT_CONSUMER_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        CONSUMER_DURATION,
+        "The duration of the consumer",
+        &["exchange_name"],
+        duration_buckets(),
+    ).unwrap()
+});
+
+/// Consumer durations of the exchanges with their own buckets, registered once per exchange.
+#[cfg(feature = "prometheus")]
+static STAT_CONSUMER_DURATION_CUSTOM: Lazy<std::sync::Mutex<std::collections::HashMap<String, prometheus::Histogram>>> =
+    Lazy::new(Default::default);
+
+#[cfg(feature = "prometheus")]
+static STAT_PERMIT_WAIT: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        PERMIT_WAIT,
+        "Time the deliveries waited for a permit of their listener, a long tail means starved deliveries",
+        &["exchange_name"],
+        duration_buckets(),
+    ).unwrap()
+});
+
+/// Headers labelling `amqp_consumer_duration_by_header`, and the values seen so far for each of them.
+struct LabelHeaders {
+    names: Vec<String>,
+    max_values: usize,
+    seen: Mutex<Vec<HashSet<String>>>,
+}
+
+impl LabelHeaders {
+    /// Label names: the header names with `_` for anything but ASCII alphanumerics.
+    #[cfg(feature = "prometheus")]
+    fn label_names(&self) -> Vec<String> {
+        self.names
+            .iter()
+            .map(|name| name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect())
+            .collect()
+    }
+
+    /// Value of each header in `delivery`.
+    fn values(&self, delivery: &Delivery) -> Vec<String> {
+        self.names
+            .iter()
+            .map(|name| {
+                crate::headers::get(delivery, name)
+                    .and_then(|value| crate::headers::as_string(value).or_else(|| crate::headers::as_u64(value).map(|v| v.to_string())))
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Label of each of `values`, `other` once `max_values` distinct ones were seen.
+    fn labels(&self, values: &[String]) -> Vec<String> {
+        let mut seen = self.seen.lock().unwrap();
+
+        values
+            .iter()
+            .zip(seen.iter_mut())
+            .map(|(value, seen)| {
+                if seen.contains(value) || seen.len() < self.max_values {
+                    seen.insert(value.clone());
+                    value.clone()
+                } else {
+                    "other".to_string()
+                }
+            })
+            .collect()
+    }
+}
+
+static LABEL_HEADERS: OnceCell<LabelHeaders> = OnceCell::new();
+
+/// Record the consumer durations in `amqp_consumer_duration_by_header` too, labelled with the values of up to two `headers`
+/// (e.g. a tenant or a partition), whether it was applied: it can only be done once, before any listener is registered.
+/// Beyond `max_values` distinct values of a header, the new ones are labelled `other`.
+pub fn set_label_headers(headers: &[&str], max_values: usize) -> bool {
+    if headers.is_empty() || headers.len() > 2 {
+        return false;
+    }
+
+    LABEL_HEADERS
+        .set(LabelHeaders {
+            names: headers.iter().map(|name| name.to_string()).collect(),
+            max_values,
+            seen: Mutex::new(vec![HashSet::new(); headers.len()]),
+        })
+        .is_ok()
+}
+
+#[cfg(feature = "prometheus")]
+static STAT_HEADER_DURATION: Lazy<Option<HistogramVec>> = Lazy::new(|| {
+    let label_names = LABEL_HEADERS.get()?.label_names();
+    let mut labels = vec!["exchange_name"];
+    labels.extend(label_names.iter().map(String::as_str));
+
+    Some(register_histogram_vec!(
+        HEADER_DURATION,
+        "The duration of the consumer, by value of the label headers",
+        &labels,
+        duration_buckets(),
+    ).unwrap())
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_PUBLISHER_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        PUBLISHER_DURATION,
+        "The duration of the publisher",
+        &["exchange_name", "routing_key"],
+        duration_buckets(),
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_CANARY_ROUND_TRIP: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        CANARY_ROUND_TRIP,
+        "The round trip of the canary probes, from publish to consumption",
+        &["exchange_name"],
+        duration_buckets(),
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_CONNECTION: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        opts!(
+            CONNECTION,
+            "Connected/Blocked (0 or 1) and open channels of each connection",
+        ),
+        &["connection", "kind"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_SLOW_HANDLERS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            SLOW_HANDLERS,
+            "Deliveries handled slower than the slow threshold of their listener",
+        ),
+        &["exchange_name"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_TENANT_MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            TENANT_MESSAGES,
+            "Messages published (out) and consumed (in) by each tenant",
+        ),
+        &["exchange_name", "tenant", "direction"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_PAYLOAD_FORMATS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            PAYLOAD_FORMATS,
+            "Format of the decoded payloads, told by their content type or sniffed",
+        ),
+        &["exchange_name", "format", "source"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_WATCHDOG: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        opts!(
+            WATCHDOG,
+            "Seconds since the consumer last received or finished a delivery, and whether it is stalled (0 or 1)",
+        ),
+        &["kind"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_PAYLOAD_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            PAYLOAD_BYTES,
+            "Bytes of the payloads published (out) and received (in), as sent on the wire",
+        ),
+        &["direction"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_RECONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            RECONNECTIONS,
+            "Reconnection attempts of a broker whose connection died, by outcome",
+        ),
+        &["outcome"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            REJECTIONS,
+            "Deliveries rejected by the listeners, by reason",
+        ),
+        &["exchange_name", "requeue", "reason"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_EXPIRED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            EXPIRED,
+            "Deliveries already expired when received, acked without being handled",
+        ),
+        &["exchange_name"],
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_PUBLISHER_CONFIRMS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            PUBLISHER_CONFIRMS,
+            "Outcome of the publishes: ack, nack, returned, not_requested, failed or unknown (not awaited)",
+        ),
+        &["exchange_name", "outcome"],
+    ).unwrap()
+});
+
+/// 64 bytes to 16 MiB.
+#[cfg(feature = "prometheus")]
+const PAYLOAD_SIZE_BYTES: &[f64] = &[
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+
+#[cfg(feature = "prometheus")]
+static STAT_PAYLOAD_SIZE: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        PAYLOAD_SIZE,
+        "Size of the payloads published (out) and received (in), as sent on the wire",
+        &["exchange_name", "direction"],
+        PAYLOAD_SIZE_BYTES.to_vec(),
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+const BATCH_SIZES: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+
+#[cfg(feature = "prometheus")]
+static STAT_BATCH_SIZE: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        BATCH_SIZE,
+        "Items of the flushed batches",
+        &["batch"],
+        BATCH_SIZES.to_vec(),
+    ).unwrap()
+});
+
+#[cfg(feature = "prometheus")]
+static STAT_BATCH_FLUSHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        opts!(
+            BATCH_FLUSHES,
+            "Batches flushed, once full (size) or after their window (timeout)",
+        ),
+        &["batch", "reason"],
+    ).unwrap()
+});
+
+/// A histogram in every enabled backend.
+#[derive(Clone)]
+struct Histogram {
+    #[cfg(feature = "prometheus")]
+    prometheus: prometheus::Histogram,
+    #[cfg(feature = "metrics")]
+    facade: ::metrics::Histogram,
+}
+
+impl Histogram {
+    fn observe(&self, value: f64) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.observe(value);
+        #[cfg(feature = "metrics")]
+        self.facade.record(value);
+    }
+
+    fn start_timer(&self) -> Timer {
+        Timer {
+            histogram: self.clone(),
+            start: Instant::now(),
+        }
+    }
+}
+
+/// A gauge in every enabled backend.
+#[derive(Clone)]
+struct Gauge {
+    #[cfg(feature = "prometheus")]
+    prometheus: prometheus::IntGauge,
+    #[cfg(feature = "metrics")]
+    facade: ::metrics::Gauge,
+}
+
+impl Gauge {
+    fn set(&self, value: i64) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.set(value);
+        #[cfg(feature = "metrics")]
+        self.facade.set(value as f64);
+    }
+}
+
+/// A counter in every enabled backend.
+#[derive(Clone)]
+struct Counter {
+    #[cfg(feature = "prometheus")]
+    prometheus: prometheus::IntCounter,
+    #[cfg(feature = "metrics")]
+    facade: ::metrics::Counter,
+}
+
+impl Counter {
+    fn inc_by(&self, value: u64) {
+        #[cfg(feature = "prometheus")]
+        self.prometheus.inc_by(value);
+        #[cfg(feature = "metrics")]
+        self.facade.increment(value);
+    }
+}
+
+/// Observe the elapsed time into its histogram once the timed operation is done.
+pub(crate) struct Timer {
+    histogram: Histogram,
+    start: Instant,
+}
+
+impl Timer {
+    /// Returns the seconds observed.
+    pub(crate) fn observe_duration(self) -> f64 {
+        let seconds = self.start.elapsed().as_secs_f64();
+        self.histogram.observe(seconds);
+
+        seconds
+    }
+}
+
+fn concurrent_tasks(exchange_name: &str, kind: &'static str) -> Gauge {
+    Gauge {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_CONCURRENT_TASK.with_label_values(&[exchange_name, kind]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::gauge!(CONCURRENT_TASK, "exchange_name" => exchange_name.to_owned(), "kind" => kind),
+    }
+}
+
+#[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+fn consumer_duration(exchange_name: &str, buckets: Option<Vec<f64>>) -> Histogram {
+    Histogram {
+        #[cfg(feature = "prometheus")]
+        prometheus: match &buckets {
+            Some(buckets) => custom_consumer_duration(exchange_name, buckets.clone()),
+            None => STAT_CONSUMER_DURATION.with_label_values(&[exchange_name]),
+        },
+        #[cfg(feature = "metrics")]
+        facade: match buckets {
+            Some(_) => ::metrics::histogram!(CONSUMER_DURATION_CUSTOM, "exchange_name" => exchange_name.to_owned()),
+            None => ::metrics::histogram!(CONSUMER_DURATION, "exchange_name" => exchange_name.to_owned()),
+        },
+    }
+}
+
+/// `amqp_consumer_duration_custom_buckets`, apart from `amqp_consumer_duration` whose buckets are shared,
+/// the exchange name being a constant label. The buckets of the first registration of an exchange win.
+#[cfg(feature = "prometheus")]
+fn custom_consumer_duration(exchange_name: &str, buckets: Vec<f64>) -> prometheus::Histogram {
+    STAT_CONSUMER_DURATION_CUSTOM
+        .lock()
+        .unwrap()
+        .entry(exchange_name.to_string())
+        .or_insert_with(|| {
+            let opts = prometheus::HistogramOpts::new(CONSUMER_DURATION_CUSTOM, "The duration of the consumer, with the buckets of its listener")
+                .const_label("exchange_name", exchange_name)
+                .buckets(buckets);
+            prometheus::register_histogram!(opts).unwrap()
+        })
+        .clone()
+}
+
+fn publisher_duration(exchange_name: &str, routing_key: &str) -> Histogram {
+    Histogram {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_PUBLISHER_DURATION.with_label_values(&[exchange_name, routing_key]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::histogram!(PUBLISHER_DURATION, "exchange_name" => exchange_name.to_owned(), "routing_key" => routing_key.to_owned()),
+    }
+}
+
+pub(crate) fn observe_canary_round_trip(exchange_name: &str, seconds: f64) {
+    if !is_enabled(MetricsCategory::Canary) {
+        return;
+    }
+
+    Histogram {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_CANARY_ROUND_TRIP.with_label_values(&[exchange_name]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::histogram!(CANARY_ROUND_TRIP, "exchange_name" => exchange_name.to_owned()),
+    }
+    .observe(seconds);
+}
+
+fn watchdog(kind: &'static str) -> Gauge {
+    Gauge {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_WATCHDOG.with_label_values(&[kind]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::gauge!(WATCHDOG, "kind" => kind),
+    }
+}
+
+pub(crate) fn observe_watchdog(idle_seconds: u64, stalled: bool) {
+    if !is_enabled(MetricsCategory::Canary) {
+        return;
+    }
+
+    watchdog("idle_seconds").set(idle_seconds as i64);
+    watchdog("stalled").set(stalled as i64);
+}
+
+fn connection(connection: &'static str, kind: &'static str) -> Gauge {
+    Gauge {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_CONNECTION.with_label_values(&[connection, kind]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::gauge!(CONNECTION, "connection" => connection, "kind" => kind),
+    }
+}
+
+/// lapin doesn't expose the frames or the bytes of its connections,
+/// only their status and channels are reported.
+pub(crate) fn observe_connection(name: &'static str, status: &lapin::ConnectionStatus, channels: usize) {
+    if !is_enabled(MetricsCategory::Connection) {
+        return;
+    }
+
+    connection(name, "connected").set(status.connected() as i64);
+    connection(name, "blocked").set(status.blocked() as i64);
+    connection(name, "channels").set(channels as i64);
+}
+
+fn payload_bytes(direction: &'static str) -> Counter {
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_PAYLOAD_BYTES.with_label_values(&[direction]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(PAYLOAD_BYTES, "direction" => direction),
+    }
+}
+
+/// `direction` is `in` or `out`.
+pub(crate) fn count_payload_bytes(direction: &'static str, bytes: usize) {
+    if !is_enabled(MetricsCategory::Connection) {
+        return;
+    }
+
+    payload_bytes(direction).inc_by(bytes as u64);
+}
+
+/// `outcome` is `succeeded`, `failed` or `gave_up`.
+pub(crate) fn count_reconnection(outcome: &'static str) {
+    if !is_enabled(MetricsCategory::Connection) {
+        return;
+    }
+
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_RECONNECTIONS.with_label_values(&[outcome]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(RECONNECTIONS, "outcome" => outcome),
+    }
+    .inc_by(1);
+}
+
+/// Rejection reasons given by this crate, always labelled as is.
+const CRATE_REASONS: &[&str] = &[
+    "batch_dropped",
+    "batch_outcome_missing",
+    "downstream_not_confirmed",
+    "invalid_signature",
+    "load_shed",
+    "missing_reply_to",
+    "missing_tenant",
+    "next_step_not_confirmed",
+    "next_step_not_published",
+    "not_confirmed",
+    "permit_unavailable",
+    "redelivered",
+    "redelivered_timeout",
+    "reply_not_published",
+    "retries_exhausted",
+    "tenant_mismatch",
+    "undecodable",
+    "undecodable_dead_letter",
+    "undecodable_payload",
+    "unencodable",
+    "unexpected_probe",
+    "unknown_tenant",
+    "unserializable_reply",
+];
+
+static MAX_REJECTION_REASONS: AtomicUsize = AtomicUsize::new(32);
+static REJECTION_REASONS: once_cell::sync::Lazy<Mutex<HashSet<String>>> = once_cell::sync::Lazy::new(Mutex::default);
+
+/// Label `amqp_consumer_rejections_total` with up to `max` distinct reasons of the listeners (32 by default),
+/// the new ones are labelled `other` beyond, so a reason carrying an error message can't blow up the series.
+/// The reasons of this crate are always labelled.
+pub fn set_max_rejection_reasons(max: usize) {
+    MAX_REJECTION_REASONS.store(max, Ordering::Relaxed);
+}
+
+fn reason_label(rejection: &crate::Rejection) -> &str {
+    let Some(reason) = rejection.reason.as_deref() else {
+        return "none";
+    };
+    if CRATE_REASONS.contains(&reason) {
+        return reason;
+    }
+
+    let mut seen = REJECTION_REASONS.lock().unwrap();
+    if seen.contains(reason) || seen.len() < MAX_REJECTION_REASONS.load(Ordering::Relaxed) {
+        seen.insert(reason.to_string());
+        reason
+    } else {
+        "other"
+    }
+}
+
+pub(crate) fn count_rejection(exchange_name: &str, rejection: &crate::Rejection) {
+    if !is_enabled(MetricsCategory::Rejections) {
+        return;
+    }
+
+    let requeue = if rejection.requeue { "true" } else { "false" };
+    let reason = reason_label(rejection);
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_REJECTIONS.with_label_values(&[exchange_name, requeue, reason]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(REJECTIONS, "exchange_name" => exchange_name.to_owned(), "requeue" => requeue, "reason" => reason.to_owned()),
+    }
+    .inc_by(1);
+}
+
+pub(crate) fn count_confirm(exchange_name: &str, confirm: &crate::audit::ConfirmOutcome) {
+    if !is_enabled(MetricsCategory::PublisherConfirms) {
+        return;
+    }
+
+    let outcome = confirm.as_str();
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_PUBLISHER_CONFIRMS.with_label_values(&[exchange_name, outcome]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(PUBLISHER_CONFIRMS, "exchange_name" => exchange_name.to_owned(), "outcome" => outcome),
+    }
+    .inc_by(1);
+}
+
+fn payload_size(exchange_name: &str, direction: &'static str) -> Histogram {
+    Histogram {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_PAYLOAD_SIZE.with_label_values(&[exchange_name, direction]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::histogram!(PAYLOAD_SIZE, "exchange_name" => exchange_name.to_owned(), "direction" => direction),
+    }
+}
+
+/// `direction` is `in` or `out`.
+pub(crate) fn observe_payload_size(exchange_name: &str, direction: &'static str, bytes: usize) {
+    if !is_enabled(MetricsCategory::PayloadSize) {
+        return;
+    }
+
+    payload_size(exchange_name, direction).observe(bytes as f64);
+}
+
+/// `reason` is `size` or `timeout`.
+pub(crate) fn observe_batch(batch: &str, size: usize, reason: &'static str) {
+    if !is_enabled(MetricsCategory::Batches) {
+        return;
+    }
+
+    Histogram {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_BATCH_SIZE.with_label_values(&[batch]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::histogram!(BATCH_SIZE, "batch" => batch.to_owned()),
+    }
+    .observe(size as f64);
+
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_BATCH_FLUSHES.with_label_values(&[batch, reason]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(BATCH_FLUSHES, "batch" => batch.to_owned(), "reason" => reason),
+    }
+    .inc_by(1);
+}
+
+pub(crate) fn observe_permit_wait(exchange_name: &str, waited: std::time::Duration) {
+    if !is_enabled(MetricsCategory::ConcurrentTasks) {
+        return;
+    }
+
+    Histogram {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_PERMIT_WAIT.with_label_values(&[exchange_name]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::histogram!(PERMIT_WAIT, "exchange_name" => exchange_name.to_owned()),
+    }
+    .observe(waited.as_secs_f64());
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+fn header_duration(exchange_name: &str, headers: &LabelHeaders, labels: Vec<String>) -> Histogram {
+    Histogram {
+        #[cfg(feature = "prometheus")]
+        prometheus: {
+            let mut values = vec![exchange_name];
+            values.extend(labels.iter().map(String::as_str));
+            STAT_HEADER_DURATION.as_ref().expect("label headers set").with_label_values(&values)
+        },
+        #[cfg(feature = "metrics")]
+        facade: {
+            let mut values = vec![::metrics::Label::new("exchange_name", exchange_name.to_owned())];
+            values.extend(headers.names.iter().zip(labels).map(|(name, label)| ::metrics::Label::new(name.clone(), label)));
+            ::metrics::histogram!(HEADER_DURATION, values)
+        },
+    }
+}
+
+pub(crate) fn count_slow_handler(exchange_name: &str) {
+    if !is_enabled(MetricsCategory::ConsumerDuration) {
+        return;
+    }
+
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_SLOW_HANDLERS.with_label_values(&[exchange_name]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(SLOW_HANDLERS, "exchange_name" => exchange_name.to_owned()),
+    }
+    .inc_by(1);
+}
+
+pub(crate) fn count_expired(exchange_name: &str) {
+    if !is_enabled(MetricsCategory::Rejections) {
+        return;
+    }
+
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_EXPIRED.with_label_values(&[exchange_name]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(EXPIRED, "exchange_name" => exchange_name.to_owned()),
+    }
+    .inc_by(1);
+}
+
+/// `direction` is `in` or `out`.
+pub(crate) fn count_tenant_message(exchange_name: &str, tenant: &str, direction: &'static str) {
+    if !is_enabled(MetricsCategory::Tenants) {
+        return;
+    }
+
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_TENANT_MESSAGES.with_label_values(&[exchange_name, tenant, direction]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(TENANT_MESSAGES, "exchange_name" => exchange_name.to_owned(), "tenant" => tenant.to_owned(), "direction" => direction),
+    }
+    .inc_by(1);
+}
+
+pub(crate) fn count_payload_format(exchange_name: &str, format: &'static str, source: &'static str) {
+    if !is_enabled(MetricsCategory::PayloadSize) {
+        return;
+    }
+
+    Counter {
+        #[cfg(feature = "prometheus")]
+        prometheus: STAT_PAYLOAD_FORMATS.with_label_values(&[exchange_name, format, source]),
+        #[cfg(feature = "metrics")]
+        facade: ::metrics::counter!(PAYLOAD_FORMATS, "exchange_name" => exchange_name.to_owned(), "format" => format, "source" => source),
+    }
+    .inc_by(1);
+}
+
+/// Start a publish duration timer, `None` when the category is disabled.
+pub(crate) fn publisher_timer(exchange: &str, routing_key: &str) -> Option<Timer> {
+    is_enabled(MetricsCategory::PublisherDuration)
+        .then(|| publisher_duration(exchange, routing_key).start_timer())
+}
+
+/// Metric handles of a listener, resolved once when the listener is registered
+/// so the consume loop never has to hash labels per delivery.
+#[derive(Clone)]
+pub(crate) struct ListenerMetrics {
+    exchange_name: String,
+    duration: Histogram,
+    permits_max: Gauge,
+    permits_used: Gauge,
+    payload_size: Histogram,
+    payload_bytes: Counter,
+    max_concurrent_tasks: i64,
+    // always maintained, the gauges are only a view of it
+    in_flight: Arc<AtomicI64>,
+    /// See `set_label_headers`, read once.
+    label_headers: Option<&'static LabelHeaders>,
+    /// `amqp_consumer_duration_by_header` by value of the label headers, but for the ones labelled `other`.
+    header_durations: Arc<RwLock<HashMap<Vec<String>, Histogram>>>,
+}
+
+impl ListenerMetrics {
+    pub(crate) fn new(exchange_name: &str, max_concurrent_tasks: usize, duration_buckets: Option<Vec<f64>>) -> Self {
+        Self {
+            exchange_name: exchange_name.to_string(),
+            duration: consumer_duration(exchange_name, duration_buckets),
+            permits_max: concurrent_tasks(exchange_name, "max"),
+            permits_used: concurrent_tasks(exchange_name, "permits_used"),
+            payload_size: payload_size(exchange_name, "in"),
+            payload_bytes: payload_bytes("in"),
+            max_concurrent_tasks: max_concurrent_tasks as i64,
+            in_flight: Arc::new(AtomicI64::new(0)),
+            label_headers: LABEL_HEADERS.get(),
+            header_durations: Arc::default(),
+        }
+    }
+
+    /// `amqp_payload_bytes_total` and `amqp_payload_size_bytes` of a delivery.
+    pub(crate) fn observe_payload(&self, bytes: usize) {
+        if is_enabled(MetricsCategory::Connection) {
+            self.payload_bytes.inc_by(bytes as u64);
+        }
+        if is_enabled(MetricsCategory::PayloadSize) {
+            self.payload_size.observe(bytes as f64);
+        }
+    }
+
+    /// See `set_label_headers`, `seconds` being already recorded in `amqp_consumer_duration`.
+    /// The labels of values seen before by the listener are looked up without the lock of the values seen by every listener.
+    pub(crate) fn observe_header_duration(&self, delivery: &Delivery, seconds: f64) {
+        let Some(headers) = self.label_headers else {
+            return;
+        };
+        if !is_enabled(MetricsCategory::ConsumerDuration) {
+            return;
+        }
+
+        let values = headers.values(delivery);
+        if let Some(histogram) = self.header_durations.read().unwrap().get(&values) {
+            histogram.observe(seconds);
+            return;
+        }
+
+        let labels = headers.labels(&values);
+        let cached = labels == values;
+        let histogram = header_duration(&self.exchange_name, headers, labels);
+        histogram.observe(seconds);
+        // the values labelled `other` aren't kept, they're unbounded
+        if cached {
+            self.header_durations.write().unwrap().insert(values, histogram);
+        }
+    }
+
+    pub(crate) fn start_timer(&self) -> Option<Timer> {
+        is_enabled(MetricsCategory::ConsumerDuration).then(|| self.duration.start_timer())
+    }
+
+    /// A delivery holding `permits` permits started.
+    pub(crate) fn task_started(&self, permits: u32) {
+        let in_flight = self.in_flight.fetch_add(permits as i64, Ordering::Relaxed) + permits as i64;
+        self.update_gauges(in_flight);
+    }
+
+    pub(crate) fn task_finished(&self, permits: u32) {
+        let in_flight = self.in_flight.fetch_sub(permits as i64, Ordering::Relaxed) - permits as i64;
+        self.update_gauges(in_flight);
+    }
+
+    fn update_gauges(&self, in_flight: i64) {
+        if is_enabled(MetricsCategory::ConcurrentTasks) {
+            self.permits_max.set(self.max_concurrent_tasks);
+            self.permits_used.set(in_flight);
+        }
+    }
+}
+
+/// Encode every metric of the prometheus default registry in the text exposition format.
+#[cfg(feature = "exposition")]
+pub fn render() -> String {
+    use prometheus::Encoder;
+
+    let mut buffer = vec![];
+    prometheus::TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Failed to encode the prometheus metrics");
+
+    String::from_utf8(buffer).expect("Prometheus text exposition is not UTF-8")
+}
+
+/// A hyper handler answering every request with `render()`, for apps which don't run any other HTTP stack.
+///
+/// ```ignore
+/// hyper::server::conn::http1::Builder::new()
+///     .serve_connection(io, hyper::service::service_fn(amqp_lapin_helper::metrics::hyper_handler))
+///     .await?;
+/// ```
+#[cfg(feature = "exposition-hyper")]
+pub async fn hyper_handler<B>(
+    _request: hyper::Request<B>,
+) -> std::result::Result<hyper::Response<http_body_util::Full<bytes::Bytes>>, std::convert::Infallible> {
+    let response = hyper::Response::builder()
+        .header(hyper::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+        .body(http_body_util::Full::new(bytes::Bytes::from(render())))
+        .expect("Failed to build the metrics response");
+
+    Ok(response)
+}
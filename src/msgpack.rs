@@ -0,0 +1,268 @@
+//! MessagePack of the serde values, for `format::MsgPackCodec`.
+//! The binaries decode to arrays of bytes and the extension types aren't supported.
+
+use crate::{Error, Result};
+use serde_json::{Map, Number, Value};
+
+/// Nesting deeper than this is refused when decoding, as serde_json does.
+const MAX_DEPTH: usize = 128;
+
+pub(crate) fn encode(value: &Value) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    write_value(&mut out, value)?;
+    Ok(out)
+}
+
+pub(crate) fn decode(data: &[u8]) -> Result<Value> {
+    let mut reader = Reader { data, position: 0 };
+    let value = reader.value(0)?;
+
+    if reader.position != data.len() {
+        return Err(Error::Decode("trailing bytes after the MessagePack value".to_string()));
+    }
+    Ok(value)
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) -> Result<()> {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => write_number(out, number),
+        Value::String(string) => write_str(out, string)?,
+        Value::Array(values) => {
+            write_len(out, values.len(), 0x90, 16, 0xdc, 0xdd)?;
+            for value in values {
+                write_value(out, value)?;
+            }
+        }
+        Value::Object(map) => {
+            write_len(out, map.len(), 0x80, 16, 0xde, 0xdf)?;
+            for (key, value) in map {
+                write_str(out, key)?;
+                write_value(out, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_number(out: &mut Vec<u8>, number: &Number) {
+    if let Some(n) = number.as_u64() {
+        match n {
+            0..=0x7f => out.push(n as u8),
+            0x80..=0xff => out.extend([0xcc, n as u8]),
+            0x100..=0xffff => {
+                out.push(0xcd);
+                out.extend((n as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(0xce);
+                out.extend((n as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xcf);
+                out.extend(n.to_be_bytes());
+            }
+        }
+    } else if let Some(n) = number.as_i64() {
+        // negative, the positive ones are u64
+        if n >= -32 {
+            out.push(n as u8);
+        } else if n >= i8::MIN as i64 {
+            out.extend([0xd0, n as u8]);
+        } else if n >= i16::MIN as i64 {
+            out.push(0xd1);
+            out.extend((n as i16).to_be_bytes());
+        } else if n >= i32::MIN as i64 {
+            out.push(0xd2);
+            out.extend((n as i32).to_be_bytes());
+        } else {
+            out.push(0xd3);
+            out.extend(n.to_be_bytes());
+        }
+    } else {
+        out.push(0xcb);
+        out.extend(number.as_f64().unwrap_or_default().to_be_bytes());
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, string: &str) -> Result<()> {
+    if string.len() < 32 {
+        out.push(0xa0 | string.len() as u8);
+    } else if string.len() <= u8::MAX as usize {
+        out.extend([0xd9, string.len() as u8]);
+    } else {
+        write_len(out, string.len(), 0, 0, 0xda, 0xdb)?;
+    }
+    out.extend(string.as_bytes());
+    Ok(())
+}
+
+/// The fix marker when `len` is below `fix_max`, else the 16 bits or 32 bits marker and length.
+fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, marker16: u8, marker32: u8) -> Result<()> {
+    if len < fix_max {
+        out.push(fix | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(marker16);
+        out.extend(len.to_be_bytes());
+    } else if let Ok(len) = u32::try_from(len) {
+        out.push(marker32);
+        out.extend(len.to_be_bytes());
+    } else {
+        return Err(Error::Encode(format!("{len} elements are too many for MessagePack")));
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| Error::Decode("truncated MessagePack value".to_string()))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    fn len(&mut self, bytes: usize) -> Result<usize> {
+        Ok(match bytes {
+            1 => u8::from_be_bytes(self.array()?) as usize,
+            2 => u16::from_be_bytes(self.array()?) as usize,
+            _ => u32::from_be_bytes(self.array()?) as usize,
+        })
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(Error::Decode("MessagePack value nested too deeply".to_string()));
+        }
+
+        let marker = self.array::<1>()?[0];
+        Ok(match marker {
+            0x00..=0x7f => Value::from(marker),
+            0x80..=0x8f => self.map((marker & 0x0f) as usize, depth)?,
+            0x90..=0x9f => self.seq((marker & 0x0f) as usize, depth)?,
+            0xa0..=0xbf => self.str((marker & 0x1f) as usize)?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4..=0xc6 => {
+                let len = self.len(1 << (marker - 0xc4))?;
+                Value::Array(self.take(len)?.iter().map(|byte| Value::from(*byte)).collect())
+            }
+            0xca => Value::from(f32::from_be_bytes(self.array()?) as f64),
+            0xcb => Value::from(f64::from_be_bytes(self.array()?)),
+            0xcc => Value::from(u8::from_be_bytes(self.array()?)),
+            0xcd => Value::from(u16::from_be_bytes(self.array()?)),
+            0xce => Value::from(u32::from_be_bytes(self.array()?)),
+            0xcf => Value::from(u64::from_be_bytes(self.array()?)),
+            0xd0 => Value::from(i8::from_be_bytes(self.array()?)),
+            0xd1 => Value::from(i16::from_be_bytes(self.array()?)),
+            0xd2 => Value::from(i32::from_be_bytes(self.array()?)),
+            0xd3 => Value::from(i64::from_be_bytes(self.array()?)),
+            0xd9..=0xdb => {
+                let len = self.len(1 << (marker - 0xd9))?;
+                self.str(len)?
+            }
+            0xdc | 0xdd => {
+                let len = self.len(if marker == 0xdc { 2 } else { 4 })?;
+                self.seq(len, depth)?
+            }
+            0xde | 0xdf => {
+                let len = self.len(if marker == 0xde { 2 } else { 4 })?;
+                self.map(len, depth)?
+            }
+            0xe0..=0xff => Value::from(marker as i8),
+            marker => return Err(Error::Decode(format!("unsupported MessagePack marker {marker:#04x}"))),
+        })
+    }
+
+    fn str(&mut self, len: usize) -> Result<Value> {
+        let bytes = self.take(len)?;
+        let string = std::str::from_utf8(bytes).map_err(|err| Error::Decode(err.to_string()))?;
+        Ok(Value::String(string.to_string()))
+    }
+
+    fn seq(&mut self, len: usize, depth: usize) -> Result<Value> {
+        // every element takes a byte at least, don't trust `len` further
+        let mut values = Vec::with_capacity(len.min(self.data.len() - self.position));
+        for _ in 0..len {
+            values.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> Result<Value> {
+        let mut map = Map::new();
+        for _ in 0..len {
+            let key = match self.value(depth + 1)? {
+                Value::String(key) => key,
+                key => return Err(Error::Decode(format!("MessagePack map key {key} isn't a string"))),
+            };
+            map.insert(key, self.value(depth + 1)?);
+        }
+        Ok(Value::Object(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn decode_error(data: &[u8]) -> String {
+        match decode(data) {
+            Err(Error::Decode(message)) => message,
+            res => panic!("expected a decode error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = json!({"id": 300, "delta": -40, "ratio": 0.5, "tags": ["a", null, true], "nested": {"b": false}});
+        assert_eq!(decode(&encode(&value).unwrap()).unwrap(), value);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = encode(&json!({"id": 70000, "name": "order"})).unwrap();
+        for len in 0..data.len() {
+            assert_eq!(decode_error(&data[..len]), "truncated MessagePack value");
+        }
+        assert_eq!(decode_error(&[0x01, 0x02]), "trailing bytes after the MessagePack value");
+    }
+
+    #[test]
+    fn depth_limit() {
+        // fixarrays of a single element around a nil
+        let nested = |depth: usize| [vec![0x91; depth], vec![0xc0]].concat();
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(decode_error(&nested(MAX_DEPTH + 1)), "MessagePack value nested too deeply");
+    }
+
+    #[test]
+    fn non_string_map_key() {
+        assert_eq!(decode_error(&[0x81, 0x01, 0xc0]), "MessagePack map key 1 isn't a string");
+    }
+
+    #[test]
+    fn largest_length_prefixes() {
+        for marker in [0xc6, 0xdb, 0xdd, 0xdf] {
+            assert_eq!(decode_error(&[marker, 0xff, 0xff, 0xff, 0xff]), "truncated MessagePack value");
+        }
+        assert_eq!(decode(&[0xdd, 0, 0, 0, 1, 0xc0]).unwrap(), json!([null]));
+        assert_eq!(decode(&[0xdf, 0, 0, 0, 1, 0xa1, b'a', 0x01]).unwrap(), json!({"a": 1}));
+    }
+}
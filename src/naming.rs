@@ -0,0 +1,175 @@
+//! Naming convention used by every helper which declares queues on its own,
+//! and the prefix/suffix of the whole topology (see `set_topology_affixes`).
+
+use std::borrow::Cow;
+use std::sync::RwLock;
+
+static AFFIXES: RwLock<TopologyAffixes> = RwLock::new(TopologyAffixes {
+    prefix: String::new(),
+    suffix: String::new(),
+});
+
+/// Prefix and suffix (e.g. `staging.`) of every exchange and queue declared, bound, published to or consumed
+/// by this crate, so several environments can share a vhost. The listeners keep their unprefixed exchange name.
+/// The default exchange and the `amq.*` ones are left alone. To be set before anything is declared:
+/// the brokers, publishers and consumers created afterwards take them, see `Broker::set_topology_affixes` for a single one.
+pub fn set_topology_affixes(prefix: &str, suffix: &str) {
+    *AFFIXES.write().unwrap() = TopologyAffixes::new(prefix, suffix);
+}
+
+/// The affixes set by `set_topology_affixes`, the default of the brokers, publishers and consumers.
+pub fn topology_affixes() -> TopologyAffixes {
+    AFFIXES.read().unwrap().clone()
+}
+
+/// `name` as on the broker, with the affixes. Only for the names known by the application:
+/// the ones read from the broker (e.g. the exchange of a delivery) are already affixed.
+pub fn resolve(name: &str) -> Cow<'_, str> {
+    AFFIXES.read().unwrap().resolve(name)
+}
+
+/// `name` as known by the application, without the affixes.
+pub fn logical(name: &str) -> Cow<'_, str> {
+    AFFIXES.read().unwrap().logical(name)
+}
+
+/// Prefix and suffix of the topology, see `set_topology_affixes`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopologyAffixes {
+    prefix: String,
+    suffix: String,
+}
+
+impl TopologyAffixes {
+    pub fn new(prefix: &str, suffix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+        }
+    }
+
+    /// See `resolve`.
+    pub fn resolve<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let Self { prefix, suffix } = self;
+
+        if name.is_empty() || name.starts_with("amq.") || (prefix.is_empty() && suffix.is_empty()) {
+            return Cow::Borrowed(name);
+        }
+
+        Cow::Owned(format!("{prefix}{name}{suffix}"))
+    }
+
+    /// See `logical`.
+    pub fn logical<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match name.strip_prefix(self.prefix.as_str()).and_then(|name| name.strip_suffix(self.suffix.as_str())) {
+            Some(logical) if logical.len() != name.len() => Cow::Owned(logical.to_string()),
+            _ => Cow::Borrowed(name),
+        }
+    }
+}
+
+/// Build queue names out of a template, e.g. `{env}.{app}.{exchange}.{routing}`.
+///
+/// Placeholders: `{env}`, `{app}`, `{exchange}` and `{routing}`.
+/// Segments left empty once rendered are dropped, so `{env}.{app}.{exchange}` without any environment
+/// renders `app.exchange` rather than `.app.exchange`.
+#[derive(Clone, Debug)]
+pub struct NamingStrategy {
+    template: String,
+    app: String,
+    environment: String,
+}
+
+impl Default for NamingStrategy {
+    fn default() -> Self {
+        Self {
+            template: Self::DEFAULT_TEMPLATE.to_string(),
+            app: String::new(),
+            environment: String::new(),
+        }
+    }
+}
+
+impl NamingStrategy {
+    pub const DEFAULT_TEMPLATE: &'static str = "{env}.{app}.{exchange}.{routing}";
+
+    pub fn new(app: &str) -> Self {
+        Self {
+            app: app.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_template(mut self, template: &str) -> Self {
+        self.template = template.to_string();
+        self
+    }
+
+    pub fn with_environment(mut self, environment: &str) -> Self {
+        self.environment = environment.to_string();
+        self
+    }
+
+    /// Name of the queue consuming `routing_key` from `exchange`.
+    pub fn queue_name(&self, exchange: &str, routing_key: &str) -> String {
+        let rendered = self
+            .template
+            .replace("{env}", &self.environment)
+            .replace("{app}", &self.app)
+            .replace("{exchange}", exchange)
+            .replace("{routing}", routing_key);
+
+        rendered
+            .split('.')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_then_logical_round_trips() {
+        let affixes = TopologyAffixes::new("staging.", ".v2");
+
+        assert_eq!(affixes.resolve("orders"), "staging.orders.v2");
+        assert_eq!(affixes.logical(&affixes.resolve("orders")), "orders");
+        assert_eq!(affixes.logical("orders"), "orders");
+    }
+
+    #[test]
+    fn default_and_amq_exchanges_are_left_alone() {
+        let affixes = TopologyAffixes::new("staging.", "");
+
+        assert_eq!(affixes.resolve(""), "");
+        assert_eq!(affixes.resolve("amq.topic"), "amq.topic");
+        assert!(matches!(affixes.resolve("orders"), Cow::Owned(_)));
+    }
+
+    #[test]
+    fn without_affixes_names_are_borrowed() {
+        let affixes = TopologyAffixes::default();
+
+        assert!(matches!(affixes.resolve("orders"), Cow::Borrowed("orders")));
+        assert!(matches!(affixes.logical("orders"), Cow::Borrowed("orders")));
+    }
+
+    #[test]
+    fn logical_needs_both_affixes() {
+        let affixes = TopologyAffixes::new("staging.", ".v2");
+
+        assert_eq!(affixes.logical("staging.orders"), "staging.orders");
+        assert_eq!(affixes.logical("orders.v2"), "orders.v2");
+    }
+
+    #[test]
+    fn queue_names_drop_the_empty_segments() {
+        let naming = NamingStrategy::new("billing");
+
+        assert_eq!(naming.queue_name("orders", "created"), "billing.orders.created");
+        assert_eq!(naming.with_environment("prod").queue_name("orders", ""), "prod.billing.orders");
+    }
+}
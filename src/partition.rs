@@ -0,0 +1,159 @@
+//! Partition-aware consumption of sharded queues (e.g. bound to a consistent-hash exchange): each instance consumes
+//! the shard queues it owns, as decided by a `Coordinator`, and hands them off when instances join or leave.
+//!
+//! Every shard has its own `basic_consume`, with its own prefetch, on a channel shared by the shards. A revoked shard
+//! is cancelled: its deliveries already received are still consumed, the next ones go to the instance taking it over.
+
+use crate::registry::ListenerRegistry;
+use crate::runtime::{self, JoinHandle};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::tasks::{self, TaskKind};
+use crate::{Consumer, Result};
+use async_trait::async_trait;
+use lapin::options::{BasicCancelOptions, BasicConsumeOptions, BasicQosOptions};
+use lapin::types::{FieldTable, ShortString};
+use lapin::{Channel, Connection};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Decides which shard queues this instance owns, e.g. from a membership service and `rendezvous`.
+#[async_trait]
+pub trait Coordinator: Send + Sync {
+    async fn assigned(&self) -> Result<Vec<String>>;
+}
+
+/// The `shards` owned by `instance` among `members`, each shard going to the member with the highest hash
+/// of the pair: a member joining or leaving only moves its own shards.
+pub fn rendezvous(instance: &str, members: &[String], shards: &[String]) -> Vec<String> {
+    shards
+        .iter()
+        .filter(|shard| {
+            members
+                .iter()
+                .max_by_key(|member| fnv1a(member, shard))
+                .is_some_and(|owner| owner == instance)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Stable across processes and versions, unlike the `DefaultHasher`.
+fn fnv1a(member: &str, shard: &str) -> u64 {
+    member
+        .bytes()
+        .chain(std::iter::once(0))
+        .chain(shard.bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+struct Shard {
+    tag: ShortString,
+    /// Tells its loop the end of its stream is expected.
+    revoked: Arc<AtomicBool>,
+}
+
+/// Consumes the shard queues owned by this instance with the listeners of a `Consumer`, see `Consumer::partitioned`.
+pub struct PartitionedConsumer {
+    channel: Channel,
+    listeners: Arc<ListenerRegistry>,
+    prefetch: u16,
+    owned: BTreeMap<String, Shard>,
+}
+
+impl std::fmt::Debug for PartitionedConsumer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PartitionedConsumer")
+            .field("channel", &self.channel)
+            .field("listeners", &self.listeners)
+            .field("prefetch", &self.prefetch)
+            .field("owned", &self.owned())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartitionedConsumer {
+    pub(crate) async fn new(conn: &Connection, listeners: Arc<ListenerRegistry>, prefetch: u16) -> Result<Self> {
+        Ok(Self {
+            channel: conn.create_channel().await?,
+            listeners,
+            prefetch,
+            owned: BTreeMap::new(),
+        })
+    }
+
+    /// The shard queues currently consumed.
+    pub fn owned(&self) -> Vec<String> {
+        self.owned.keys().cloned().collect()
+    }
+
+    /// Consume the shards of `assigned` not owned yet and release the owned ones not in it,
+    /// then tell every listener with `BrokerListener::on_partitions_changed`.
+    pub async fn rebalance(&mut self, assigned: &[String]) -> Result<()> {
+        let revoked: Vec<String> = self.owned.keys().filter(|shard| !assigned.contains(shard)).cloned().collect();
+        for shard in &revoked {
+            if let Some(shard) = self.owned.remove(shard) {
+                shard.revoked.store(true, Ordering::Release);
+                self.channel.basic_cancel(shard.tag.as_str(), BasicCancelOptions::default()).await?;
+            }
+        }
+
+        let mut added = vec![];
+        for shard in assigned {
+            if self.owned.contains_key(shard) {
+                continue;
+            }
+            let owned = self.consume(shard).await?;
+            self.owned.insert(shard.clone(), owned);
+            added.push(shard.clone());
+        }
+
+        if !added.is_empty() || !revoked.is_empty() {
+            info!(?added, ?revoked, "Partitions rebalanced");
+            for listener in self.listeners.all() {
+                listener.inner.on_partitions_changed(&added, &revoked).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rebalance on the shards `coordinator` assigns.
+    pub async fn rebalance_with(&mut self, coordinator: &dyn Coordinator) -> Result<()> {
+        let assigned = coordinator.assigned().await?;
+        self.rebalance(&assigned).await
+    }
+
+    /// Rebalance every `interval` with `coordinator`, a failed rebalance is retried on the next one.
+    pub fn spawn_rebalancing(mut self, coordinator: Arc<dyn Coordinator>, interval: Duration) -> JoinHandle<()> {
+        tasks::spawn(TaskKind::Background, "amqp-rebalance", async move {
+            loop {
+                if let Err(err) = self.rebalance_with(coordinator.as_ref()).await {
+                    error!(%err, "Failed to rebalance the partitions");
+                }
+
+                runtime::sleep(interval).await;
+            }
+        })
+    }
+
+    async fn consume(&self, shard: &str) -> Result<Shard> {
+        // the prefetch of the consumers started from now on
+        self.channel.basic_qos(self.prefetch, BasicQosOptions::default()).await?;
+        let consumer = self
+            .channel
+            .basic_consume(&self.listeners.settings().affixes.resolve(shard), "", BasicConsumeOptions::default(), FieldTable::default())
+            .await?;
+        let tag = consumer.tag();
+        let revoked = Arc::new(AtomicBool::new(false));
+
+        let listeners = self.listeners.clone();
+        tasks::spawn(
+            TaskKind::ConsumerLoop,
+            "amqp-partition",
+            Consumer::consume_until(consumer, listeners, Some(revoked.clone())),
+        );
+
+        Ok(Shard { tag, revoked })
+    }
+}
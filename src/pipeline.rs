@@ -0,0 +1,111 @@
+//! ETL stages between exchanges: each delivery of the source exchange is decoded, transformed then published
+//! to the target exchange, and only acked once the broker confirmed the publish (at-least-once).
+
+use crate::context::ConsumeContext;
+use crate::{format, BrokerListener, Rejection};
+use async_trait::async_trait;
+use lapin::message::Delivery;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+
+/// A listener of `source` publishing the transform of each delivery to `target`, see `Pipeline::new`.
+pub struct Pipeline<I, O, F> {
+    source: &'static str,
+    target: String,
+    /// The one of the delivery when `None`.
+    routing_key: Option<String>,
+    max_concurrent_tasks: usize,
+    transform: F,
+    _types: PhantomData<fn(I) -> O>,
+}
+
+impl<I, O, F> std::fmt::Debug for Pipeline<I, O, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("source", &self.source)
+            .field("target", &self.target)
+            .field("routing_key", &self.routing_key)
+            .field("max_concurrent_tasks", &self.max_concurrent_tasks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<I, O, F> Pipeline<I, O, F>
+where
+    I: DeserializeOwned + Send,
+    O: Serialize + Send,
+    F: Fn(I) -> Result<O, Rejection> + Send + Sync,
+{
+    /// Decode the payloads of `source` (JSON or bincode, see `format::decode`), transform them with `transform`
+    /// then publish them to `target` in bincode, with their routing key. Add it to the consumer like any listener.
+    pub fn new(source: &'static str, target: &str, transform: F) -> Self {
+        Self {
+            source,
+            target: target.to_string(),
+            routing_key: None,
+            max_concurrent_tasks: crate::DEFAULT_MAX_CONCURRENT_TASKS.load(std::sync::atomic::Ordering::Relaxed),
+            transform,
+            _types: PhantomData,
+        }
+    }
+
+    /// Publish with this routing key rather than the one of the delivery.
+    pub fn routing_key(mut self, routing_key: &str) -> Self {
+        self.routing_key = Some(routing_key.to_string());
+        self
+    }
+
+    pub fn max_concurrent_tasks(mut self, max: usize) -> Self {
+        self.max_concurrent_tasks = max.max(1);
+        self
+    }
+}
+
+#[async_trait]
+impl<I, O, F> BrokerListener for Pipeline<I, O, F>
+where
+    I: DeserializeOwned + Send,
+    O: Serialize + Send,
+    F: Fn(I) -> Result<O, Rejection> + Send + Sync,
+{
+    fn exchange_name(&self) -> &'static str {
+        self.source
+    }
+
+    fn max_concurrent_tasks(&self) -> usize {
+        self.max_concurrent_tasks
+    }
+
+    async fn consume(&self, _delivery: &Delivery) -> Result<(), Rejection> {
+        unreachable!("a pipeline publishes through the context")
+    }
+
+    async fn consume_with_context(&self, delivery: &Delivery, context: &ConsumeContext) -> Result<(), Rejection> {
+        let input: I = format::decode(delivery).map_err(|err| {
+            error!(%err, source = self.source, "Failed to decode a pipeline input");
+            Rejection::discard().with_reason("undecodable")
+        })?;
+        let output = (self.transform)(input)?;
+        let payload = bincode::serialize(&output).map_err(|err| {
+            error!(%err, target = self.target.as_str(), "Failed to encode a pipeline output");
+            Rejection::discard().with_reason("unencodable")
+        })?;
+
+        let routing_key = self.routing_key.as_deref().unwrap_or(delivery.routing_key.as_str());
+        let publisher = context.publisher();
+        let res = match publisher.enable_confirms().await {
+            Ok(()) => match publisher.publish_raw(&self.target, routing_key, &payload).await {
+                Ok(confirm) => crate::check_confirmed(&self.target, confirm).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+
+        // not confirmed, the delivery is retried rather than lost
+        res.map_err(|err| {
+            warn!(%err, target = self.target.as_str(), "Pipeline output not published");
+            Rejection::requeue().with_reason("not_confirmed")
+        })
+    }
+}
@@ -0,0 +1,172 @@
+//! Reconnection of a `Broker` once its connection died, e.g. the broker restarted, see `Broker::run`.
+//!
+//! The exchanges, queues and bindings declared on the lost connection are declared again, then the publisher
+//! and the consumer channels are created again and the queues consumed again.
+
+use lapin::options::{ExchangeBindOptions, QueueBindOptions};
+use lapin::topology::TopologyDefinition;
+use lapin::Channel;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How a `Broker` reconnects: waiting `initial_backoff_ms`, doubled after each failed attempt up to `max_backoff_ms`,
+/// give or take `jitter` (a fraction of the wait) so the instances don't all reconnect at once.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    /// Give up after this many failed attempts in a row, never when `None`.
+    pub max_retries: Option<u32>,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Between 0 and 1.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: None,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Wait before the `attempt`-th attempt (starting at 1).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt.max(1) - 1);
+        let backoff = self.initial_backoff_ms.saturating_mul(factor).min(self.max_backoff_ms) as f64;
+
+        // uniformly within [-jitter, +jitter]
+        let random = (uuid::Uuid::new_v4().as_u128() >> 75) as f64 / (1u64 << 53) as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0) * (2.0 * random - 1.0);
+
+        Duration::from_millis((backoff * (1.0 + jitter)) as u64)
+    }
+
+    /// Whether to give up after `attempt` failed.
+    pub(crate) fn exhausted(&self, attempt: u32) -> bool {
+        self.max_retries.is_some_and(|max| attempt >= max)
+    }
+}
+
+/// Declare again on `channel` the exchanges, queues and bindings of `topology`.
+/// The exclusive queues are left out, the server names them on each declaration.
+pub(crate) async fn redeclare(channel: &Channel, topology: &TopologyDefinition) -> lapin::Result<()> {
+    for exchange in &topology.exchanges {
+        if let Some(options) = exchange.options {
+            channel
+                .exchange_declare(
+                    exchange.name.as_str(),
+                    exchange.kind.clone().unwrap_or_default(),
+                    options,
+                    exchange.arguments.clone().unwrap_or_default(),
+                )
+                .await?;
+        }
+    }
+    for exchange in &topology.exchanges {
+        for binding in &exchange.bindings {
+            channel
+                .exchange_bind(
+                    exchange.name.as_str(),
+                    binding.source.as_str(),
+                    binding.routing_key.as_str(),
+                    ExchangeBindOptions::default(),
+                    binding.arguments.clone(),
+                )
+                .await?;
+        }
+    }
+
+    for queue in &topology.queues {
+        if let Some(options) = queue.options {
+            channel
+                .queue_declare(queue.name.as_str(), options, queue.arguments.clone().unwrap_or_default())
+                .await?;
+        }
+    }
+    for queue in &topology.queues {
+        for binding in &queue.bindings {
+            channel
+                .queue_bind(
+                    queue.name.as_str(),
+                    binding.source.as_str(),
+                    binding.routing_key.as_str(),
+                    QueueBindOptions::default(),
+                    binding.arguments.clone(),
+                )
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_retries: Some(3),
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let policy = policy(0.0);
+
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(5), Duration::from_millis(1_000));
+    }
+
+    #[test]
+    fn delay_stays_within_the_jitter() {
+        let policy = policy(0.2);
+
+        for attempt in 1..=10 {
+            let backoff = policy.delay(attempt).as_millis() as f64;
+            let expected = (100u64 << (attempt - 1)).min(1_000) as f64;
+            // truncated to the millisecond
+            assert!(backoff + 1.0 >= expected * 0.8 && backoff <= expected * 1.2, "{backoff} for attempt {attempt}");
+        }
+    }
+
+    #[test]
+    fn jitter_beyond_one_is_clamped() {
+        let policy = policy(5.0);
+
+        for _ in 0..100 {
+            assert!(policy.delay(4) <= Duration::from_millis(1_600));
+        }
+    }
+
+    #[test]
+    fn delay_saturates_at_high_attempts() {
+        let policy = ReconnectPolicy {
+            initial_backoff_ms: u64::MAX / 2,
+            max_backoff_ms: u64::MAX,
+            jitter: 0.0,
+            ..ReconnectPolicy::default()
+        };
+
+        // the f64 conversion rounds up to the next power of two, which saturates back to u64::MAX
+        assert_eq!(policy.delay(100), Duration::from_millis(u64::MAX));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(u64::MAX));
+    }
+
+    #[test]
+    fn exhausted_after_max_retries() {
+        assert!(!policy(0.0).exhausted(2));
+        assert!(policy(0.0).exhausted(3));
+        assert!(!ReconnectPolicy::default().exhausted(u32::MAX));
+    }
+}
@@ -0,0 +1,217 @@
+//! Keep payloads and sensitive headers out of the logs and panic messages of this crate.
+
+use lapin::message::Delivery;
+use std::fmt;
+
+/// How the preview of a binary payload is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreviewEncoding {
+    Hex,
+    Base64,
+}
+
+/// Format deliveries without their payload (only its size, or a preview when enabled), and with the value
+/// of the configured headers hidden.
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    /// Compared case-insensitively.
+    header_names: Vec<String>,
+    payload_preview: Option<(usize, PreviewEncoding)>,
+}
+
+impl Redactor {
+    pub fn new(header_names: &[&str]) -> Self {
+        Self {
+            header_names: header_names.iter().map(|name| name.to_lowercase()).collect(),
+            payload_preview: None,
+        }
+    }
+
+    /// Show the first `max_bytes` of the payloads, and log the failed deliveries with it.
+    pub fn with_payload_preview(mut self, max_bytes: usize, encoding: PreviewEncoding) -> Self {
+        self.payload_preview = Some((max_bytes, encoding));
+        self
+    }
+
+    pub fn has_payload_preview(&self) -> bool {
+        self.payload_preview.is_some()
+    }
+
+    pub fn is_redacted(&self, header_name: &str) -> bool {
+        self.header_names.iter().any(|name| name.eq_ignore_ascii_case(header_name))
+    }
+
+    pub fn delivery<'a>(&'a self, delivery: &'a Delivery) -> RedactedDelivery<'a> {
+        RedactedDelivery {
+            redactor: self,
+            delivery,
+        }
+    }
+}
+
+pub struct RedactedDelivery<'a> {
+    redactor: &'a Redactor,
+    delivery: &'a Delivery,
+}
+
+struct Headers<'a>(&'a RedactedDelivery<'a>);
+
+impl fmt::Debug for Headers<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        if let Some(headers) = self.0.delivery.properties.headers() {
+            for (name, value) in headers.inner() {
+                if self.0.redactor.is_redacted(name.as_str()) {
+                    map.entry(&name.as_str(), &"<redacted>");
+                } else {
+                    map.entry(&name.as_str(), value);
+                }
+            }
+        }
+        map.finish()
+    }
+}
+
+impl fmt::Debug for RedactedDelivery<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let delivery = self.delivery;
+        let properties = &delivery.properties;
+
+        f.debug_struct("Delivery")
+            .field("delivery_tag", &delivery.delivery_tag)
+            .field("exchange", &delivery.exchange.as_str())
+            .field("routing_key", &delivery.routing_key.as_str())
+            .field("redelivered", &delivery.redelivered)
+            .field("message_id", &properties.message_id().as_ref().map(|id| id.as_str()))
+            .field("correlation_id", &properties.correlation_id().as_ref().map(|id| id.as_str()))
+            .field("content_type", &properties.content_type().as_ref().map(|t| t.as_str()))
+            .field("content_encoding", &properties.content_encoding().as_ref().map(|e| e.as_str()))
+            .field("timestamp", properties.timestamp())
+            .field("headers", &Headers(self))
+            .field("payload", &Payload(self))
+            .finish()
+    }
+}
+
+struct Payload<'a>(&'a RedactedDelivery<'a>);
+
+impl fmt::Debug for Payload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data = &self.0.delivery.data;
+        write!(f, "<{} bytes>", data.len())?;
+
+        let Some((max_bytes, encoding)) = self.0.redactor.payload_preview else {
+            return Ok(());
+        };
+        let preview = &data[..data.len().min(max_bytes)];
+        match encoding {
+            PreviewEncoding::Hex => {
+                f.write_str(" hex:")?;
+                for byte in preview {
+                    write!(f, "{byte:02x}")?;
+                }
+            }
+            PreviewEncoding::Base64 => write!(f, " base64:{}", base64(preview))?,
+        }
+        if preview.len() < data.len() {
+            f.write_str("…")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers;
+    use lapin::acker::Acker;
+    use lapin::BasicProperties;
+
+    fn delivery(data: &[u8]) -> Delivery {
+        let properties = headers::insert(BasicProperties::default(), "Authorization", headers::long_string("Bearer secret"));
+        Delivery {
+            delivery_tag: 1,
+            exchange: "orders".into(),
+            routing_key: "created".into(),
+            redelivered: false,
+            properties: headers::insert(properties, "x-tenant", headers::long_string("acme")),
+            data: data.to_vec(),
+            acker: Acker::default(),
+        }
+    }
+
+    #[test]
+    fn headers_are_redacted_case_insensitively() {
+        let redactor = Redactor::new(&["AUTHORIZATION"]);
+        assert!(redactor.is_redacted("authorization"));
+        assert!(!redactor.is_redacted("x-tenant"));
+
+        let formatted = format!("{:?}", redactor.delivery(&delivery(b"")));
+        let tenant = format!("{:?}", headers::long_string("acme"));
+        let expected = format!(r#"headers: {{"Authorization": "<redacted>", "x-tenant": {tenant}}}"#);
+        assert!(formatted.contains(&expected), "{formatted}");
+    }
+
+    #[test]
+    fn payloads_are_hidden() {
+        let redactor = Redactor::new(&[]);
+        assert!(!redactor.has_payload_preview());
+
+        let formatted = format!("{:?}", redactor.delivery(&delivery(b"card=4111")));
+        assert!(formatted.contains("payload: <9 bytes>"), "{formatted}");
+        assert!(!formatted.contains("4111"), "{formatted}");
+        assert!(formatted.contains(r#"exchange: "orders""#), "{formatted}");
+    }
+
+    #[test]
+    fn hex_preview() {
+        let redactor = Redactor::new(&[]).with_payload_preview(2, PreviewEncoding::Hex);
+        assert!(redactor.has_payload_preview());
+
+        let formatted = format!("{:?}", redactor.delivery(&delivery(&[0x00, 0xab, 0xff])));
+        assert!(formatted.contains("payload: <3 bytes> hex:00ab…"), "{formatted}");
+
+        let formatted = format!("{:?}", redactor.delivery(&delivery(&[0x0f, 0xf0])));
+        assert!(formatted.contains("payload: <2 bytes> hex:0ff0 }"), "{formatted}");
+    }
+
+    #[test]
+    fn base64_preview() {
+        let redactor = Redactor::new(&[]).with_payload_preview(4, PreviewEncoding::Base64);
+        let formatted = format!("{:?}", redactor.delivery(&delivery(b"hello")));
+        assert!(formatted.contains("payload: <5 bytes> base64:aGVsbA==…"), "{formatted}");
+
+        let formatted = format!("{:?}", redactor.delivery(&delivery(b"")));
+        assert!(formatted.contains("payload: <0 bytes> base64: }"), "{formatted}");
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+}
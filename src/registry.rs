@@ -0,0 +1,115 @@
+//! The listeners of a consumer, shared between the `Consumer` and its spawned loop.
+
+use crate::stats::ConsumerStats;
+use crate::{ConsumerSettings, Listener};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Listeners by exchange name, in registration order.
+///
+/// The loop looks the listener of each delivery up here, so listeners added or removed once spawned
+/// are taken into account from the next delivery.
+#[derive(Default)]
+pub struct ListenerRegistry {
+    listeners: RwLock<Vec<Arc<Listener>>>,
+    /// Settings of the consumer, given to every listener and set when spawned.
+    settings: RwLock<Arc<ConsumerSettings>>,
+    /// Set once the consumer was cancelled, its stream ending is then expected.
+    cancelled: AtomicBool,
+}
+
+impl ListenerRegistry {
+    /// The listener of `exchange`, as named on the broker (e.g. the exchange of a delivery)
+    /// with the topology affixes of the consumer.
+    pub fn get(&self, exchange: &str) -> Option<Arc<Listener>> {
+        self.get_logical(&self.settings().affixes.logical(exchange))
+    }
+
+    /// The listener of `exchange`, as named by the application.
+    pub(crate) fn get_logical(&self, exchange: &str) -> Option<Arc<Listener>> {
+        self.listeners
+            .read()
+            .unwrap()
+            .iter()
+            .find(|listener| listener.inner.exchange_name() == exchange)
+            .cloned()
+    }
+
+    /// Exchanges of the registered listeners.
+    pub fn exchanges(&self) -> Vec<&'static str> {
+        self.listeners.read().unwrap().iter().map(|listener| listener.inner.exchange_name()).collect()
+    }
+
+    /// Statistics of every listener, by exchange name.
+    pub fn stats(&self) -> Vec<(&'static str, ConsumerStats)> {
+        self.listeners
+            .read()
+            .unwrap()
+            .iter()
+            .map(|listener| (listener.inner.exchange_name(), listener.stats()))
+            .collect()
+    }
+
+    /// Deliveries received by the listeners and not acked or rejected yet.
+    pub fn in_flight(&self) -> u64 {
+        self.listeners.read().unwrap().iter().map(|listener| listener.stats().in_flight).sum()
+    }
+
+    /// When any listener last received or finished a delivery.
+    pub fn last_activity_at(&self) -> Option<DateTime<Utc>> {
+        self.listeners
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|listener| listener.stats().last_activity_at())
+            .max()
+    }
+
+    pub(crate) fn all(&self) -> Vec<Arc<Listener>> {
+        self.listeners.read().unwrap().clone()
+    }
+
+    pub(crate) fn insert(&self, mut listener: Listener) {
+        listener.settings = self.settings();
+        self.listeners.write().unwrap().push(Arc::new(listener));
+    }
+
+    /// The listener of `exchange`, named like in `get` or as by the application.
+    pub(crate) fn remove(&self, exchange: &str) -> Option<Arc<Listener>> {
+        let exchange = self.settings().affixes.logical(exchange).into_owned();
+        let mut listeners = self.listeners.write().unwrap();
+        let index = listeners.iter().position(|listener| listener.inner.exchange_name() == exchange)?;
+
+        Some(listeners.remove(index))
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_cancelled(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn settings(&self) -> Arc<ConsumerSettings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Replace the settings of the registered listeners and of the ones added from now on.
+    pub(crate) fn set_settings(&self, settings: Arc<ConsumerSettings>) {
+        for listener in self.listeners.write().unwrap().iter_mut() {
+            let mut updated = Listener::clone(listener);
+            updated.settings = settings.clone();
+            *listener = Arc::new(updated);
+        }
+        *self.settings.write().unwrap() = settings;
+    }
+}
+
+impl fmt::Debug for ListenerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.exchanges()).finish()
+    }
+}
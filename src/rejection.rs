@@ -0,0 +1,79 @@
+//! Why a listener failed to consume a delivery, and whether it should be requeued.
+
+use lapin::message::Delivery;
+use lapin::options::{BasicNackOptions, BasicRejectOptions};
+use std::fmt;
+
+/// Set on the deliveries dead-lettered by this crate (see `Consumer::set_dead_letter_exchange`)
+/// and on the ones republished for retry.
+pub const REASON_HEADER: &str = "x-rejection-reason";
+
+/// Returned by the listeners, `false.into()` and `true.into()` keep the meaning of the former `Err(bool)`.
+///
+/// The reason ends up in the logs, the `amqp_consumer_rejections_total` metric (keep it low-cardinality,
+/// e.g. `invalid_payload` rather than the full error, see `metrics::set_max_rejection_reasons`)
+/// and the `x-rejection-reason` header.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rejection {
+    pub requeue: bool,
+    pub reason: Option<String>,
+}
+
+impl Rejection {
+    /// Reject with requeue (or retry when the listener has a retry policy).
+    pub fn requeue() -> Self {
+        Self {
+            requeue: true,
+            reason: None,
+        }
+    }
+
+    /// Reject without requeue, dead-lettered when configured.
+    pub fn discard() -> Self {
+        Self {
+            requeue: false,
+            reason: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+}
+
+impl From<bool> for Rejection {
+    fn from(requeue: bool) -> Self {
+        Self { requeue, reason: None }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = if self.requeue { "requeue" } else { "discard" };
+        match &self.reason {
+            Some(reason) => write!(f, "{action}: {reason}"),
+            None => f.write_str(action),
+        }
+    }
+}
+
+/// How a listener's failed deliveries are given back to the broker, some policies and plugins
+/// tell `basic.reject` and `basic.nack` apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RejectMethod {
+    #[default]
+    Reject,
+    /// `basic.nack` of this delivery only: settling the ones before it too would take the deliveries
+    /// other tasks are still handling on the channel.
+    Nack,
+}
+
+impl RejectMethod {
+    pub(crate) async fn apply(self, delivery: &Delivery, requeue: bool) -> lapin::Result<()> {
+        match self {
+            RejectMethod::Reject => delivery.reject(BasicRejectOptions { requeue }).await,
+            RejectMethod::Nack => delivery.nack(BasicNackOptions { multiple: false, requeue }).await,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Replay of stream queues from an offset or a timestamp, for backfills and debugging.
+//!
+//! A temporary consumer feeds the deliveries to a listener. Nothing is requeued, retried or archived:
+//! on a stream an ack only lets the next deliveries come, whatever the listener returned.
+
+use crate::context::ConsumeContext;
+use crate::naming::{self, TopologyAffixes};
+use crate::{trace, BrokerListener, Publisher, Result};
+use chrono::{DateTime, Utc};
+use futures_lite::StreamExt;
+use lapin::options::{BasicAckOptions, BasicConsumeOptions, BasicQosOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::Connection;
+use std::time::Duration;
+
+/// Where to start reading the stream, the `x-stream-offset` argument.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayStart {
+    First,
+    Last,
+    Offset(u64),
+    /// Second precision, as stored by the broker.
+    Timestamp(DateTime<Utc>),
+}
+
+impl ReplayStart {
+    fn argument(self) -> AMQPValue {
+        match self {
+            ReplayStart::First => AMQPValue::LongString("first".into()),
+            ReplayStart::Last => AMQPValue::LongString("last".into()),
+            ReplayStart::Offset(offset) => AMQPValue::LongLongInt(offset as i64),
+            ReplayStart::Timestamp(at) => AMQPValue::Timestamp(at.timestamp() as u64),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayOptions {
+    pub start: ReplayStart,
+    /// Stop after this many deliveries.
+    pub max_deliveries: Option<u64>,
+    /// Stop once no delivery came for this long, i.e. the end of the stream was reached.
+    pub idle_timeout: Duration,
+    pub prefetch: u16,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            start: ReplayStart::First,
+            max_deliveries: None,
+            idle_timeout: Duration::from_secs(5),
+            prefetch: 100,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplaySummary {
+    pub replayed: u64,
+    /// Deliveries the listener returned an error for.
+    pub failed: u64,
+}
+
+/// Feed the deliveries of the stream `queue` to `listener`, on a temporary channel of `conn`.
+/// The listener publishes its follow-up messages on that channel.
+pub async fn replay(
+    conn: &Connection,
+    queue: &str,
+    listener: &dyn BrokerListener,
+    options: ReplayOptions,
+) -> Result<ReplaySummary> {
+    replay_with(conn, &naming::topology_affixes(), None, queue, listener, options).await
+}
+
+/// `replay` of `queue` named with `affixes`, the listener publishing with `publisher` when set.
+pub(crate) async fn replay_with(
+    conn: &Connection,
+    affixes: &TopologyAffixes,
+    publisher: Option<&Publisher>,
+    queue: &str,
+    listener: &dyn BrokerListener,
+    options: ReplayOptions,
+) -> Result<ReplaySummary> {
+    let channel = conn.create_channel().await?;
+    // streams can only be consumed with a prefetch
+    channel.basic_qos(options.prefetch, BasicQosOptions::default()).await?;
+
+    let mut arguments = FieldTable::default();
+    arguments.insert("x-stream-offset".into(), options.start.argument());
+    let mut consumer = channel
+        .basic_consume(&affixes.resolve(queue), "", BasicConsumeOptions::default(), arguments)
+        .await?;
+
+    let mut summary = ReplaySummary::default();
+    while options.max_deliveries.is_none_or(|max| summary.replayed < max) {
+        let delivery = match crate::runtime::timeout(options.idle_timeout, consumer.next()).await {
+            Some(Some(delivery)) => delivery?,
+            // caught up, or the consumer was cancelled
+            Some(None) | None => break,
+        };
+
+        summary.replayed += 1;
+        // through the context, like any delivery, as some listeners only implement `consume_with_context`
+        let publisher = publisher
+            .cloned()
+            .unwrap_or_else(|| Publisher::with_channel(channel.clone()))
+            .with_trace_id(trace::trace_id(&delivery));
+        let context = ConsumeContext::new(&delivery, publisher, channel.clone());
+        if listener.consume_with_context(&delivery, &context).await.is_err() {
+            summary.failed += 1;
+            warn!(queue, delivery_tag = delivery.delivery_tag, "Replayed delivery failed");
+        }
+
+        delivery.ack(BasicAckOptions::default()).await?;
+    }
+
+    channel.close(200, "Replay done").await?;
+    info!(queue, replayed = summary.replayed, failed = summary.failed, "Replay done");
+
+    Ok(summary)
+}
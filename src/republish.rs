@@ -0,0 +1,76 @@
+//! Republishing a delivery, its payload and properties preserved unless overridden: for the retries,
+//! the dead-lettering, the replays and the repair tools. See `Publisher::publish_from_delivery`.
+
+use crate::headers;
+use crate::naming::TopologyAffixes;
+use lapin::message::Delivery;
+use lapin::types::AMQPValue;
+use lapin::BasicProperties;
+
+/// What changes from the delivery republished, nothing by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides {
+    pub exchange: Option<String>,
+    pub routing_key: Option<String>,
+    /// Set, or replaced when already there.
+    pub headers: Vec<(String, AMQPValue)>,
+    pub removed_headers: Vec<String>,
+    /// Per-message TTL in milliseconds.
+    pub expiration: Option<String>,
+}
+
+impl Overrides {
+    pub fn with_exchange(mut self, exchange: impl Into<String>) -> Self {
+        self.exchange = Some(exchange.into());
+        self
+    }
+
+    pub fn with_routing_key(mut self, routing_key: impl Into<String>) -> Self {
+        self.routing_key = Some(routing_key.into());
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: AMQPValue) -> Self {
+        self.headers.push((name.into(), value));
+        self
+    }
+
+    pub fn with_string_header(self, name: impl Into<String>, value: &str) -> Self {
+        self.with_header(name, headers::long_string(value))
+    }
+
+    pub fn without_header(mut self, name: impl Into<String>) -> Self {
+        self.removed_headers.push(name.into());
+        self
+    }
+
+    pub fn with_expiration(mut self, expiration: std::time::Duration) -> Self {
+        self.expiration = Some(expiration.as_millis().to_string());
+        self
+    }
+
+    /// Exchange (without `affixes`), routing key and properties to republish `delivery` with.
+    pub(crate) fn apply(&self, delivery: &Delivery, affixes: &TopologyAffixes) -> (String, String, BasicProperties) {
+        let exchange = self.exchange.clone().unwrap_or_else(|| affixes.logical(delivery.exchange.as_str()).into_owned());
+        let routing_key = self.routing_key.clone().unwrap_or_else(|| delivery.routing_key.to_string());
+
+        let mut properties = delivery.properties.clone();
+        if !self.removed_headers.is_empty() {
+            if let Some(headers) = properties.headers().clone() {
+                let mut headers = headers.inner().clone();
+                for name in &self.removed_headers {
+                    headers.remove(name.as_str());
+                }
+                properties = properties.with_headers(headers.into());
+            }
+        }
+        for (name, value) in &self.headers {
+            properties = headers::insert(properties, name, value.clone());
+        }
+        if let Some(expiration) = &self.expiration {
+            properties = properties.with_expiration(expiration.as_str().into());
+        }
+
+        (exchange, routing_key, properties)
+    }
+}
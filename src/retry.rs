@@ -0,0 +1,351 @@
+//! Retry of failed deliveries through a dedicated retry exchange.
+//!
+//! A retryable failure republishes the message to the retry exchange with the attempt count
+//! in `x-attempt` and the original routing key in `x-original-routing-key`, then acks the original delivery.
+//! Once `max_attempts` is reached, the delivery is rejected without requeue (dead-lettered when configured).
+//!
+//! `declare_backoff_ladder` scaffolds delayed retries on vanilla RabbitMQ: one fanout exchange and holding queue
+//! per delay, the queue has a message TTL and dead-letters back to the consumed queue through the default exchange,
+//! so only the listener which failed gets the message again. Its routing key stays in `x-original-routing-key`.
+
+use crate::headers::{self, as_string, as_u64};
+use crate::naming::{NamingStrategy, TopologyAffixes};
+use crate::republish::Overrides;
+use crate::Result;
+use chrono::{DateTime, TimeZone, Utc};
+use lapin::message::Delivery;
+use lapin::options::{BasicPublishOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{Channel, ExchangeKind};
+use std::time::Duration;
+
+pub const ATTEMPT_HEADER: &str = "x-attempt";
+pub const ORIGINAL_ROUTING_KEY_HEADER: &str = "x-original-routing-key";
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Exchange the failed deliveries are republished to, it must route them back to the consumed queue
+    /// (usually after a delay).
+    pub exchange: String,
+    /// Attempts, including the first one, before giving up.
+    pub max_attempts: u32,
+    /// Exchange of each backoff step, the last one is reused once the ladder is climbed.
+    /// Takes precedence over `exchange` when not empty.
+    pub ladder: Vec<String>,
+}
+
+impl RetryPolicy {
+    pub fn new(exchange: &str, max_attempts: u32) -> Self {
+        Self {
+            exchange: exchange.to_string(),
+            max_attempts,
+            ladder: vec![],
+        }
+    }
+
+    /// Exchange to republish the `attempt`-th failure (starting at 1) to.
+    pub fn exchange_for(&self, attempt: u32) -> &str {
+        let step = (attempt.max(1) - 1) as usize;
+
+        self.ladder
+            .get(step)
+            .or(self.ladder.last())
+            .unwrap_or(&self.exchange)
+    }
+}
+
+/// What a listener does with a delivery flagged `redelivered`, i.e. already handed to a consumer
+/// which didn't settle it (it crashed, timed out or requeued it), maybe on another node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RedeliveryPolicy {
+    /// Handle it like any other delivery.
+    #[default]
+    Process,
+    /// Reject it without requeue (dead-lettered when configured) without handling it again.
+    DeadLetter,
+    /// Handle it with this timeout, rejecting it without requeue once exceeded.
+    Timeout(Duration),
+}
+
+fn format_delay(delay: Duration) -> String {
+    let ms = delay.as_millis();
+    match ms {
+        ms if ms > 0 && ms % 3_600_000 == 0 => format!("{}h", ms / 3_600_000),
+        ms if ms > 0 && ms % 60_000 == 0 => format!("{}m", ms / 60_000),
+        ms if ms > 0 && ms % 1_000 == 0 => format!("{}s", ms / 1_000),
+        ms => format!("{}ms", ms),
+    }
+}
+
+/// Declare a holding queue per delay of `schedule` for the consumers of `exchange` on `queue`, named with `affixes`,
+/// and return the policy using them.
+///
+/// Each step is a fanout exchange bound to a queue of the same name (after the naming convention,
+/// e.g. `orders.retry.5s`), whose messages expire after the delay and are dead-lettered to `queue`
+/// through the default exchange, rather than to every queue bound to `exchange`.
+pub async fn declare_backoff_ladder(
+    channel: &Channel,
+    naming: &NamingStrategy,
+    affixes: &TopologyAffixes,
+    exchange: &str,
+    queue: &str,
+    schedule: &[Duration],
+    max_attempts: u32,
+) -> Result<RetryPolicy> {
+    let mut ladder = vec![];
+
+    for delay in schedule {
+        let name = naming.queue_name(exchange, &format!("retry.{}", format_delay(*delay)));
+
+        channel
+            .exchange_declare(
+                &affixes.resolve(&name),
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        let mut arguments = FieldTable::default();
+        arguments.insert("x-message-ttl".into(), AMQPValue::LongLongInt(delay.as_millis() as i64));
+        arguments.insert("x-dead-letter-exchange".into(), AMQPValue::LongString("".into()));
+        arguments.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString(affixes.resolve(queue).as_ref().into()));
+        channel
+            .queue_declare(
+                &affixes.resolve(&name),
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                arguments,
+            )
+            .await?;
+        channel
+            .queue_bind(&affixes.resolve(&name), &affixes.resolve(&name), "", QueueBindOptions::default(), FieldTable::default())
+            .await?;
+
+        ladder.push(name);
+    }
+
+    Ok(RetryPolicy {
+        exchange: ladder.first().cloned().unwrap_or_default(),
+        max_attempts,
+        ladder,
+    })
+}
+
+/// Number of failed attempts already recorded in the `x-attempt` header, 0 on the first delivery.
+pub fn attempt(delivery: &Delivery) -> u32 {
+    headers::get(delivery, ATTEMPT_HEADER)
+        .and_then(as_u64)
+        .map(|attempt| attempt as u32)
+        .unwrap_or(0)
+}
+
+/// The routing key the message was first published with.
+pub fn original_routing_key(delivery: &Delivery) -> String {
+    headers::get(delivery, ORIGINAL_ROUTING_KEY_HEADER)
+        .and_then(as_string)
+        .unwrap_or_else(|| delivery.routing_key.to_string())
+}
+
+/// One entry of the RabbitMQ `x-death` header, added each time the message is dead-lettered from a queue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeathRecord {
+    pub queue: String,
+    pub exchange: String,
+    /// `rejected`, `expired`, `maxlen` or `delivery_limit`
+    pub reason: String,
+    pub count: u64,
+    pub time: Option<DateTime<Utc>>,
+    pub routing_keys: Vec<String>,
+}
+
+impl DeathRecord {
+    fn parse(table: &FieldTable) -> Self {
+        let get = |name: &str| table.inner().get(name);
+        let string = |name: &str| get(name).and_then(as_string).unwrap_or_default();
+
+        Self {
+            queue: string("queue"),
+            exchange: string("exchange"),
+            reason: string("reason"),
+            count: get("count").and_then(as_u64).unwrap_or(0),
+            time: get("time")
+                .and_then(as_u64)
+                .and_then(|seconds| Utc.timestamp_opt(seconds as i64, 0).single()),
+            routing_keys: match get("routing-keys") {
+                Some(AMQPValue::FieldArray(keys)) => keys.as_slice().iter().filter_map(as_string).collect(),
+                _ => vec![],
+            },
+        }
+    }
+}
+
+/// What is known about the previous failures of a delivery, out of the `x-death` header
+/// and the `x-attempt` header of this crate.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetryInfo {
+    /// Failed attempts so far, 0 on the first delivery.
+    pub attempts: u32,
+    /// Oldest dead-lettering time recorded by the broker.
+    pub first_failed_at: Option<DateTime<Utc>>,
+    /// Reasons of each `x-death` entry, most recent first.
+    pub reasons: Vec<String>,
+    pub deaths: Vec<DeathRecord>,
+}
+
+impl RetryInfo {
+    pub fn from_delivery(delivery: &Delivery) -> Self {
+        let deaths: Vec<DeathRecord> = match headers::get(delivery, "x-death") {
+            Some(AMQPValue::FieldArray(entries)) => entries
+                .as_slice()
+                .iter()
+                .filter_map(|entry| match entry {
+                    AMQPValue::FieldTable(table) => Some(DeathRecord::parse(table)),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        // a message going through TTL retry queues is counted by both
+        let dead_lettered = deaths
+            .iter()
+            .filter(|death| death.reason == "rejected")
+            .map(|death| death.count)
+            .sum::<u64>() as u32;
+
+        Self {
+            attempts: attempt(delivery).max(dead_lettered),
+            first_failed_at: deaths.iter().filter_map(|death| death.time).min(),
+            reasons: deaths.iter().map(|death| death.reason.clone()).collect(),
+            deaths,
+        }
+    }
+}
+
+/// Republish `delivery` to the retry exchange with its attempt count incremented, and the reason of the failure,
+/// on `channel` in confirm mode: fails unless the broker acked the copy.
+pub(crate) async fn republish(
+    channel: &Channel,
+    affixes: &TopologyAffixes,
+    policy: &RetryPolicy,
+    delivery: &Delivery,
+    attempt: u32,
+    reason: Option<&str>,
+) -> Result<()> {
+    let routing_key = original_routing_key(delivery);
+
+    let mut overrides = Overrides::default()
+        .with_exchange(policy.exchange_for(attempt))
+        .with_routing_key(routing_key.as_str())
+        .with_header(ATTEMPT_HEADER, AMQPValue::LongUInt(attempt))
+        .with_string_header(ORIGINAL_ROUTING_KEY_HEADER, &routing_key);
+    if let Some(reason) = reason {
+        overrides = overrides.with_string_header(crate::rejection::REASON_HEADER, reason);
+    }
+    let (exchange, routing_key, properties) = overrides.apply(delivery, affixes);
+
+    let confirmation = channel
+        .basic_publish(&affixes.resolve(&exchange), &routing_key, BasicPublishOptions::default(), &delivery.data, properties)
+        .await?
+        .await?;
+
+    crate::confirm::confirmed(&exchange, confirmation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::acker::Acker;
+    use lapin::types::FieldArray;
+    use lapin::BasicProperties;
+
+    fn delivery(properties: BasicProperties) -> Delivery {
+        Delivery {
+            delivery_tag: 1,
+            exchange: "orders".into(),
+            routing_key: "created".into(),
+            redelivered: false,
+            properties,
+            data: vec![],
+            acker: Acker::default(),
+        }
+    }
+
+    fn death(queue: &str, reason: &str, count: i64, time: u64) -> AMQPValue {
+        let mut table = FieldTable::default();
+        table.insert("queue".into(), headers::long_string(queue));
+        table.insert("exchange".into(), headers::long_string("orders"));
+        table.insert("reason".into(), headers::long_string(reason));
+        table.insert("count".into(), AMQPValue::LongLongInt(count));
+        table.insert("time".into(), AMQPValue::Timestamp(time));
+        table.insert("routing-keys".into(), AMQPValue::FieldArray(FieldArray::from(vec![headers::long_string("created")])));
+        AMQPValue::FieldTable(table)
+    }
+
+    fn with_deaths(deaths: Vec<AMQPValue>) -> BasicProperties {
+        headers::insert(BasicProperties::default(), "x-death", AMQPValue::FieldArray(FieldArray::from(deaths)))
+    }
+
+    #[test]
+    fn without_headers_nothing_failed_yet() {
+        let info = RetryInfo::from_delivery(&delivery(BasicProperties::default()));
+
+        assert_eq!(info, RetryInfo::default());
+        assert_eq!(original_routing_key(&delivery(BasicProperties::default())), "created");
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        let properties = headers::insert(BasicProperties::default(), "x-death", headers::long_string("rejected"));
+        let properties = headers::insert(properties, ATTEMPT_HEADER, headers::long_string("3"));
+        assert_eq!(RetryInfo::from_delivery(&delivery(properties)), RetryInfo::default());
+
+        // entries which aren't tables are skipped, the missing fields of the others left empty
+        let properties = with_deaths(vec![headers::long_string("rejected"), AMQPValue::FieldTable(FieldTable::default())]);
+        let info = RetryInfo::from_delivery(&delivery(properties));
+        assert_eq!(info.attempts, 0);
+        assert_eq!(info.first_failed_at, None);
+        assert_eq!(info.reasons, vec![String::new()]);
+        assert_eq!(
+            info.deaths,
+            vec![DeathRecord {
+                queue: String::new(),
+                exchange: String::new(),
+                reason: String::new(),
+                count: 0,
+                time: None,
+                routing_keys: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn every_death_entry_is_read() {
+        let properties = with_deaths(vec![
+            death("orders.retry.5s", "expired", 2, 1_700_000_100),
+            death("orders", "rejected", 3, 1_700_000_000),
+        ]);
+        let info = RetryInfo::from_delivery(&delivery(properties));
+
+        // only the rejections count, the expirations out of the retry queues are the same failures
+        assert_eq!(info.attempts, 3);
+        assert_eq!(info.first_failed_at, Utc.timestamp_opt(1_700_000_000, 0).single());
+        assert_eq!(info.reasons, vec!["expired", "rejected"]);
+        assert_eq!(info.deaths[0].queue, "orders.retry.5s");
+        assert_eq!(info.deaths[1].routing_keys, vec!["created"]);
+    }
+
+    #[test]
+    fn attempt_header_wins_when_higher() {
+        let properties = with_deaths(vec![death("orders", "rejected", 1, 1_700_000_000)]);
+        let properties = headers::insert(properties, ATTEMPT_HEADER, AMQPValue::LongUInt(4));
+
+        assert_eq!(RetryInfo::from_delivery(&delivery(properties)).attempts, 4);
+    }
+}
@@ -0,0 +1,234 @@
+//! Request-reply over AMQP: requests carry `reply_to` and `correlation_id`, replies are published
+//! to the default exchange with `reply_to` as routing key.
+
+use crate::context::ConsumeContext;
+use crate::tasks::{self, TaskKind};
+use crate::{runtime, BrokerListener, Error, ExclusiveQueue, Publisher, Rejection, Result};
+use async_trait::async_trait;
+use futures_lite::StreamExt;
+use lapin::message::Delivery;
+use lapin::options::BasicConsumeOptions;
+use lapin::{BasicProperties, Channel};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+type Pending = Arc<Mutex<HashMap<String, oneshot::Sender<Delivery>>>>;
+
+/// Why a request got no response, sent back to the caller.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+pub enum RpcError<E> {
+    #[error("The request couldn't be decoded: {0}")]
+    BadRequest(String),
+    #[error("The handler didn't respond in time")]
+    Timeout,
+    #[error("The handler failed")]
+    Handler(E),
+}
+
+/// What is published to `reply_to`, bincode serialized.
+pub type RpcReply<Resp, E> = std::result::Result<Resp, RpcError<E>>;
+
+/// Listener decoding `Req`, running the handler within a deadline and publishing its `RpcReply` to `reply_to`.
+/// See `Consumer::respond_with`.
+pub struct Responder<Req, Resp, E, F> {
+    exchange: &'static str,
+    timeout: Duration,
+    handler: F,
+    _types: PhantomData<fn(Req) -> (Resp, E)>,
+}
+
+impl<Req, Resp, E, F> std::fmt::Debug for Responder<Req, Resp, E, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Responder")
+            .field("exchange", &self.exchange)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Req, Resp, E, F> Responder<Req, Resp, E, F> {
+    pub fn new(exchange: &'static str, timeout: Duration, handler: F) -> Self {
+        Self {
+            exchange,
+            timeout,
+            handler,
+            _types: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<Req, Resp, E, F, Fut> BrokerListener for Responder<Req, Resp, E, F>
+where
+    Req: DeserializeOwned + Send + 'static,
+    Resp: Serialize + Send + 'static,
+    E: Serialize + Send + 'static,
+    F: Fn(Req) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::result::Result<Resp, E>> + Send,
+{
+    fn exchange_name(&self) -> &'static str {
+        self.exchange
+    }
+
+    async fn consume(&self, _delivery: &Delivery) -> std::result::Result<(), Rejection> {
+        unreachable!("requests are consumed with a context")
+    }
+
+    async fn consume_with_context(
+        &self,
+        delivery: &Delivery,
+        context: &ConsumeContext,
+    ) -> std::result::Result<(), Rejection> {
+        let Some(reply_to) = delivery.properties.reply_to().clone() else {
+            warn!(exchange_name = self.exchange, "Request without `reply_to`, nobody to respond to");
+            return Err(Rejection::discard().with_reason("missing_reply_to"));
+        };
+
+        let reply: RpcReply<Resp, E> = match bincode::deserialize::<Req>(&delivery.data) {
+            Ok(request) => match runtime::timeout(self.timeout, (self.handler)(request)).await {
+                Some(Ok(response)) => Ok(response),
+                Some(Err(err)) => Err(RpcError::Handler(err)),
+                None => Err(RpcError::Timeout),
+            },
+            Err(err) => Err(RpcError::BadRequest(err.to_string())),
+        };
+
+        let payload = match bincode::serialize(&reply) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!(%err, exchange_name = self.exchange, "Failed to serialize a reply");
+                return Err(Rejection::discard().with_reason("unserializable_reply"));
+            }
+        };
+
+        let mut properties = BasicProperties::default();
+        if let Some(correlation_id) = delivery.properties.correlation_id().clone() {
+            properties = properties.with_correlation_id(correlation_id);
+        }
+
+        // the default exchange routes to the queue named after the routing key
+        if let Err(err) = context.publisher().publish_with("", reply_to.as_str(), &payload, properties).await {
+            error!(%err, exchange_name = self.exchange, %reply_to, "Failed to publish a reply");
+            return Err(Rejection::requeue().with_reason("reply_not_published"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Publish requests and wait for their reply on an exclusive queue.
+///
+/// Pending requests are tracked by correlation id and forgotten as soon as their call completes, times out
+/// or is dropped by the caller, so unanswered requests never pile up. Late replies are discarded.
+#[derive(Clone)]
+pub struct RpcClient {
+    publisher: Publisher,
+    reply_queue: Arc<ExclusiveQueue>,
+    pending: Pending,
+}
+
+impl std::fmt::Debug for RpcClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcClient")
+            .field("publisher", &self.publisher)
+            .field("reply_queue", &self.reply_queue)
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+impl RpcClient {
+    /// Declare the reply queue on `channel` and consume it in the background.
+    pub async fn new(publisher: Publisher, channel: &Channel) -> Result<Self> {
+        let reply_queue = ExclusiveQueue::declare(channel).await?;
+        let mut replies = reply_queue
+            .consume(BasicConsumeOptions {
+                no_ack: true,
+                ..BasicConsumeOptions::default()
+            })
+            .await?;
+
+        let pending = Pending::default();
+        let dispatched = pending.clone();
+        tasks::spawn(TaskKind::Background, "amqp-rpc-replies", async move {
+            while let Some(Ok(delivery)) = replies.next().await {
+                let correlation_id = delivery.properties.correlation_id().as_ref().map(|id| id.to_string());
+                let sender = correlation_id.and_then(|id| dispatched.lock().unwrap().remove(&id));
+
+                match sender {
+                    Some(sender) => {
+                        let _ = sender.send(delivery);
+                    }
+                    None => debug!("Discarded a reply to an abandoned or unknown request"),
+                }
+            }
+
+            // fail the calls still waiting with `Error::RpcClosed`
+            dispatched.lock().unwrap().clear();
+        });
+
+        Ok(Self {
+            publisher,
+            reply_queue: Arc::new(reply_queue),
+            pending,
+        })
+    }
+
+    /// Requests still waiting for their reply.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Publish `request` then wait up to `timeout` for the reply of a `Responder`.
+    pub async fn call<Req, Resp, E>(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        request: &Req,
+        timeout: Duration,
+    ) -> Result<RpcReply<Resp, E>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: DeserializeOwned,
+    {
+        let correlation_id = uuid::Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(correlation_id.clone(), sender);
+        // forget the request on any way out, including the caller dropping this future
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            correlation_id: &correlation_id,
+        };
+
+        let properties = BasicProperties::default()
+            .with_correlation_id(correlation_id.as_str().into())
+            .with_reply_to(self.reply_queue.name().into());
+        let payload = bincode::serialize(request)?;
+        self.publisher.publish_with(exchange, routing_key, &payload, properties).await?;
+
+        let reply = runtime::timeout(timeout, receiver)
+            .await
+            .ok_or(Error::RpcTimeout(timeout))?
+            .map_err(|_| Error::RpcClosed)?;
+
+        Ok(bincode::deserialize(&reply.data)?)
+    }
+}
+
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    correlation_id: &'a str,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(self.correlation_id);
+    }
+}
@@ -0,0 +1,123 @@
+//! The async runtime used to drive the connections, spawn the tasks of this crate and sleep.
+//!
+//! Chosen with the `runtime-tokio` (default) or `runtime-async-std` feature, tokio wins when both are enabled.
+//! async-std also covers smol applications, both run on `async-global-executor` and `async-io`.
+
+use lapin::ConnectionProperties;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(not(any(feature = "runtime-tokio", feature = "runtime-async-std")))]
+compile_error!("one of the `runtime-tokio` or `runtime-async-std` features must be enabled");
+
+#[cfg(feature = "runtime-tokio")]
+type Inner<T> = tokio::task::JoinHandle<T>;
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+type Inner<T> = async_std::task::JoinHandle<T>;
+
+/// Handle of a task spawned by this crate, resolves to the output of the task.
+///
+/// Dropping it detaches the task. A panic of the task is resumed where the handle is awaited.
+#[derive(Debug)]
+pub struct JoinHandle<T>(Inner<T>);
+
+impl<T: Send + 'static> JoinHandle<T> {
+    /// Cancel the task at its next await point.
+    pub fn abort(self) {
+        #[cfg(feature = "runtime-tokio")]
+        self.0.abort();
+
+        #[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+        async_std::task::spawn(self.0.cancel());
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        #[cfg(feature = "runtime-tokio")]
+        return Pin::new(&mut self.0).poll(cx).map(|result| match result {
+            Ok(output) => output,
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(err) => panic!("{}", err),
+        });
+
+        #[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+/// Connection properties wired to the runtime.
+pub fn connection_properties() -> ConnectionProperties {
+    #[cfg(feature = "runtime-tokio")]
+    return ConnectionProperties::default()
+        .with_executor(tokio_executor_trait::Tokio::current())
+        .with_reactor(tokio_reactor_trait::Tokio);
+
+    // lapin's own default runtime is the one async-std runs on
+    #[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+    ConnectionProperties::default()
+}
+
+/// Whether a task can be spawned from here, tokio needs to be called from within a runtime.
+pub(crate) fn can_spawn() -> bool {
+    #[cfg(feature = "runtime-tokio")]
+    return tokio::runtime::Handle::try_current().is_ok();
+
+    #[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+    true
+}
+
+/// Spawn `future`, named for tokio-console or the async-std task name.
+pub(crate) fn spawn<F>(name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "runtime-tokio", tokio_unstable, feature = "tokio-console"))]
+    return JoinHandle(
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("Failed to spawn a task"),
+    );
+
+    #[cfg(all(feature = "runtime-tokio", not(all(tokio_unstable, feature = "tokio-console"))))]
+    return {
+        let _ = name;
+        JoinHandle(tokio::task::spawn(future))
+    };
+
+    #[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+    JoinHandle(
+        async_std::task::Builder::new()
+            .name(name.to_string())
+            .spawn(future)
+            .expect("Failed to spawn a task"),
+    )
+}
+
+/// Sleep according to the `clock` of the crate.
+pub(crate) async fn sleep(duration: Duration) {
+    if let Some(clock) = crate::clock::current() {
+        return clock.sleep(duration).await;
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    tokio::time::sleep(duration).await;
+
+    #[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+    async_std::task::sleep(duration).await;
+}
+
+/// `None` when `future` didn't complete within `duration`.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    futures_lite::future::or(async { Some(future.await) }, async {
+        sleep(duration).await;
+        None
+    })
+    .await
+}
@@ -0,0 +1,237 @@
+//! Step chains: each step is consumed from its own exchange and its output is published to the next step's exchange
+//! once it succeeded. When a step fails for good, the compensations of the steps completed before it are published.
+//!
+//! The step exchanges are expected to be bound to consumed queues, like any other listener.
+//! Delivery is at-least-once: a step whose output wasn't acked by the broker is retried.
+//! The inputs of the compensated steps travel along with the saga, each compensation receives the input of its step.
+
+use crate::context::ConsumeContext;
+use crate::{check_confirmed, headers, BrokerListener, Consumer, Publisher, PublishConfirm, Rejection, Result};
+use async_trait::async_trait;
+use lapin::message::Delivery;
+use lapin::types::{AMQPValue, ByteArray, FieldArray};
+use lapin::BasicProperties;
+use std::sync::Arc;
+
+pub const SAGA_ID_HEADER: &str = "x-saga-id";
+pub const SAGA_STEP_HEADER: &str = "x-saga-step";
+/// Set on the compensation messages, index of the step which failed.
+pub const SAGA_FAILED_STEP_HEADER: &str = "x-saga-failed-step";
+/// Inputs of the steps completed so far, by index, void for the steps without a compensation.
+pub const SAGA_INPUTS_HEADER: &str = "x-saga-inputs";
+
+#[async_trait]
+pub trait SagaStep: Send + Sync {
+    /// Run the step on the output of the previous one, return the payload of the next one.
+    /// Rejected with requeue: requeue or retry the step
+    /// Rejected without requeue: give up and compensate the steps completed so far
+    async fn run(&self, delivery: &Delivery, context: &ConsumeContext) -> std::result::Result<Vec<u8>, Rejection>;
+}
+
+struct StepDefinition {
+    exchange: &'static str,
+    step: Arc<dyn SagaStep>,
+    /// Receives the input of this step when a later step fails for good.
+    compensation: Option<&'static str>,
+}
+
+#[derive(Clone)]
+pub struct Saga {
+    steps: Vec<Arc<StepDefinition>>,
+}
+
+impl Saga {
+    pub fn new() -> Self {
+        Self { steps: vec![] }
+    }
+
+    pub fn with_step(self, exchange: &'static str, step: Arc<dyn SagaStep>) -> Self {
+        self.push(exchange, step, None)
+    }
+
+    /// A step which has to be undone, by the listeners of `compensation`, when a later step fails for good.
+    pub fn with_compensated_step(self, exchange: &'static str, step: Arc<dyn SagaStep>, compensation: &'static str) -> Self {
+        self.push(exchange, step, Some(compensation))
+    }
+
+    fn push(mut self, exchange: &'static str, step: Arc<dyn SagaStep>, compensation: Option<&'static str>) -> Self {
+        self.steps.push(Arc::new(StepDefinition {
+            exchange,
+            step,
+            compensation,
+        }));
+        self
+    }
+
+    /// Register a listener for each step on `consumer`, before it's spawned.
+    /// The outputs and compensations are published with `publisher`.
+    pub fn attach(&self, consumer: &mut Consumer, publisher: &Publisher) {
+        for index in 0..self.steps.len() {
+            consumer.add_listener(Arc::new(StepListener {
+                saga: self.clone(),
+                publisher: publisher.clone(),
+                index,
+            }));
+        }
+    }
+
+    /// Start a new saga with `payload` as the input of the first step, return its id.
+    pub async fn start(&self, publisher: &Publisher, routing_key: &str, payload: &[u8]) -> Result<(String, PublishConfirm)> {
+        let first = self.steps.first().expect("A saga needs at least one step");
+        let saga_id = uuid::Uuid::new_v4().to_string();
+
+        let confirm = publisher
+            .publish_with(first.exchange, routing_key, payload, properties(&saga_id, SAGA_STEP_HEADER, 0))
+            .await?;
+
+        Ok((saga_id, confirm))
+    }
+
+    /// Publish the compensations of the steps before `failed`, the most recent first, each with the input of its step.
+    async fn compensate(&self, publisher: &Publisher, saga_id: &str, failed: usize, delivery: &Delivery) {
+        let inputs = inputs(delivery);
+        let compensations = self.steps[..failed]
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(index, definition)| Some((index, definition.compensation?)));
+
+        for (index, compensation) in compensations {
+            let Some(AMQPValue::ByteArray(input)) = inputs.get(index) else {
+                error!(saga_id, compensation, step = index, "Missing the input of a compensated saga step");
+                continue;
+            };
+
+            let res = publisher
+                .publish_with(
+                    compensation,
+                    delivery.routing_key.as_str(),
+                    input.as_slice(),
+                    properties(saga_id, SAGA_FAILED_STEP_HEADER, failed),
+                )
+                .await;
+
+            if let Err(err) = res {
+                error!(%err, saga_id, compensation, "Failed to publish a saga compensation");
+            }
+        }
+    }
+}
+
+impl Default for Saga {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Saga {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let steps = self.steps.iter().map(|definition| (definition.exchange, definition.compensation));
+        f.debug_struct("Saga").field("steps", &steps.collect::<Vec<_>>()).finish()
+    }
+}
+
+/// Inputs carried by `delivery`, see `SAGA_INPUTS_HEADER`.
+fn inputs(delivery: &Delivery) -> Vec<AMQPValue> {
+    match headers::get(delivery, SAGA_INPUTS_HEADER) {
+        Some(AMQPValue::FieldArray(inputs)) => inputs.as_slice().to_vec(),
+        _ => vec![],
+    }
+}
+
+fn properties(saga_id: &str, step_header: &str, step: usize) -> BasicProperties {
+    let properties = headers::insert(BasicProperties::default(), SAGA_ID_HEADER, headers::long_string(saga_id));
+    headers::insert(properties, step_header, AMQPValue::LongUInt(step as u32))
+}
+
+struct StepListener {
+    saga: Saga,
+    publisher: Publisher,
+    index: usize,
+}
+
+impl StepListener {
+    fn saga_id(delivery: &Delivery) -> String {
+        headers::get(delivery, SAGA_ID_HEADER)
+            .and_then(headers::as_string)
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl BrokerListener for StepListener {
+    fn exchange_name(&self) -> &'static str {
+        self.saga.steps[self.index].exchange
+    }
+
+    async fn on_poison(&self, delivery: &Delivery, _attempts: u32, _last_error: Option<&str>) {
+        self.saga.compensate(&self.publisher, &Self::saga_id(delivery), self.index, delivery).await;
+    }
+
+    async fn consume(&self, _delivery: &Delivery) -> std::result::Result<(), Rejection> {
+        unreachable!("saga steps are consumed with a context")
+    }
+
+    async fn consume_with_context(
+        &self,
+        delivery: &Delivery,
+        context: &ConsumeContext,
+    ) -> std::result::Result<(), Rejection> {
+        let saga_id = Self::saga_id(delivery);
+        // the whole saga is a single trace, and an unroutable step must not be confirmed
+        let publisher = self
+            .publisher
+            .clone()
+            .with_trace_id(crate::trace::trace_id(delivery))
+            .with_mandatory(true);
+
+        let output = match self.saga.steps[self.index].step.run(delivery, context).await {
+            Ok(output) => output,
+            Err(rejection) if !rejection.requeue => {
+                self.saga.compensate(&publisher, &saga_id, self.index, delivery).await;
+                return Err(rejection);
+            }
+            Err(rejection) => return Err(rejection),
+        };
+
+        let Some(next) = self.saga.steps.get(self.index + 1) else {
+            debug!(saga_id, "Saga completed");
+            return Ok(());
+        };
+
+        if let Err(err) = publisher.enable_confirms().await {
+            error!(%err, saga_id, "Failed to enable the confirms of the saga publisher");
+            return Err(Rejection::requeue().with_reason("next_step_not_published"));
+        }
+
+        let mut inputs = inputs(delivery);
+        inputs.resize(self.index, AMQPValue::Void);
+        inputs.push(match self.saga.steps[self.index].compensation {
+            Some(_) => AMQPValue::ByteArray(ByteArray::from(delivery.data.as_slice())),
+            None => AMQPValue::Void,
+        });
+        let properties = headers::insert(
+            properties(&saga_id, SAGA_STEP_HEADER, self.index + 1),
+            SAGA_INPUTS_HEADER,
+            AMQPValue::FieldArray(FieldArray::from(inputs)),
+        );
+
+        let res = publisher
+            .publish_with(next.exchange, delivery.routing_key.as_str(), &output, properties)
+            .await;
+
+        match res {
+            Ok(confirm) => match check_confirmed(next.exchange, confirm).await {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    error!(%err, saga_id, next_step = next.exchange, "Failed to confirm the next saga step");
+                    Err(Rejection::requeue().with_reason("next_step_not_confirmed"))
+                }
+            },
+            Err(err) => {
+                error!(%err, saga_id, next_step = next.exchange, "Failed to publish the next saga step");
+                Err(Rejection::requeue().with_reason("next_step_not_published"))
+            }
+        }
+    }
+}
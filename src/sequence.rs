@@ -0,0 +1,164 @@
+//! Sequence-numbered publishes, to validate ordering assumptions (e.g. during a migration).
+//!
+//! Each publisher stamps its id and an incrementing number on every message, `SequenceChecker` tells the
+//! consumer about the gaps and the reordering, per publisher. Redeliveries and retries show up as out of order.
+
+use crate::headers;
+use lapin::message::Delivery;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub const PUBLISHER_ID_HEADER: &str = "x-publisher-id";
+pub const SEQUENCE_HEADER: &str = "x-sequence";
+
+/// Numbering of the messages of a publisher and its clones.
+#[derive(Debug)]
+pub(crate) struct Sequence {
+    pub(crate) publisher_id: String,
+    last: AtomicU64,
+}
+
+impl Sequence {
+    pub(crate) fn new() -> Self {
+        Self {
+            publisher_id: uuid::Uuid::new_v4().to_string(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of the next message, starting at 1.
+    pub(crate) fn next(&self) -> u64 {
+        self.last.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SequenceCheck {
+    /// First message received from this publisher.
+    First,
+    InOrder,
+    /// Messages between the last received one and this one are missing, or still to come.
+    Gap { expected: u64, received: u64 },
+    /// At most the last received number, a redelivery or a message overtaken by the later ones.
+    OutOfOrder { expected: u64, received: u64 },
+    /// Not sequence-numbered.
+    Unnumbered,
+}
+
+/// Last sequence number received from each publisher.
+#[derive(Debug, Default)]
+pub struct SequenceChecker {
+    last: Mutex<HashMap<String, u64>>,
+}
+
+impl SequenceChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check(&self, delivery: &Delivery) -> SequenceCheck {
+        let publisher_id = headers::get(delivery, PUBLISHER_ID_HEADER).and_then(headers::as_string);
+        let sequence = headers::get(delivery, SEQUENCE_HEADER).and_then(headers::as_u64);
+        let (Some(publisher_id), Some(received)) = (publisher_id, sequence) else {
+            return SequenceCheck::Unnumbered;
+        };
+
+        let mut last_by_publisher = self.last.lock().unwrap();
+        let Some(last) = last_by_publisher.get_mut(&publisher_id) else {
+            last_by_publisher.insert(publisher_id, received);
+            return SequenceCheck::First;
+        };
+
+        let expected = *last + 1;
+        let check = if received == expected {
+            SequenceCheck::InOrder
+        } else if received > expected {
+            SequenceCheck::Gap { expected, received }
+        } else {
+            SequenceCheck::OutOfOrder { expected, received }
+        };
+
+        if received >= expected {
+            *last = received;
+        } else {
+            warn!(publisher_id, expected, received, "Sequence number out of order");
+        }
+        if let SequenceCheck::Gap { .. } = check {
+            warn!(publisher_id, expected, received, "Gap in the sequence numbers");
+        }
+
+        check
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::acker::Acker;
+    use lapin::types::AMQPValue;
+    use lapin::BasicProperties;
+
+    fn delivery(publisher_id: &str, sequence: u64) -> Delivery {
+        let properties = headers::insert(BasicProperties::default(), PUBLISHER_ID_HEADER, headers::long_string(publisher_id));
+        Delivery {
+            delivery_tag: 1,
+            exchange: "orders".into(),
+            routing_key: "created".into(),
+            redelivered: false,
+            properties: headers::insert(properties, SEQUENCE_HEADER, AMQPValue::LongLongInt(sequence as i64)),
+            data: vec![],
+            acker: Acker::default(),
+        }
+    }
+
+    #[test]
+    fn in_order_and_gaps() {
+        let checker = SequenceChecker::new();
+        assert_eq!(checker.check(&delivery("a", 1)), SequenceCheck::First);
+        assert_eq!(checker.check(&delivery("a", 2)), SequenceCheck::InOrder);
+        assert_eq!(checker.check(&delivery("a", 5)), SequenceCheck::Gap { expected: 3, received: 5 });
+        assert_eq!(checker.check(&delivery("a", 6)), SequenceCheck::InOrder);
+        // the gap filled late
+        assert_eq!(checker.check(&delivery("a", 3)), SequenceCheck::OutOfOrder { expected: 7, received: 3 });
+        assert_eq!(checker.check(&delivery("a", 7)), SequenceCheck::InOrder);
+    }
+
+    #[test]
+    fn duplicates() {
+        let checker = SequenceChecker::new();
+        checker.check(&delivery("a", 1));
+        checker.check(&delivery("a", 2));
+        assert_eq!(checker.check(&delivery("a", 2)), SequenceCheck::OutOfOrder { expected: 3, received: 2 });
+        assert_eq!(checker.check(&delivery("a", 3)), SequenceCheck::InOrder);
+    }
+
+    #[test]
+    fn restarts() {
+        let checker = SequenceChecker::new();
+        checker.check(&delivery("a", 1));
+        checker.check(&delivery("a", 2));
+        // a restarted publisher has a new id and numbers from 1 again
+        assert_eq!(checker.check(&delivery("b", 1)), SequenceCheck::First);
+        assert_eq!(checker.check(&delivery("b", 2)), SequenceCheck::InOrder);
+        assert_eq!(checker.check(&delivery("a", 3)), SequenceCheck::InOrder);
+        // the same id restarting is out of order until it catches up
+        assert_eq!(checker.check(&delivery("a", 1)), SequenceCheck::OutOfOrder { expected: 4, received: 1 });
+    }
+
+    #[test]
+    fn unnumbered() {
+        let checker = SequenceChecker::new();
+        let mut unnumbered = delivery("a", 1);
+        unnumbered.properties = BasicProperties::default();
+        assert_eq!(checker.check(&unnumbered), SequenceCheck::Unnumbered);
+        assert_eq!(checker.check(&delivery("a", 1)), SequenceCheck::First);
+    }
+
+    #[test]
+    fn sequence_numbers_start_at_one() {
+        let sequence = Sequence::new();
+        assert_eq!((sequence.next(), sequence.next()), (1, 2));
+        assert_ne!(sequence.publisher_id, Sequence::new().publisher_id);
+    }
+}
@@ -0,0 +1,118 @@
+//! A connection shared by several `Broker`s, e.g. one per bounded context of a modular monolith:
+//! each of them holds a lease on it, the last one shut down closes it.
+
+use crate::{runtime, Broker, Result};
+use lapin::Connection;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Inner {
+    conn: Connection,
+    leases: AtomicUsize,
+}
+
+/// Handle on the shared connection, cheap to clone. It doesn't keep the connection open by itself.
+#[derive(Clone)]
+pub struct SharedConnection {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for SharedConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedConnection")
+            .field("status", self.inner.conn.status())
+            .field("leases", &self.leases())
+            .finish()
+    }
+}
+
+impl SharedConnection {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                conn,
+                leases: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub async fn connect(uri: &str) -> Result<Self> {
+        let conn = Connection::connect(uri, runtime::connection_properties()).await?;
+
+        Ok(Self::new(conn))
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.inner.conn
+    }
+
+    /// Brokers currently on this connection.
+    pub fn leases(&self) -> usize {
+        self.inner.leases.load(Ordering::Acquire)
+    }
+
+    /// A new `Broker` on this connection, to set up with `setup_publisher` and `setup_consumer`.
+    pub fn broker(&self) -> Broker {
+        Broker::with_lease(self.lease())
+    }
+
+    pub(crate) fn lease(&self) -> ConnectionLease {
+        self.inner.leases.fetch_add(1, Ordering::AcqRel);
+
+        ConnectionLease {
+            inner: self.inner.clone(),
+            released: false,
+        }
+    }
+}
+
+/// The connection of a `Broker`, shared or its own.
+pub(crate) struct ConnectionLease {
+    inner: Arc<Inner>,
+    released: bool,
+}
+
+impl fmt::Debug for ConnectionLease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner.conn, f)
+    }
+}
+
+impl ConnectionLease {
+    /// A connection nobody else uses.
+    pub(crate) fn owned(conn: Connection) -> Self {
+        SharedConnection::new(conn).lease()
+    }
+
+    /// Give the lease back, closing the connection if it was the last one.
+    pub(crate) async fn release(mut self) -> Result<()> {
+        self.released = true;
+        let last = self.inner.leases.fetch_sub(1, Ordering::AcqRel) == 1;
+
+        if last && self.inner.conn.status().connected() {
+            self.inner.conn.close(200, "Shutdown").await?;
+        } else if !last {
+            debug!(leases = self.inner.leases.load(Ordering::Acquire), "Shared connection kept open for the other brokers");
+        }
+
+        Ok(())
+    }
+}
+
+impl Deref for ConnectionLease {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.inner.conn
+    }
+}
+
+impl Drop for ConnectionLease {
+    fn drop(&mut self) {
+        if !self.released {
+            self.inner.leases.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Load shedding: under overload, deliveries are rejected with requeue (for another instance, or later)
+//! instead of piling up behind the busy permits, so the latency of the accepted ones stays predictable.
+//! And the order the deliveries wait for the permits in.
+
+use crate::clock;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedSemaphorePermit;
+
+/// Thresholds above which a listener sheds the deliveries waiting for a permit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadShedding {
+    /// Longest a delivery waits for a permit before being rejected.
+    pub max_wait: Option<Duration>,
+    /// While every permit is taken and the oldest in-flight delivery runs for longer than this,
+    /// deliveries are rejected instead of waiting.
+    pub max_in_flight_time: Option<Duration>,
+}
+
+impl LoadShedding {
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    pub fn with_max_in_flight_time(mut self, max_in_flight_time: Duration) -> Self {
+        self.max_in_flight_time = Some(max_in_flight_time);
+        self
+    }
+}
+
+/// How the consumer waits for the permits of a listener, see `BrokerListener::permit_acquisition`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PermitAcquisition {
+    /// The consumer waits for the permit before reading the next delivery: the permits are given in delivery order,
+    /// but a listener busy with long tasks holds up the deliveries of every listener.
+    #[default]
+    Fifo,
+    /// The delivery waits for its permit in its own task, so the consumer reads the next ones (e.g. short tasks
+    /// of other listeners) meanwhile. The waiting deliveries of the listener still get the permits in order,
+    /// and are bounded by the prefetch.
+    Detached,
+}
+
+/// Start of the in-flight deliveries of a listener, in the order they started.
+#[derive(Debug, Default)]
+pub(crate) struct InFlightTimes {
+    next: AtomicU64,
+    started: Mutex<BTreeMap<u64, Instant>>,
+}
+
+impl InFlightTimes {
+    /// How long the oldest in-flight delivery has been running.
+    pub(crate) fn oldest(&self) -> Option<Duration> {
+        self.started.lock().unwrap().values().next().map(|started| clock::elapsed(*started))
+    }
+}
+
+/// The permit of a delivery, and its start when tracked, both released once dropped.
+#[derive(Debug)]
+pub(crate) struct Running {
+    _permit: OwnedSemaphorePermit,
+    permits: u32,
+    started: Option<(u64, Arc<InFlightTimes>)>,
+}
+
+impl Running {
+    pub(crate) fn new(permit: OwnedSemaphorePermit, permits: u32, times: Option<&Arc<InFlightTimes>>) -> Self {
+        let started = times.map(|times| {
+            let id = times.next.fetch_add(1, Ordering::Relaxed);
+            times.started.lock().unwrap().insert(id, clock::now());
+            (id, times.clone())
+        });
+
+        Self {
+            _permit: permit,
+            permits,
+            started,
+        }
+    }
+
+    /// The number of permits held, the cost of the delivery.
+    pub(crate) fn permits(&self) -> u32 {
+        self.permits
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some((id, times)) = &self.started {
+            times.started.lock().unwrap().remove(id);
+        }
+    }
+}
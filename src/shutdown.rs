@@ -0,0 +1,27 @@
+//! Ordered shutdown of a `Broker`, see `Broker::shutdown`.
+
+use std::time::Duration;
+
+/// How long each stage of the shutdown may take, the next stage starts anyway once it's over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownTimeouts {
+    /// Cancel the consumers and wait for the in-flight deliveries to be acked or rejected.
+    pub drain: Duration,
+    /// Run the `on_stop` hook of the drained listeners.
+    pub stop: Duration,
+    /// Wait for the confirmations of the pending publishes.
+    pub flush: Duration,
+    /// Close the channels then the connections.
+    pub close: Duration,
+}
+
+impl Default for ShutdownTimeouts {
+    fn default() -> Self {
+        Self {
+            drain: Duration::from_secs(30),
+            stop: Duration::from_secs(10),
+            flush: Duration::from_secs(10),
+            close: Duration::from_secs(5),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Signature of the payloads, protecting consumers against tampered or foreign messages on shared brokers.
+//!
+//! The publisher signs the payload as sent (after encryption) into the `x-signature` header,
+//! the consumer verifies it before anything else is done with the delivery.
+
+use lapin::message::Delivery;
+use std::sync::Arc;
+
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+pub trait Signer: Send + Sync {
+    fn sign(&self, payload: &[u8]) -> String;
+
+    fn verify(&self, payload: &[u8], signature: &str) -> bool;
+}
+
+/// What to do with a delivery whose signature is missing or wrong.
+#[derive(Clone, Default)]
+pub enum SignatureFailureAction {
+    /// Reject without requeue.
+    #[default]
+    Reject,
+    /// Republish to this exchange, then ack.
+    DeadLetter(String),
+    /// Call back, then reject without requeue.
+    Callback(Arc<dyn Fn(&Delivery) + Send + Sync>),
+}
+
+impl std::fmt::Debug for SignatureFailureAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureFailureAction::Reject => f.write_str("Reject"),
+            SignatureFailureAction::DeadLetter(exchange) => f.debug_tuple("DeadLetter").field(exchange).finish(),
+            SignatureFailureAction::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+/// HMAC-SHA256, hex encoded.
+#[cfg(feature = "signing")]
+pub struct HmacSha256Signer {
+    key: Vec<u8>,
+}
+
+#[cfg(feature = "signing")]
+impl std::fmt::Debug for HmacSha256Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HmacSha256Signer").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "signing")]
+impl HmacSha256Signer {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    fn mac(&self, payload: &[u8]) -> hmac::Hmac<sha2::Sha256> {
+        use hmac::Mac;
+
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(payload);
+        mac
+    }
+}
+
+#[cfg(feature = "signing")]
+impl Signer for HmacSha256Signer {
+    fn sign(&self, payload: &[u8]) -> String {
+        use hmac::Mac;
+
+        self.mac(payload)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn verify(&self, payload: &[u8], signature: &str) -> bool {
+        use hmac::Mac;
+
+        let Some(signature) = decode_hex(signature) else {
+            return false;
+        };
+
+        // constant time comparison
+        self.mac(payload).verify_slice(&signature).is_ok()
+    }
+}
+
+#[cfg(feature = "signing")]
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,79 @@
+//! Consumption statistics of each listener, always recorded (unlike the metrics) and readable from the application,
+//! e.g. for custom autoscaling or shutting down once idle.
+
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A snapshot of the statistics of a listener since it was registered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConsumerStats {
+    /// Deliveries dispatched to the listener.
+    pub received: u64,
+    pub acked: u64,
+    /// Rejected with requeue, requeued or republished for retry.
+    pub rejected_requeue: u64,
+    /// Rejected without requeue, undecodable or wrongly signed, dropped or dead-lettered.
+    pub rejected_drop: u64,
+    /// Received but not acked or rejected yet.
+    pub in_flight: u64,
+    pub last_delivery_at: Option<DateTime<Utc>>,
+    /// When a delivery was last acked or rejected.
+    pub last_finished_at: Option<DateTime<Utc>>,
+}
+
+impl ConsumerStats {
+    /// When a delivery was last received or finished.
+    pub fn last_activity_at(&self) -> Option<DateTime<Utc>> {
+        self.last_delivery_at.max(self.last_finished_at)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct StatsRecorder {
+    received: AtomicU64,
+    acked: AtomicU64,
+    rejected_requeue: AtomicU64,
+    rejected_drop: AtomicU64,
+    last_delivery_at: Mutex<Option<DateTime<Utc>>>,
+    last_finished_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl StatsRecorder {
+    pub(crate) fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        *self.last_delivery_at.lock().unwrap() = Some(Utc::now());
+    }
+
+    pub(crate) fn acked(&self) {
+        self.acked.fetch_add(1, Ordering::Relaxed);
+        self.finished();
+    }
+
+    pub(crate) fn rejected(&self, requeue: bool) {
+        let counter = if requeue { &self.rejected_requeue } else { &self.rejected_drop };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.finished();
+    }
+
+    fn finished(&self) {
+        *self.last_finished_at.lock().unwrap() = Some(Utc::now());
+    }
+
+    pub(crate) fn snapshot(&self) -> ConsumerStats {
+        let received = self.received.load(Ordering::Relaxed);
+        let acked = self.acked.load(Ordering::Relaxed);
+        let rejected_requeue = self.rejected_requeue.load(Ordering::Relaxed);
+        let rejected_drop = self.rejected_drop.load(Ordering::Relaxed);
+
+        ConsumerStats {
+            received,
+            acked,
+            rejected_requeue,
+            rejected_drop,
+            in_flight: received.saturating_sub(acked + rejected_requeue + rejected_drop),
+            last_delivery_at: *self.last_delivery_at.lock().unwrap(),
+            last_finished_at: *self.last_finished_at.lock().unwrap(),
+        }
+    }
+}
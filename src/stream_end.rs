@@ -0,0 +1,104 @@
+//! What the consumer loop does once its stream of deliveries ends while not cancelled by `Consumer::drain`,
+//! e.g. its channel was closed or the broker cancelled the consumer when its queue was deleted,
+//! and how it gets over the errors read from the stream.
+
+use crate::{ConsumerStream, Result};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Build a new stream of deliveries, e.g. on a new channel, to resume consuming.
+pub type RecoverFn = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<ConsumerStream>> + Send>> + Send + Sync>;
+
+/// See `Consumer::set_stream_end_action`.
+#[derive(Clone, Default)]
+pub enum StreamEndAction {
+    /// Log a warning and return `Ok(())`.
+    #[default]
+    Stop,
+    /// Return `Error::ConsumerEnded`.
+    Fail,
+    /// Call back, then return `Error::ConsumerEnded`.
+    Callback(Arc<dyn Fn() + Send + Sync>),
+    /// Resume on the stream returned, each time it ends. Only for the loop run by `Consumer::spawn`,
+    /// `Consumer::consume` returns `Error::ConsumerEnded` instead.
+    Recover(RecoverFn),
+}
+
+impl std::fmt::Debug for StreamEndAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamEndAction::Stop => f.write_str("Stop"),
+            StreamEndAction::Fail => f.write_str("Fail"),
+            StreamEndAction::Callback(_) => f.write_str("Callback"),
+            StreamEndAction::Recover(_) => f.write_str("Recover"),
+        }
+    }
+}
+
+/// Keep reading the stream after an error, waiting `initial`, doubled after each consecutive error up to `max`.
+/// The loop gives up with the error once `max_retries` errors in a row were read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamErrorBackoff {
+    pub max_retries: u32,
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl StreamErrorBackoff {
+    pub fn new(max_retries: u32, initial: Duration, max: Duration) -> Self {
+        Self {
+            max_retries,
+            initial,
+            max,
+        }
+    }
+
+    /// Wait before reading again after the `retry`-th consecutive error (starting at 1).
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.max(1) - 1);
+
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+impl Default for StreamErrorBackoff {
+    fn default() -> Self {
+        Self::new(5, Duration::from_millis(100), Duration::from_secs(10))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_from_initial() {
+        let backoff = StreamErrorBackoff::new(5, Duration::from_millis(100), Duration::from_secs(10));
+
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(4), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let backoff = StreamErrorBackoff::new(5, Duration::from_millis(100), Duration::from_secs(1));
+
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+        assert_eq!(backoff.delay(20), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn delay_saturates_at_high_retries() {
+        let backoff = StreamErrorBackoff::new(u32::MAX, Duration::from_secs(1), Duration::MAX);
+
+        assert_eq!(backoff.delay(33), Duration::from_secs(u32::MAX as u64));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(u32::MAX as u64));
+
+        let backoff = StreamErrorBackoff::new(u32::MAX, Duration::MAX, Duration::from_secs(10));
+        assert_eq!(backoff.delay(3), Duration::from_secs(10));
+    }
+}
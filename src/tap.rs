@@ -0,0 +1,88 @@
+//! Copy of the consumed/published messages to an archive exchange, for audit pipelines,
+//! and of the published ones to an in-process broadcast channel (see `enable_local_tap`).
+
+use crate::headers;
+use crate::naming::TopologyAffixes;
+use crate::tasks::{self, TaskKind};
+use lapin::options::BasicPublishOptions;
+use lapin::{BasicProperties, Channel};
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+
+pub const ORIGINAL_EXCHANGE_HEADER: &str = "x-original-exchange";
+
+/// A message published by this process, as seen on the local tap.
+#[derive(Clone, Debug)]
+pub struct PublishedMessage {
+    pub exchange: String,
+    pub routing_key: String,
+    /// Before encryption, if any.
+    pub payload: Vec<u8>,
+    pub properties: BasicProperties,
+}
+
+static LOCAL_TAP: RwLock<Option<broadcast::Sender<Arc<PublishedMessage>>>> = RwLock::new(None);
+
+/// Broadcast every message published from now on by any publisher of the process, once handed to the broker,
+/// so co-located components can react without a round-trip (and tests can assert on them).
+///
+/// A receiver lagging more than `capacity` messages behind misses the oldest ones. Enabling it again
+/// keeps the current channel and only subscribes to it.
+pub fn enable_local_tap(capacity: usize) -> broadcast::Receiver<Arc<PublishedMessage>> {
+    let mut local_tap = LOCAL_TAP.write().unwrap();
+    local_tap.get_or_insert_with(|| broadcast::channel(capacity.max(1)).0).subscribe()
+}
+
+/// A new receiver of the local tap, `None` while it isn't enabled.
+pub fn subscribe_local_tap() -> Option<broadcast::Receiver<Arc<PublishedMessage>>> {
+    LOCAL_TAP.read().unwrap().as_ref().map(broadcast::Sender::subscribe)
+}
+
+/// Stop broadcasting, the receivers end once they read what was already sent.
+pub fn disable_local_tap() {
+    LOCAL_TAP.write().unwrap().take();
+}
+
+pub(crate) fn mirror(exchange: &str, routing_key: &str, payload: &[u8], properties: &BasicProperties) {
+    let local_tap = LOCAL_TAP.read().unwrap();
+    let Some(sender) = local_tap.as_ref() else {
+        return;
+    };
+
+    // no receiver is not an error, they come and go
+    let _ = sender.send(Arc::new(PublishedMessage {
+        exchange: exchange.to_string(),
+        routing_key: routing_key.to_string(),
+        payload: payload.to_vec(),
+        properties: properties.clone(),
+    }));
+}
+
+/// Publish a copy of a message to `archive_exchange` in background, fire-and-forget:
+/// a failure is only logged and never affects the original message.
+pub(crate) fn archive(
+    channel: &Channel,
+    affixes: &TopologyAffixes,
+    archive_exchange: &str,
+    exchange: &str,
+    routing_key: &str,
+    data: &[u8],
+    properties: &BasicProperties,
+) {
+    let properties = headers::insert(properties.clone(), ORIGINAL_EXCHANGE_HEADER, headers::long_string(exchange));
+
+    let channel = channel.clone();
+    let archive_exchange = affixes.resolve(archive_exchange).into_owned();
+    let routing_key = routing_key.to_string();
+    let data = data.to_vec();
+
+    tasks::spawn(TaskKind::Background, "amqp-archive", async move {
+        let res = channel
+            .basic_publish(&archive_exchange, &routing_key, BasicPublishOptions::default(), &data, properties)
+            .await;
+
+        if let Err(err) = res {
+            warn!(%err, %archive_exchange, "Failed to archive a message");
+        }
+    });
+}
@@ -0,0 +1,73 @@
+//! Every task spawned by this crate, named and instrumented so they can be told apart in tokio-console
+//! (built with `RUSTFLAGS="--cfg tokio_unstable"` and the `tokio-console` feature) and counted at runtime.
+
+use crate::runtime::{self, JoinHandle};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::Instrument;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskKind {
+    /// Reading deliveries from the broker and dispatching them.
+    ConsumerLoop,
+    /// Running a listener on a single delivery.
+    Delivery,
+    /// Anything else: canary, archive copies, cleanups...
+    Background,
+}
+
+static CONSUMER_LOOPS: AtomicUsize = AtomicUsize::new(0);
+static DELIVERIES: AtomicUsize = AtomicUsize::new(0);
+static BACKGROUND: AtomicUsize = AtomicUsize::new(0);
+
+impl TaskKind {
+    fn counter(self) -> &'static AtomicUsize {
+        match self {
+            TaskKind::ConsumerLoop => &CONSUMER_LOOPS,
+            TaskKind::Delivery => &DELIVERIES,
+            TaskKind::Background => &BACKGROUND,
+        }
+    }
+}
+
+/// Tasks of this crate currently alive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TaskCounts {
+    pub consumer_loops: usize,
+    pub deliveries: usize,
+    pub background: usize,
+}
+
+pub fn task_counts() -> TaskCounts {
+    TaskCounts {
+        consumer_loops: CONSUMER_LOOPS.load(Ordering::Relaxed),
+        deliveries: DELIVERIES.load(Ordering::Relaxed),
+        background: BACKGROUND.load(Ordering::Relaxed),
+    }
+}
+
+/// Decrement the count once the task is over, completed or aborted.
+struct Alive(TaskKind);
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        self.0.counter().fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn spawn<F>(kind: TaskKind, name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    kind.counter().fetch_add(1, Ordering::Relaxed);
+    let alive = Alive(kind);
+
+    let future = async move {
+        let _alive = alive;
+        future.await
+    }
+    .instrument(info_span!("amqp_task", task = name));
+
+    runtime::spawn(name, future)
+}
@@ -0,0 +1,221 @@
+//! Several tenants sharing one topology: routing keys are prefixed with the tenant id on publish,
+//! and the prefix is checked against the `x-tenant-id` header on consume.
+//!
+//! Bind the queues with the tenant prefix (e.g. `acme.orders.*`) to keep a consumer to a single tenant,
+//! or with `*.orders.*` to serve all of them.
+
+use crate::context::ConsumeContext;
+use crate::retry::{RedeliveryPolicy, RetryPolicy};
+use crate::shedding::{LoadShedding, PermitAcquisition};
+use crate::{headers, BrokerListener, BrokerPublish, Error, Publisher, PublishConfirm, RejectMethod, Rejection, Result};
+use async_trait::async_trait;
+use lapin::message::Delivery;
+use lapin::BasicProperties;
+use serde::Serialize;
+use std::collections::HashSet;
+
+pub const TENANT_HEADER: &str = "x-tenant-id";
+
+/// The tenant of a delivery, from its header.
+pub fn tenant_id(delivery: &Delivery) -> Option<String> {
+    headers::get(delivery, TENANT_HEADER).and_then(headers::as_string)
+}
+
+/// The routing key of a delivery, without its tenant prefix.
+pub fn routing_key(delivery: &Delivery) -> &str {
+    let routing_key = delivery.routing_key.as_str();
+
+    tenant_id(delivery)
+        .and_then(|tenant| routing_key.strip_prefix(tenant.as_str())?.strip_prefix('.'))
+        .unwrap_or(routing_key)
+}
+
+fn validate(tenant: &str) -> Result<()> {
+    // dots and wildcards would make the prefix ambiguous in topic bindings
+    if tenant.is_empty() || tenant.contains(['.', '*', '#']) {
+        return Err(Error::InvalidTenant(tenant.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Publish on behalf of a single tenant.
+#[derive(Clone, Debug)]
+pub struct TenantPublisher {
+    publisher: Publisher,
+    tenant: String,
+}
+
+impl TenantPublisher {
+    /// Fails with `Error::InvalidTenant` when `tenant` is empty or contains `.`, `*` or `#`.
+    pub fn new(publisher: Publisher, tenant: &str) -> Result<Self> {
+        validate(tenant)?;
+
+        Ok(Self {
+            publisher,
+            tenant: tenant.to_string(),
+        })
+    }
+
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    pub async fn publish<P>(&self, entity: &P, routing_key: &str) -> Result<PublishConfirm>
+    where
+        P: BrokerPublish + Serialize,
+    {
+        let serialized = bincode::serialize(entity)?;
+
+        self.publish_raw(entity.exchange_name(), routing_key, &serialized).await
+    }
+
+    /// Push without serializing
+    pub async fn publish_raw(&self, exchange: &str, routing_key: &str, msg: &[u8]) -> Result<PublishConfirm> {
+        let properties = headers::insert(BasicProperties::default(), TENANT_HEADER, headers::long_string(&self.tenant));
+        let routing_key = format!("{}.{}", self.tenant, routing_key);
+
+        let confirm = self.publisher.publish_with(exchange, &routing_key, msg, properties).await?;
+        crate::metrics::count_tenant_message(exchange, &self.tenant, "out");
+
+        Ok(confirm)
+    }
+}
+
+/// Wrap a listener to only hand it the deliveries of known tenants, the others are rejected without requeue.
+///
+/// The listener gets the tenant with `tenant_id`, and the unprefixed routing key with `routing_key`.
+pub struct TenantListener<L> {
+    inner: L,
+    /// `None` accepts every tenant.
+    tenants: Option<HashSet<String>>,
+}
+
+impl<L: BrokerListener> TenantListener<L> {
+    pub fn new(inner: L) -> Self {
+        Self { inner, tenants: None }
+    }
+
+    /// Only accept these tenants.
+    pub fn with_tenants<I, S>(mut self, tenants: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tenants = Some(tenants.into_iter().map(Into::into).collect());
+        self
+    }
+
+    fn check(&self, delivery: &Delivery) -> std::result::Result<(), Rejection> {
+        let Some(tenant) = tenant_id(delivery) else {
+            return Err(Rejection::discard().with_reason("missing_tenant"));
+        };
+
+        let prefixed = delivery
+            .routing_key
+            .as_str()
+            .strip_prefix(tenant.as_str())
+            .is_some_and(|rest| rest.starts_with('.'));
+        if !prefixed {
+            warn!(tenant, routing_key = %delivery.routing_key, "Routing key not prefixed with the tenant of the delivery");
+            return Err(Rejection::discard().with_reason("tenant_mismatch"));
+        }
+
+        if self.tenants.as_ref().is_some_and(|tenants| !tenants.contains(&tenant)) {
+            return Err(Rejection::discard().with_reason("unknown_tenant"));
+        }
+
+        crate::metrics::count_tenant_message(self.inner.exchange_name(), &tenant, "in");
+
+        Ok(())
+    }
+}
+
+impl<L> std::fmt::Debug for TenantListener<L> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TenantListener")
+            .field("tenants", &self.tenants)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<L: BrokerListener> BrokerListener for TenantListener<L> {
+    fn exchange_name(&self) -> &'static str {
+        self.inner.exchange_name()
+    }
+
+    fn max_concurrent_tasks(&self) -> usize {
+        self.inner.max_concurrent_tasks()
+    }
+
+    fn cost(&self, delivery: &Delivery) -> u32 {
+        self.inner.cost(delivery)
+    }
+
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.inner.retry_policy()
+    }
+
+    fn duration_buckets(&self) -> Option<Vec<f64>> {
+        self.inner.duration_buckets()
+    }
+
+    fn slow_threshold(&self) -> Option<std::time::Duration> {
+        self.inner.slow_threshold()
+    }
+
+    fn load_shedding(&self) -> Option<LoadShedding> {
+        self.inner.load_shedding()
+    }
+
+    fn redelivery_policy(&self) -> RedeliveryPolicy {
+        self.inner.redelivery_policy()
+    }
+
+    fn drop_expired(&self) -> bool {
+        self.inner.drop_expired()
+    }
+
+    fn ack_after_downstream_confirm(&self) -> bool {
+        self.inner.ack_after_downstream_confirm()
+    }
+
+    fn permit_acquisition(&self) -> PermitAcquisition {
+        self.inner.permit_acquisition()
+    }
+
+    fn reject_method(&self) -> RejectMethod {
+        self.inner.reject_method()
+    }
+
+    async fn on_start(&self) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.on_start().await
+    }
+
+    async fn on_stop(&self) {
+        self.inner.on_stop().await
+    }
+
+    async fn on_partitions_changed(&self, assigned: &[String], revoked: &[String]) {
+        self.inner.on_partitions_changed(assigned, revoked).await
+    }
+
+    async fn on_poison(&self, delivery: &Delivery, attempts: u32, last_error: Option<&str>) {
+        self.inner.on_poison(delivery, attempts, last_error).await
+    }
+
+    async fn consume(&self, delivery: &Delivery) -> std::result::Result<(), Rejection> {
+        self.check(delivery)?;
+        self.inner.consume(delivery).await
+    }
+
+    async fn consume_with_context(
+        &self,
+        delivery: &Delivery,
+        context: &ConsumeContext,
+    ) -> std::result::Result<(), Rejection> {
+        self.check(delivery)?;
+        self.inner.consume_with_context(delivery, context).await
+    }
+}
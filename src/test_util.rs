@@ -0,0 +1,165 @@
+//! Integration tests against a shared RabbitMQ: each test declares its topology under a unique prefix,
+//! so the tests can run in parallel, and deletes it once done.
+
+use crate::tasks::{self, TaskKind};
+use crate::{Broker, Result};
+use lapin::options::{ExchangeBindOptions, ExchangeDeclareOptions, ExchangeDeleteOptions, QueueBindOptions, QueueDeclareOptions, QueueDeleteOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{Channel, ExchangeKind};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Queues left behind (e.g. by a crashed test) expire once unused for this long.
+const QUEUE_EXPIRES_MS: i64 = 10 * 60 * 1000;
+
+/// The names leaked by `EphemeralBroker::name`, once each.
+static NAMES: Lazy<Mutex<HashMap<String, &'static str>>> = Lazy::new(Mutex::default);
+
+/// A connected `Broker` (publisher and consumer set up) along with the topology declared for a single test,
+/// deleted by `cleanup` or, in the background, on drop.
+#[derive(Debug)]
+pub struct EphemeralBroker {
+    broker: Broker,
+    channel: Channel,
+    prefix: String,
+    exchanges: Vec<String>,
+    queues: Vec<String>,
+}
+
+/// Connect to `uri` with a prefix unique to this test.
+pub async fn ephemeral_broker(uri: &str) -> Result<EphemeralBroker> {
+    let broker = Broker::connected(uri).await?;
+    let channel = broker.conn.as_ref().unwrap().create_channel().await?;
+
+    Ok(EphemeralBroker {
+        broker,
+        channel,
+        prefix: format!("test.{}", Uuid::new_v4().to_simple()),
+        exchanges: vec![],
+        queues: vec![],
+    })
+}
+
+impl EphemeralBroker {
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// `name` under the prefix of this test, without the topology affixes, leaked once
+    /// so it can be returned by `BrokerListener::exchange_name`.
+    pub fn name(&self, name: &str) -> &'static str {
+        let name = format!("{}.{}", self.prefix, name);
+        NAMES
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_insert_with_key(|name| Box::leak(name.clone().into_boxed_str()))
+    }
+
+    /// `name`, as returned by the declarations, as on the broker.
+    fn resolve<'a>(&self, name: &'a str) -> std::borrow::Cow<'a, str> {
+        self.broker.affixes.resolve(name)
+    }
+
+    pub async fn declare_exchange(&mut self, name: &str, kind: ExchangeKind) -> Result<&'static str> {
+        let name = self.name(name);
+        let resolved = self.resolve(name).into_owned();
+        self.channel
+            .exchange_declare(&resolved, kind, ExchangeDeclareOptions::default(), FieldTable::default())
+            .await?;
+        self.exchanges.push(resolved);
+
+        Ok(name)
+    }
+
+    pub async fn declare_queue(&mut self, name: &str) -> Result<&'static str> {
+        let name = self.name(name);
+        let resolved = self.resolve(name).into_owned();
+        let mut arguments = FieldTable::default();
+        arguments.insert("x-expires".into(), AMQPValue::LongLongInt(QUEUE_EXPIRES_MS));
+        self.channel
+            .queue_declare(&resolved, QueueDeclareOptions::default(), arguments)
+            .await?;
+        self.queues.push(resolved);
+
+        Ok(name)
+    }
+
+    /// Bind `queue` to `exchange`, both names as returned by the declarations.
+    pub async fn bind(&self, queue: &str, exchange: &str, routing_key: &str) -> Result<()> {
+        self.channel
+            .queue_bind(&self.resolve(queue), &self.resolve(exchange), routing_key, QueueBindOptions::default(), FieldTable::default())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Bind the exchange `destination` to `source`, both names as returned by the declarations.
+    pub async fn bind_exchange(&self, destination: &str, source: &str, routing_key: &str) -> Result<()> {
+        self.channel
+            .exchange_bind(
+                &self.resolve(destination),
+                &self.resolve(source),
+                routing_key,
+                ExchangeBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Delete the declared queues then exchanges, and shut the broker down.
+    pub async fn cleanup(mut self) -> Result<()> {
+        delete(&self.channel, std::mem::take(&mut self.queues), std::mem::take(&mut self.exchanges)).await?;
+        self.broker.shutdown(Default::default()).await
+    }
+}
+
+async fn delete(channel: &Channel, queues: Vec<String>, exchanges: Vec<String>) -> Result<()> {
+    for queue in queues {
+        channel.queue_delete(&queue, QueueDeleteOptions::default()).await?;
+    }
+    for exchange in exchanges {
+        channel.exchange_delete(&exchange, ExchangeDeleteOptions::default()).await?;
+    }
+
+    Ok(())
+}
+
+impl Deref for EphemeralBroker {
+    type Target = Broker;
+
+    fn deref(&self) -> &Broker {
+        &self.broker
+    }
+}
+
+impl DerefMut for EphemeralBroker {
+    fn deref_mut(&mut self) -> &mut Broker {
+        &mut self.broker
+    }
+}
+
+impl Drop for EphemeralBroker {
+    fn drop(&mut self) {
+        // a test ending right after the drop may stop its runtime before this runs, hence `cleanup`
+        if self.queues.is_empty() && self.exchanges.is_empty() {
+            return;
+        }
+        if !self.channel.status().connected() || !crate::runtime::can_spawn() {
+            return;
+        }
+
+        let channel = self.channel.clone();
+        let (queues, exchanges) = (std::mem::take(&mut self.queues), std::mem::take(&mut self.exchanges));
+        tasks::spawn(TaskKind::Background, "amqp-test-cleanup", async move {
+            if let Err(err) = delete(&channel, queues, exchanges).await {
+                debug!(%err, "Failed to delete the topology of a test");
+            }
+        });
+    }
+}
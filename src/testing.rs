@@ -0,0 +1,333 @@
+//! Deterministic drivers of the dispatch, to property-test a listener and its configuration without a broker:
+//! every delivery is settled exactly once, no permit leaks, retries stay within the policy.
+//! The rules are the consumer's own: signature, dedup, expiry, redelivery, downstream confirms and retries.
+//!
+//! The deliveries are handed to `BrokerListener::consume`, `consume_with_context` needs a channel.
+//! The timeouts follow the `clock` of the crate, e.g. a `MockClock`.
+
+use crate::audit::ConfirmOutcome;
+use crate::confirm::Downstream;
+use crate::dispatch::{self, Admission, Settle};
+use crate::journal::Journal;
+use crate::retry::RetryPolicy;
+use crate::signing::Signer;
+use crate::{BrokerListener, Error, Rejection, Result};
+use lapin::acker::Acker;
+use lapin::message::Delivery;
+use lapin::options::BasicAckOptions;
+use lapin::types::DeliveryTag;
+use lapin::BasicProperties;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+/// A delivery not attached to any channel, its acker fails when used twice.
+pub fn delivery(delivery_tag: DeliveryTag, exchange: &str, routing_key: &str, data: &[u8], properties: BasicProperties) -> Delivery {
+    Delivery {
+        delivery_tag,
+        exchange: exchange.into(),
+        routing_key: routing_key.into(),
+        redelivered: false,
+        properties,
+        data: data.to_vec(),
+        acker: Acker::default(),
+    }
+}
+
+/// What the consumer would do with a handled delivery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Settlement {
+    Ack,
+    /// Republished to `exchange` for its `attempt`-th retry, then acked.
+    Retry { exchange: String, attempt: u32 },
+    /// Rejected, or dead-lettered when the consumer has a dead-letter exchange.
+    Reject { requeue: bool, reason: Option<String> },
+}
+
+/// The rules of the consumer, for the outcome `res` of `delivery` handled by a listener with `retry_policy`.
+/// Returns the settlement, and whether the retries are exhausted (`on_poison` is then called).
+pub fn settle(delivery: &Delivery, retry_policy: Option<&RetryPolicy>, res: std::result::Result<(), Rejection>) -> (Settlement, bool) {
+    match dispatch::settle(delivery, retry_policy, res) {
+        Settle::Ack => (Settlement::Ack, false),
+        Settle::Retry { policy, attempt, .. } => (Settlement::Retry { exchange: policy.exchange_for(attempt).to_string(), attempt }, false),
+        Settle::Poison { rejection, .. } => (Settlement::Reject { requeue: false, reason: rejection.reason }, true),
+        Settle::Reject(rejection) => (Settlement::Reject { requeue: rejection.requeue, reason: rejection.reason }, false),
+    }
+}
+
+/// Runs deliveries through a listener under its concurrency limit, and records how each was settled.
+pub struct Dispatcher<L> {
+    listener: Arc<L>,
+    max_concurrent_tasks: usize,
+    semaphore: Arc<Semaphore>,
+    settlements: Mutex<Vec<(DeliveryTag, Settlement)>>,
+    signer: Option<Arc<dyn Signer>>,
+    journal: Option<Arc<dyn Journal>>,
+    downstream_outcomes: Vec<ConfirmOutcome>,
+}
+
+impl<L: fmt::Debug> fmt::Debug for Dispatcher<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("listener", &self.listener)
+            .field("max_concurrent_tasks", &self.max_concurrent_tasks)
+            .field("semaphore", &self.semaphore)
+            .field("settlements", &self.settlements)
+            .field("signer", &self.signer.is_some())
+            .field("journal", &self.journal)
+            .field("downstream_outcomes", &self.downstream_outcomes)
+            .finish()
+    }
+}
+
+impl<L: BrokerListener> Dispatcher<L> {
+    pub fn new(listener: L) -> Self {
+        let max_concurrent_tasks = listener.max_concurrent_tasks();
+
+        Self {
+            listener: Arc::new(listener),
+            max_concurrent_tasks,
+            semaphore: Arc::new(Semaphore::new(max_concurrent_tasks)),
+            settlements: Mutex::default(),
+            signer: None,
+            journal: None,
+            downstream_outcomes: vec![],
+        }
+    }
+
+    /// Verify the signatures like a consumer with `Consumer::set_signer`, the others are rejected.
+    pub fn with_signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Skip the redelivered duplicates like a consumer with `Consumer::set_journal`.
+    pub fn with_journal(mut self, journal: Arc<dyn Journal>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Outcomes of the messages each delivery derives, for a listener which acks after their downstream confirm.
+    pub fn with_downstream_outcomes(mut self, outcomes: Vec<ConfirmOutcome>) -> Self {
+        self.downstream_outcomes = outcomes;
+        self
+    }
+
+    pub fn listener(&self) -> &L {
+        &self.listener
+    }
+
+    /// Handle `delivery` like the consumer, settling it through its acker.
+    /// A listener which already acked or rejected it makes this fail.
+    pub async fn dispatch(&self, delivery: Delivery) -> Result<Settlement> {
+        // like the consumer, a delivery without permit is requeued
+        let cost = self.listener.cost(&delivery).clamp(1, self.max_concurrent_tasks.max(1) as u32);
+        let Ok(permit) = self.semaphore.clone().acquire_many_owned(cost).await else {
+            let settlement = Settlement::Reject { requeue: true, reason: Some("permit_unavailable".to_string()) };
+            self.listener.reject_method().apply(&delivery, true).await.map_err(Error::from)?;
+            return Ok(self.record(&delivery, settlement));
+        };
+
+        let admission = dispatch::admit(&delivery, self.signer.as_deref(), self.journal.as_deref(), self.listener.drop_expired());
+        let journal_key = match admission {
+            Admission::Consume { journal_key } => journal_key,
+            Admission::Unsigned => {
+                self.listener.reject_method().apply(&delivery, false).await.map_err(Error::from)?;
+                let settlement = Settlement::Reject { requeue: false, reason: Some("invalid_signature".to_string()) };
+                return Ok(self.record(&delivery, settlement));
+            }
+            Admission::Probe | Admission::Duplicate(_) | Admission::Expired => {
+                delivery.ack(BasicAckOptions::default()).await.map_err(Error::from)?;
+                return Ok(self.record(&delivery, Settlement::Ack));
+            }
+        };
+
+        let downstream = self.listener.ack_after_downstream_confirm().then(|| {
+            let downstream = Downstream::default();
+            for outcome in &self.downstream_outcomes {
+                let _ = downstream.track().send(outcome.clone());
+            }
+            downstream
+        });
+        let consume = self.listener.consume(&delivery);
+        let res = dispatch::consume_redelivered(delivery.redelivered, self.listener.redelivery_policy(), consume).await;
+        let res = dispatch::confirm_downstream(res, downstream.as_ref()).await;
+        drop(permit);
+
+        let retry_policy = self.listener.retry_policy();
+        let settlement = match dispatch::settle(&delivery, retry_policy.as_ref(), res) {
+            Settle::Ack => {
+                if let (Some(journal), Some(key)) = (&self.journal, &journal_key) {
+                    journal.record(key);
+                }
+                delivery.ack(BasicAckOptions::default()).await.map_err(Error::from)?;
+                Settlement::Ack
+            }
+            Settle::Retry { policy, attempt, .. } => {
+                delivery.ack(BasicAckOptions::default()).await.map_err(Error::from)?;
+                Settlement::Retry { exchange: policy.exchange_for(attempt).to_string(), attempt }
+            }
+            Settle::Poison { attempt, rejection, last_error } => {
+                self.listener.reject_method().apply(&delivery, false).await.map_err(Error::from)?;
+                self.listener.on_poison(&delivery, attempt, last_error.as_deref()).await;
+                Settlement::Reject { requeue: false, reason: rejection.reason }
+            }
+            Settle::Reject(rejection) => {
+                self.listener.reject_method().apply(&delivery, rejection.requeue).await.map_err(Error::from)?;
+                Settlement::Reject { requeue: rejection.requeue, reason: rejection.reason }
+            }
+        };
+
+        Ok(self.record(&delivery, settlement))
+    }
+
+    fn record(&self, delivery: &Delivery, settlement: Settlement) -> Settlement {
+        self.settlements.lock().unwrap().push((delivery.delivery_tag, settlement.clone()));
+        settlement
+    }
+
+    /// Every settlement so far, in the order they happened.
+    pub fn settlements(&self) -> Vec<(DeliveryTag, Settlement)> {
+        self.settlements.lock().unwrap().clone()
+    }
+
+    /// Close the semaphore, as if the permits were gone for good.
+    pub fn close_permits(&self) {
+        self.semaphore.close();
+    }
+
+    /// Permits not given back, 0 once every dispatch completed.
+    pub fn permits_in_use(&self) -> usize {
+        self.max_concurrent_tasks - self.semaphore.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use futures_lite::future::block_on;
+
+    struct Unreachable;
+
+    #[async_trait]
+    impl BrokerListener for Unreachable {
+        fn exchange_name(&self) -> &'static str {
+            "test.dispatcher"
+        }
+
+        async fn consume(&self, _delivery: &Delivery) -> std::result::Result<(), Rejection> {
+            unreachable!("no permit, no consume")
+        }
+    }
+
+    #[test]
+    fn closed_permits_requeue_every_delivery() {
+        let dispatcher = Dispatcher::new(Unreachable);
+        dispatcher.close_permits();
+
+        for delivery_tag in 1..=3 {
+            let settlement = block_on(dispatcher.dispatch(delivery(delivery_tag, "test.dispatcher", "", b"", BasicProperties::default())));
+            assert_eq!(
+                settlement.unwrap(),
+                Settlement::Reject { requeue: true, reason: Some("permit_unavailable".to_string()) }
+            );
+        }
+
+        assert_eq!(dispatcher.settlements().len(), 3);
+        assert_eq!(dispatcher.permits_in_use(), 0);
+    }
+
+    /// Counts its calls, acks after the downstream confirm when `downstream` is set.
+    #[derive(Default)]
+    struct Counting {
+        calls: std::sync::atomic::AtomicUsize,
+        downstream: bool,
+    }
+
+    #[async_trait]
+    impl BrokerListener for Counting {
+        fn exchange_name(&self) -> &'static str {
+            "test.dispatcher"
+        }
+
+        fn ack_after_downstream_confirm(&self) -> bool {
+            self.downstream
+        }
+
+        async fn consume(&self, _delivery: &Delivery) -> std::result::Result<(), Rejection> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    impl Counting {
+        fn calls(&self) -> usize {
+            self.calls.load(std::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    struct Fixed;
+
+    impl Signer for Fixed {
+        fn sign(&self, _payload: &[u8]) -> String {
+            "signed".to_string()
+        }
+
+        fn verify(&self, _payload: &[u8], signature: &str) -> bool {
+            signature == "signed"
+        }
+    }
+
+    #[test]
+    fn redelivered_duplicates_are_acked_without_the_listener() {
+        let dispatcher = Dispatcher::new(Counting::default()).with_journal(Arc::new(crate::journal::MemoryJournal::new(8)));
+        let properties = BasicProperties::default().with_message_id("once".into());
+
+        let first = block_on(dispatcher.dispatch(delivery(1, "test.dispatcher", "", b"", properties.clone())));
+        let mut redelivered = delivery(2, "test.dispatcher", "", b"", properties);
+        redelivered.redelivered = true;
+        let duplicate = block_on(dispatcher.dispatch(redelivered));
+
+        assert_eq!((first.unwrap(), duplicate.unwrap()), (Settlement::Ack, Settlement::Ack));
+        assert_eq!(dispatcher.listener().calls(), 1);
+    }
+
+    #[test]
+    fn unsigned_deliveries_are_rejected_without_the_listener() {
+        let dispatcher = Dispatcher::new(Counting::default()).with_signer(Arc::new(Fixed));
+        let signed = crate::headers::insert(
+            BasicProperties::default(),
+            crate::signing::SIGNATURE_HEADER,
+            crate::headers::long_string("signed"),
+        );
+
+        let unsigned = block_on(dispatcher.dispatch(delivery(1, "test.dispatcher", "", b"", BasicProperties::default())));
+        let signed = block_on(dispatcher.dispatch(delivery(2, "test.dispatcher", "", b"", signed)));
+
+        assert_eq!(unsigned.unwrap(), Settlement::Reject { requeue: false, reason: Some("invalid_signature".to_string()) });
+        assert_eq!(signed.unwrap(), Settlement::Ack);
+        assert_eq!(dispatcher.listener().calls(), 1);
+    }
+
+    #[test]
+    fn unconfirmed_downstream_requeues() {
+        let listener = Counting { downstream: true, ..Counting::default() };
+        let dispatcher = Dispatcher::new(listener).with_downstream_outcomes(vec![ConfirmOutcome::Ack, ConfirmOutcome::Nack]);
+
+        let settlement = block_on(dispatcher.dispatch(delivery(1, "test.dispatcher", "", b"", BasicProperties::default())));
+
+        assert_eq!(settlement.unwrap(), Settlement::Reject { requeue: true, reason: Some("downstream_not_confirmed".to_string()) });
+    }
+
+    #[test]
+    fn forged_probes_reach_the_listener() {
+        let dispatcher = Dispatcher::new(Counting::default());
+        let forged = crate::headers::insert(BasicProperties::default(), crate::verify::PROBE_HEADER, lapin::types::AMQPValue::Boolean(true));
+
+        let settlement = block_on(dispatcher.dispatch(delivery(1, "test.dispatcher", "", b"", forged)));
+
+        assert_eq!(settlement.unwrap(), Settlement::Ack);
+        assert_eq!(dispatcher.listener().calls(), 1);
+    }
+}
@@ -0,0 +1,207 @@
+//! What this crate declared and registered, for debugging endpoints and for asserting the expected wiring
+//! in integration tests.
+
+use lapin::topology::TopologyDefinition;
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::ExchangeKind;
+use serde::{Serialize, Serializer};
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Topology {
+    /// Exchanges, queues, bindings and consumers declared on the main connection, as tracked by lapin.
+    pub declared: TopologyDefinition,
+    /// Same for the publisher's own connection, when `Broker::set_separate_connections` is enabled.
+    pub publisher_declared: Option<TopologyDefinition>,
+    /// Exchange names of the registered listeners.
+    pub listeners: Vec<&'static str>,
+}
+
+impl Topology {
+    /// Whether a consumer is running on `queue`, on any channel.
+    pub fn is_consumed(&self, queue: &str) -> bool {
+        self.declared
+            .channels
+            .iter()
+            .flat_map(|channel| channel.consumers.iter())
+            .any(|consumer| consumer.queue.as_str() == queue)
+    }
+
+    /// Whether `queue` is bound to `exchange` with `routing_key`.
+    pub fn is_bound(&self, queue: &str, exchange: &str, routing_key: &str) -> bool {
+        let queues = self.declared.queues.iter();
+        let exclusive_queues = self.declared.channels.iter().flat_map(|channel| channel.queues.iter());
+
+        queues
+            .chain(exclusive_queues)
+            .filter(|definition| definition.name.as_str() == queue)
+            .flat_map(|definition| definition.bindings.iter())
+            .any(|binding| binding.source.as_str() == exchange && binding.routing_key.as_str() == routing_key)
+    }
+
+    /// Whether the exchange `destination` is bound to `source` with `routing_key`.
+    pub fn is_exchange_bound(&self, destination: &str, source: &str, routing_key: &str) -> bool {
+        self.declared
+            .exchanges
+            .iter()
+            .filter(|definition| definition.name.as_str() == destination)
+            .flat_map(|definition| definition.bindings.iter())
+            .any(|binding| binding.source.as_str() == source && binding.routing_key.as_str() == routing_key)
+    }
+}
+
+/// RabbitMQ definitions (as imported by the management plugin) of what was declared, see
+/// `Topology::export_rabbitmq_definitions`. Serializes to the `definitions.json` format, e.g. with `serde_json`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RabbitMqDefinitions {
+    pub exchanges: Vec<ExchangeDefinition>,
+    pub queues: Vec<QueueDefinition>,
+    pub bindings: Vec<BindingDefinition>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ExchangeDefinition {
+    pub name: String,
+    pub vhost: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub durable: bool,
+    pub auto_delete: bool,
+    pub internal: bool,
+    pub arguments: Arguments,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueueDefinition {
+    pub name: String,
+    pub vhost: String,
+    pub durable: bool,
+    pub auto_delete: bool,
+    pub arguments: Arguments,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BindingDefinition {
+    pub source: String,
+    pub vhost: String,
+    pub destination: String,
+    /// `queue` or `exchange`.
+    pub destination_type: &'static str,
+    pub routing_key: String,
+    pub arguments: Arguments,
+}
+
+/// Declaration arguments, serialized as a plain JSON object.
+#[derive(Clone, Debug, Default)]
+pub struct Arguments(pub FieldTable);
+
+impl Serialize for Arguments {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.inner().iter().map(|(key, value)| (key.as_str(), Value(value))))
+    }
+}
+
+/// An AMQP value as its plain JSON counterpart.
+struct Value<'a>(&'a AMQPValue);
+
+impl Serialize for Value<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            AMQPValue::Boolean(v) => serializer.serialize_bool(*v),
+            AMQPValue::ShortShortInt(v) => serializer.serialize_i8(*v),
+            AMQPValue::ShortShortUInt(v) => serializer.serialize_u8(*v),
+            AMQPValue::ShortInt(v) => serializer.serialize_i16(*v),
+            AMQPValue::ShortUInt(v) => serializer.serialize_u16(*v),
+            AMQPValue::LongInt(v) => serializer.serialize_i32(*v),
+            AMQPValue::LongUInt(v) => serializer.serialize_u32(*v),
+            AMQPValue::LongLongInt(v) => serializer.serialize_i64(*v),
+            AMQPValue::Float(v) => serializer.serialize_f32(*v),
+            AMQPValue::Double(v) => serializer.serialize_f64(*v),
+            AMQPValue::DecimalValue(v) => serializer.serialize_f64(v.value as f64 / 10f64.powi(v.scale as i32)),
+            AMQPValue::ShortString(v) => serializer.serialize_str(v.as_str()),
+            AMQPValue::LongString(v) => serializer.serialize_str(&String::from_utf8_lossy(v.as_bytes())),
+            AMQPValue::FieldArray(v) => serializer.collect_seq(v.as_slice().iter().map(Value)),
+            AMQPValue::Timestamp(v) => serializer.serialize_u64(*v),
+            AMQPValue::FieldTable(v) => Arguments(v.clone()).serialize(serializer),
+            AMQPValue::ByteArray(v) => serializer.collect_seq(v.as_slice()),
+            AMQPValue::Void => serializer.serialize_none(),
+        }
+    }
+}
+
+fn exchange_kind(kind: &ExchangeKind) -> String {
+    match kind {
+        ExchangeKind::Custom(kind) => kind.clone(),
+        ExchangeKind::Direct => "direct".to_string(),
+        ExchangeKind::Fanout => "fanout".to_string(),
+        ExchangeKind::Headers => "headers".to_string(),
+        ExchangeKind::Topic => "topic".to_string(),
+    }
+}
+
+impl Topology {
+    /// The exchanges, durable queues and bindings declared, on both connections, as RabbitMQ definitions of `vhost`.
+    /// Exclusive queues are left out, the server names them on each declaration.
+    pub fn export_rabbitmq_definitions(&self, vhost: &str) -> RabbitMqDefinitions {
+        let mut definitions = RabbitMqDefinitions::default();
+
+        for declared in std::iter::once(&self.declared).chain(self.publisher_declared.as_ref()) {
+            for exchange in &declared.exchanges {
+                let name = exchange.name.to_string();
+                let options = exchange.options.unwrap_or_default();
+
+                for binding in &exchange.bindings {
+                    definitions.bindings.push(BindingDefinition {
+                        source: binding.source.to_string(),
+                        vhost: vhost.to_string(),
+                        destination: name.clone(),
+                        destination_type: "exchange",
+                        routing_key: binding.routing_key.to_string(),
+                        arguments: Arguments(binding.arguments.clone()),
+                    });
+                }
+
+                if definitions.exchanges.iter().any(|definition| definition.name == name) {
+                    continue;
+                }
+                definitions.exchanges.push(ExchangeDefinition {
+                    kind: exchange_kind(exchange.kind.as_ref().unwrap_or(&ExchangeKind::Direct)),
+                    vhost: vhost.to_string(),
+                    durable: options.durable,
+                    auto_delete: options.auto_delete,
+                    internal: options.internal,
+                    arguments: Arguments(exchange.arguments.clone().unwrap_or_default()),
+                    name,
+                });
+            }
+
+            for queue in &declared.queues {
+                let name = queue.name.to_string();
+                let options = queue.options.unwrap_or_default();
+
+                for binding in &queue.bindings {
+                    definitions.bindings.push(BindingDefinition {
+                        source: binding.source.to_string(),
+                        vhost: vhost.to_string(),
+                        destination: name.clone(),
+                        destination_type: "queue",
+                        routing_key: binding.routing_key.to_string(),
+                        arguments: Arguments(binding.arguments.clone()),
+                    });
+                }
+
+                if definitions.queues.iter().any(|definition| definition.name == name) {
+                    continue;
+                }
+                definitions.queues.push(QueueDefinition {
+                    vhost: vhost.to_string(),
+                    durable: options.durable,
+                    auto_delete: options.auto_delete,
+                    arguments: Arguments(queue.arguments.clone().unwrap_or_default()),
+                    name,
+                });
+            }
+        }
+
+        definitions
+    }
+}
@@ -0,0 +1,13 @@
+//! A trace id carried by the messages, joining the `amqp_publish` and `amqp_consume` spans of a message's journey.
+//!
+//! The id is taken from the `x-trace-id` header of the delivery being consumed when publishing
+//! through `ConsumeContext::publisher`, a new one is generated otherwise.
+
+use crate::headers;
+use lapin::message::Delivery;
+
+pub const TRACE_ID_HEADER: &str = "x-trace-id";
+
+pub fn trace_id(delivery: &Delivery) -> Option<String> {
+    headers::get(delivery, TRACE_ID_HEADER).and_then(headers::as_string)
+}
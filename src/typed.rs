@@ -0,0 +1,284 @@
+//! Listeners of typed messages: the payload is decoded (after its content type, see `format::decode`,
+//! or with a given `Codec`) before reaching them.
+//! Plugged into a `Consumer` through the `Typed` adapter, see `Consumer::add_typed_listener`.
+
+use crate::format::{self, Codec, PayloadFormat};
+use crate::retry::{RedeliveryPolicy, RetryInfo, RetryPolicy};
+use crate::shedding::{LoadShedding, PermitAcquisition};
+use crate::{BrokerListener, ConsumeContext, RejectMethod, Rejection};
+use async_trait::async_trait;
+use lapin::message::Delivery;
+use lapin::BasicProperties;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// What is known of a delivery besides its decoded payload.
+#[derive(Clone, Debug)]
+pub struct DeliveryMeta {
+    pub exchange: String,
+    pub routing_key: String,
+    pub redelivered: bool,
+    pub properties: BasicProperties,
+    pub retry: RetryInfo,
+}
+
+impl DeliveryMeta {
+    pub fn from_delivery(delivery: &Delivery) -> Self {
+        Self {
+            exchange: delivery.exchange.to_string(),
+            routing_key: delivery.routing_key.to_string(),
+            redelivered: delivery.redelivered,
+            properties: delivery.properties.clone(),
+            retry: RetryInfo::from_delivery(delivery),
+        }
+    }
+}
+
+/// A `BrokerListener` receiving decoded payloads, each hook defaults to the one of `BrokerListener`.
+#[async_trait]
+pub trait TypedBrokerListener<T: DeserializeOwned + Send + 'static>: Send + Sync {
+    /// See `BrokerListener::exchange_name`.
+    fn exchange_name(&self) -> &'static str;
+
+    /// See `BrokerListener::cost`, given the raw delivery.
+    fn cost(&self, _delivery: &Delivery) -> u32 {
+        1
+    }
+
+    /// See `BrokerListener::max_concurrent_tasks`, the default one when `None`.
+    fn max_concurrent_tasks(&self) -> Option<usize> {
+        None
+    }
+
+    /// See `BrokerListener::retry_policy`.
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        None
+    }
+
+    /// See `BrokerListener::duration_buckets`.
+    fn duration_buckets(&self) -> Option<Vec<f64>> {
+        None
+    }
+
+    /// See `BrokerListener::slow_threshold`.
+    fn slow_threshold(&self) -> Option<Duration> {
+        None
+    }
+
+    /// See `BrokerListener::load_shedding`.
+    fn load_shedding(&self) -> Option<LoadShedding> {
+        None
+    }
+
+    /// See `BrokerListener::redelivery_policy`.
+    fn redelivery_policy(&self) -> RedeliveryPolicy {
+        RedeliveryPolicy::Process
+    }
+
+    /// See `BrokerListener::drop_expired`.
+    fn drop_expired(&self) -> bool {
+        false
+    }
+
+    /// See `BrokerListener::ack_after_downstream_confirm`.
+    fn ack_after_downstream_confirm(&self) -> bool {
+        false
+    }
+
+    /// See `BrokerListener::permit_acquisition`.
+    fn permit_acquisition(&self) -> PermitAcquisition {
+        PermitAcquisition::Fifo
+    }
+
+    /// See `BrokerListener::reject_method`.
+    fn reject_method(&self) -> RejectMethod {
+        RejectMethod::Reject
+    }
+
+    /// How the deliveries whose payload can't be decoded are rejected: without requeue
+    /// (dead-lettered when configured) by default.
+    fn decode_failure(&self) -> Rejection {
+        Rejection::discard().with_reason("undecodable_payload")
+    }
+
+    /// See `BrokerListener::on_start`.
+    async fn on_start(&self) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(())
+    }
+
+    /// See `BrokerListener::on_stop`.
+    async fn on_stop(&self) {}
+
+    /// See `BrokerListener::on_partitions_changed`.
+    async fn on_partitions_changed(&self, _assigned: &[String], _revoked: &[String]) {}
+
+    /// See `BrokerListener::on_poison`, given the raw delivery: its payload may be the one which can't be decoded.
+    async fn on_poison(&self, _delivery: &Delivery, _attempts: u32, _last_error: Option<&str>) {}
+
+    async fn consume(&self, msg: T, meta: &DeliveryMeta) -> std::result::Result<(), Rejection>;
+
+    /// See `BrokerListener::consume_with_context`.
+    async fn consume_with_context(
+        &self,
+        msg: T,
+        meta: &DeliveryMeta,
+        _context: &ConsumeContext,
+    ) -> std::result::Result<(), Rejection> {
+        self.consume(msg, meta).await
+    }
+}
+
+/// A `TypedBrokerListener` as a `BrokerListener`, decoding the payloads with `C`.
+pub struct Typed<L, T, C = PayloadFormat> {
+    inner: L,
+    /// `None` to decode after the content type, see `format::decode`.
+    codec: Option<C>,
+    _msg: PhantomData<fn(T)>,
+}
+
+impl<L, T> Typed<L, T> {
+    /// Decoding the payloads after their content type, see `format::decode`.
+    pub fn new(inner: L) -> Self {
+        Self {
+            inner,
+            codec: None,
+            _msg: PhantomData,
+        }
+    }
+}
+
+impl<L, T, C> Typed<L, T, C> {
+    /// Decoding the payloads with `codec`, whatever their content type.
+    pub fn with_codec(inner: L, codec: C) -> Self {
+        Self {
+            inner,
+            codec: Some(codec),
+            _msg: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+}
+
+impl<L, T, C> std::fmt::Debug for Typed<L, T, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Typed")
+            .field("msg", &std::any::type_name::<T>())
+            .field("codec", &std::any::type_name::<C>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<L, T, C> Typed<L, T, C>
+where
+    L: TypedBrokerListener<T>,
+    T: DeserializeOwned + Send + 'static,
+    C: Codec,
+{
+    fn decode(&self, delivery: &Delivery) -> std::result::Result<(T, DeliveryMeta), Rejection> {
+        let msg = match &self.codec {
+            Some(codec) => codec.decode::<T>(&delivery.data),
+            None => format::decode::<T>(delivery),
+        };
+
+        match msg {
+            Ok(msg) => Ok((msg, DeliveryMeta::from_delivery(delivery))),
+            Err(err) => {
+                warn!(%err, exchange_name = self.inner.exchange_name(), "Failed to decode a delivery");
+                Err(self.inner.decode_failure())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<L, T, C> BrokerListener for Typed<L, T, C>
+where
+    L: TypedBrokerListener<T>,
+    T: DeserializeOwned + Send + 'static,
+    C: Codec + Send + Sync,
+{
+    fn exchange_name(&self) -> &'static str {
+        self.inner.exchange_name()
+    }
+
+    fn cost(&self, delivery: &Delivery) -> u32 {
+        self.inner.cost(delivery)
+    }
+
+    fn max_concurrent_tasks(&self) -> usize {
+        match self.inner.max_concurrent_tasks() {
+            Some(max) => max,
+            None => crate::DEFAULT_MAX_CONCURRENT_TASKS.load(std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.inner.retry_policy()
+    }
+
+    fn duration_buckets(&self) -> Option<Vec<f64>> {
+        self.inner.duration_buckets()
+    }
+
+    fn slow_threshold(&self) -> Option<Duration> {
+        self.inner.slow_threshold()
+    }
+
+    fn load_shedding(&self) -> Option<LoadShedding> {
+        self.inner.load_shedding()
+    }
+
+    fn redelivery_policy(&self) -> RedeliveryPolicy {
+        self.inner.redelivery_policy()
+    }
+
+    fn drop_expired(&self) -> bool {
+        self.inner.drop_expired()
+    }
+
+    fn ack_after_downstream_confirm(&self) -> bool {
+        self.inner.ack_after_downstream_confirm()
+    }
+
+    fn permit_acquisition(&self) -> PermitAcquisition {
+        self.inner.permit_acquisition()
+    }
+
+    fn reject_method(&self) -> RejectMethod {
+        self.inner.reject_method()
+    }
+
+    async fn on_start(&self) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.on_start().await
+    }
+
+    async fn on_stop(&self) {
+        self.inner.on_stop().await
+    }
+
+    async fn on_partitions_changed(&self, assigned: &[String], revoked: &[String]) {
+        self.inner.on_partitions_changed(assigned, revoked).await
+    }
+
+    async fn on_poison(&self, delivery: &Delivery, attempts: u32, last_error: Option<&str>) {
+        self.inner.on_poison(delivery, attempts, last_error).await
+    }
+
+    async fn consume(&self, delivery: &Delivery) -> std::result::Result<(), Rejection> {
+        let (msg, meta) = self.decode(delivery)?;
+        self.inner.consume(msg, &meta).await
+    }
+
+    async fn consume_with_context(
+        &self,
+        delivery: &Delivery,
+        context: &ConsumeContext,
+    ) -> std::result::Result<(), Rejection> {
+        let (msg, meta) = self.decode(delivery)?;
+        self.inner.consume_with_context(msg, &meta, context).await
+    }
+}
@@ -0,0 +1,67 @@
+//! Deploy-time smoke test of the publishing: a probe published mandatory with confirms tells whether the exchange
+//! routes the messages of a routing key anywhere, i.e. whether the bindings are in place. See `Broker::verify_publish`.
+//!
+//! The probes expire at once, and the consumers of this crate ack without handling the ones delivered anyway,
+//! as long as they were sent by this process: a probe carries a nonce of the process, and is signed like the messages
+//! of the publisher, so a forged one can't skip the signature check or make a message disappear.
+
+use crate::audit::ConfirmOutcome;
+use crate::signing::{self, Signer};
+use crate::{confirm, headers, Error, Result};
+use lapin::message::Delivery;
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use lapin::{BasicProperties, Connection};
+use once_cell::sync::Lazy;
+
+/// Set on the probes, to the nonce of the process which sent them.
+pub const PROBE_HEADER: &str = "x-publish-probe";
+
+static PROBE_NONCE: Lazy<String> = Lazy::new(|| uuid::Uuid::new_v4().to_string());
+
+/// Whether `delivery` is a probe sent by this process.
+pub fn is_probe(delivery: &Delivery) -> bool {
+    headers::get(delivery, PROBE_HEADER)
+        .and_then(headers::as_string)
+        .is_some_and(|nonce| nonce == *PROBE_NONCE)
+}
+
+/// Publish a probe to `exchange`, as named on the broker, with `routing_key` on a channel of its own, `false` when it was returned unroutable.
+/// Signed with `signer`, for the consumers verifying the signatures.
+pub(crate) async fn verify_publish(conn: &Connection, exchange: &str, routing_key: &str, signer: Option<&dyn Signer>) -> Result<bool> {
+    let channel = conn.create_channel().await?;
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+
+    let mut properties = headers::insert(
+        BasicProperties::default().with_expiration("0".into()),
+        PROBE_HEADER,
+        headers::long_string(&PROBE_NONCE),
+    );
+    if let Some(signer) = signer {
+        properties = headers::insert(properties, signing::SIGNATURE_HEADER, headers::long_string(&signer.sign(&[])));
+    }
+    let options = BasicPublishOptions {
+        mandatory: true,
+        ..BasicPublishOptions::default()
+    };
+    let res = match channel.basic_publish(exchange, routing_key, options, &[], properties).await {
+        Ok(confirm) => confirm.await,
+        Err(err) => Err(err),
+    };
+    let outcome = confirm::outcome(&res);
+
+    if let Err(err) = channel.close(200, "Probe published").await {
+        debug!(%err, "Failed to close the probe channel");
+    }
+
+    match outcome {
+        ConfirmOutcome::Ack => Ok(true),
+        ConfirmOutcome::Returned => {
+            warn!(exchange, routing_key, "Probe unroutable, the exchange has no matching binding");
+            Ok(false)
+        }
+        outcome => Err(Error::NotConfirmed {
+            exchange: exchange.to_string(),
+            outcome: outcome.as_str(),
+        }),
+    }
+}
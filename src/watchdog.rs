@@ -0,0 +1,59 @@
+//! Dead-man's switch: notices a consumer whose task is still running but which doesn't receive or finish
+//! anything anymore, e.g. its stream ended silently or one of its channels died.
+
+use crate::clock;
+use crate::runtime::{self, JoinHandle};
+use crate::tasks::{self, TaskKind};
+use crate::ListenerRegistry;
+use chrono::Utc;
+use lapin::Channel;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Why the consumer looks stalled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stall {
+    /// Nothing received or finished for this long.
+    Idle(Duration),
+    /// One of the channels of the consumer isn't connected anymore.
+    ChannelClosed,
+}
+
+/// Check every `interval` the consumer of `channels` and `listeners`, `on_stall` is called on every check it looks stalled.
+pub(crate) fn spawn<F>(
+    channels: Vec<Channel>,
+    listeners: Arc<ListenerRegistry>,
+    interval: Duration,
+    stall_after: Duration,
+    on_stall: F,
+) -> JoinHandle<()>
+where
+    F: Fn(Stall) + Send + Sync + 'static,
+{
+    let started_at = clock::now();
+
+    tasks::spawn(TaskKind::Background, "amqp-watchdog", async move {
+        loop {
+            runtime::sleep(interval).await;
+
+            let idle = match listeners.last_activity_at() {
+                Some(at) => (Utc::now() - at).to_std().unwrap_or_default(),
+                None => clock::elapsed(started_at),
+            };
+
+            let stall = if channels.iter().any(|channel| !channel.status().connected()) {
+                Some(Stall::ChannelClosed)
+            } else if idle > stall_after {
+                Some(Stall::Idle(idle))
+            } else {
+                None
+            };
+            crate::metrics::observe_watchdog(idle.as_secs(), stall.is_some());
+
+            if let Some(stall) = stall {
+                warn!(?stall, "Consumer looks stalled");
+                on_stall(stall);
+            }
+        }
+    })
+}